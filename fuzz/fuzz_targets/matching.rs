@@ -0,0 +1,877 @@
+//! Fuzz the matching engine against a naive reference orderbook
+//!
+//! Every input is a market configuration followed by a sequence of orders from a handful of
+//! owners. Each order goes through `Orderbook::new_order` and through `Reference`, a Vec based
+//! price-time matcher that knows nothing about order trees. Both must agree on the returned
+//! amounts, the events pushed to the heap, the error of rejected orders and the resting orders
+//! of both book sides. Any panic inside the program counts as a finding as well.
+//!
+//! Amounts and prices cover the whole i64 range. So that the account counters can't overflow,
+//! owners can lock at most i64::MAX of each token and settle their free balances after every
+//! order.
+//!
+//! Run with `cargo fuzz run matching` from the repository root.
+
+#![no_main]
+
+use anchor_lang::prelude::*;
+use arbitrary::Arbitrary;
+use bytemuck::Zeroable;
+use libfuzzer_sys::fuzz_target;
+use openbook_v2::error::OpenBookError;
+use openbook_v2::state::Event;
+use openbook_v2::state::*;
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+
+/// Few enough that no book side can fill up: 4 * MAX_OPEN_ORDERS is far below the node capacity
+const OWNERS: usize = 4;
+
+const START_TS: u64 = 1_700_000_000;
+
+const FEES_SCALE_FACTOR: i128 = 1_000_000;
+
+#[derive(Arbitrary, Debug)]
+struct FuzzInput {
+    base_lot_size: u16,
+    quote_lot_size: u16,
+    maker_fee: i32,
+    taker_fee: u32,
+    orders: Vec<FuzzOrder>,
+}
+
+#[derive(Arbitrary, Debug)]
+struct FuzzOrder {
+    owner: u8,
+    bid: bool,
+    max_base_lots: i64,
+    max_quote_lots_including_fees: i64,
+    client_order_id: u64,
+    time_in_force: u8,
+    self_trade_behavior: FuzzSelfTradeBehavior,
+    params: FuzzOrderParams,
+    oracle_price_lots: Option<i64>,
+    advance_secs: u8,
+    limit: u8,
+}
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum FuzzSelfTradeBehavior {
+    DecrementTake,
+    CancelProvide,
+    AbortTransaction,
+}
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum FuzzPostOrderType {
+    Limit,
+    PostOnly,
+    PostOnlySlide,
+}
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum FuzzOrderParams {
+    Market,
+    ImmediateOrCancel {
+        price_lots: i64,
+    },
+    Fixed {
+        price_lots: i64,
+        order_type: FuzzPostOrderType,
+    },
+    OraclePegged {
+        price_offset_lots: i64,
+        order_type: FuzzPostOrderType,
+        peg_limit: i64,
+    },
+    FillOrKill {
+        price_lots: i64,
+    },
+}
+
+impl FuzzInput {
+    fn market(&self) -> Market {
+        let taker_fee = i64::from(self.taker_fee % 1_000_001);
+        let maker_fee = (i64::from(self.maker_fee) % 1_000_001).max(-taker_fee);
+
+        let mut market = Market::zeroed();
+        market.base_lot_size = i64::from(self.base_lot_size) + 1;
+        market.quote_lot_size = i64::from(self.quote_lot_size) + 1;
+        market.maker_fee = maker_fee;
+        market.taker_fee = taker_fee;
+        market
+    }
+}
+
+impl FuzzOrder {
+    fn order(&self) -> Order {
+        let post_order_type = |order_type| match order_type {
+            FuzzPostOrderType::Limit => PostOrderType::Limit,
+            FuzzPostOrderType::PostOnly => PostOrderType::PostOnly,
+            FuzzPostOrderType::PostOnlySlide => PostOrderType::PostOnlySlide,
+        };
+        let params = match self.params {
+            FuzzOrderParams::Market => OrderParams::Market,
+            FuzzOrderParams::ImmediateOrCancel { price_lots } => {
+                OrderParams::ImmediateOrCancel { price_lots }
+            }
+            FuzzOrderParams::Fixed {
+                price_lots,
+                order_type,
+            } => OrderParams::Fixed {
+                price_lots,
+                order_type: post_order_type(order_type),
+            },
+            FuzzOrderParams::OraclePegged {
+                price_offset_lots,
+                order_type,
+                peg_limit,
+            } => OrderParams::OraclePegged {
+                price_offset_lots,
+                order_type: post_order_type(order_type),
+                peg_limit,
+            },
+            FuzzOrderParams::FillOrKill { price_lots } => OrderParams::FillOrKill { price_lots },
+        };
+
+        Order {
+            side: if self.bid { Side::Bid } else { Side::Ask },
+            max_base_lots: self.max_base_lots,
+            max_quote_lots_including_fees: self.max_quote_lots_including_fees,
+            client_order_id: self.client_order_id,
+            time_in_force: self.time_in_force.into(),
+            self_trade_behavior: match self.self_trade_behavior {
+                FuzzSelfTradeBehavior::DecrementTake => SelfTradeBehavior::DecrementTake,
+                FuzzSelfTradeBehavior::CancelProvide => SelfTradeBehavior::CancelProvide,
+                FuzzSelfTradeBehavior::AbortTransaction => SelfTradeBehavior::AbortTransaction,
+            },
+            params,
+        }
+    }
+}
+
+fn owner_key(owner: usize) -> Pubkey {
+    Pubkey::new_from_array([owner as u8 + 1; 32])
+}
+
+fn owner_index(key: &Pubkey) -> usize {
+    key.to_bytes()[0] as usize - 1
+}
+
+fn error_code(error: OpenBookError) -> u32 {
+    error.into()
+}
+
+/// A resting order of the reference book
+#[derive(Clone, Debug)]
+struct RefOrder {
+    side: Side,
+    tree: BookSideOrderTree,
+    key: u128,
+    seq_num: u64,
+    owner: usize,
+    slot: u8,
+    quantity: i64,
+    timestamp: u64,
+    time_in_force: u16,
+    peg_limit: i64,
+    /// Price the quote of a bid is locked at
+    locked_price: i64,
+    client_order_id: u64,
+}
+
+impl RefOrder {
+    /// Current price and validity of the order, None if it can't be matched at this oracle price
+    fn state(&self, now_ts: u64, oracle_price_lots: Option<i64>) -> Option<(i64, bool)> {
+        let expired =
+            self.time_in_force > 0 && now_ts >= self.timestamp + u64::from(self.time_in_force);
+        let price_data = (self.key >> 64) as u64;
+        match self.tree {
+            BookSideOrderTree::Fixed => Some((price_data as i64, !expired)),
+            BookSideOrderTree::OraclePegged => {
+                let price =
+                    oracle_price_lots?.saturating_add(oracle_pegged_price_offset(price_data));
+                if !(1..i64::MAX).contains(&price) {
+                    return None;
+                }
+                let beyond_limit =
+                    self.peg_limit != -1 && self.side.is_price_better(price, self.peg_limit);
+                Some((price, !expired && !beyond_limit))
+            }
+        }
+    }
+
+    fn expiry(&self) -> u64 {
+        if self.time_in_force == 0 {
+            u64::MAX
+        } else {
+            self.timestamp + u64::from(self.time_in_force)
+        }
+    }
+
+    fn resting(&self) -> Resting {
+        Resting {
+            key: self.key,
+            side: self.side.into(),
+            tree: self.tree.into(),
+            owner: owner_key(self.owner),
+            slot: self.slot,
+            quantity: self.quantity,
+            timestamp: self.timestamp,
+            time_in_force: self.time_in_force,
+            peg_limit: self.peg_limit,
+            client_order_id: self.client_order_id,
+        }
+    }
+}
+
+/// Resting order in a form that can be compared between the engine and the reference
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Resting {
+    key: u128,
+    side: u8,
+    tree: u8,
+    owner: Pubkey,
+    slot: u8,
+    quantity: i64,
+    timestamp: u64,
+    time_in_force: u16,
+    peg_limit: i64,
+    client_order_id: u64,
+}
+
+impl Resting {
+    fn from_leaf(side: Side, tree: BookSideOrderTree, leaf: &LeafNode) -> Self {
+        Self {
+            key: leaf.key.into(),
+            side: side.into(),
+            tree: tree.into(),
+            owner: leaf.owner,
+            slot: leaf.owner_slot,
+            quantity: leaf.quantity,
+            timestamp: leaf.timestamp,
+            time_in_force: leaf.time_in_force,
+            peg_limit: leaf.peg_limit,
+            client_order_id: leaf.client_order_id,
+        }
+    }
+}
+
+/// Fee math of the market, recomputed in i128
+struct Fees {
+    maker_fee: i128,
+    taker_fee: i128,
+}
+
+impl Fees {
+    fn new(market: &Market) -> Self {
+        Self {
+            maker_fee: market.maker_fee.into(),
+            taker_fee: market.taker_fee.into(),
+        }
+    }
+
+    /// Same rounding as Market::ceil_fee_division(), which truncates for negative numerators
+    fn ceil(numerator: i128) -> i128 {
+        (numerator + FEES_SCALE_FACTOR - 1) / FEES_SCALE_FACTOR
+    }
+
+    fn subtract_taker_fees(&self, quote: i128) -> i128 {
+        quote * FEES_SCALE_FACTOR / (FEES_SCALE_FACTOR + self.taker_fee)
+    }
+
+    fn taker_fees_ceil(&self, amount: i128) -> i128 {
+        Self::ceil(amount * self.taker_fee)
+    }
+
+    fn maker_fees_ceil(&self, amount: i128) -> i128 {
+        if self.maker_fee > 0 {
+            Self::ceil(amount * self.maker_fee)
+        } else {
+            0
+        }
+    }
+
+    fn maker_rebate_floor(&self, amount: i128) -> i128 {
+        if self.maker_fee > 0 {
+            0
+        } else {
+            amount * -self.maker_fee / FEES_SCALE_FACTOR
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Expected {
+    amounts: OrderWithAmounts,
+    events: Vec<AnyEvent>,
+}
+
+/// Price-time priority matcher over a flat list of resting orders
+///
+/// Mirrors the documented behavior of new_order(): expired and peg-limited orders are dropped
+/// while matching, events are emitted for other owners and applied directly for the taker, and
+/// the remainder is posted after dropping one expired order of the own side.
+#[derive(Clone, Debug)]
+struct Reference {
+    orders: Vec<RefOrder>,
+    seq_num: u64,
+    heap_seq_num: u64,
+    slots: [[bool; MAX_OPEN_ORDERS]; OWNERS],
+}
+
+impl Reference {
+    fn new() -> Self {
+        Self {
+            orders: vec![],
+            seq_num: 0,
+            heap_seq_num: 0,
+            slots: [[false; MAX_OPEN_ORDERS]; OWNERS],
+        }
+    }
+
+    /// Matchable orders of `side` as (index, price, valid), best first
+    fn book(
+        &self,
+        side: Side,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+    ) -> Vec<(usize, i64, bool)> {
+        let mut book: Vec<(usize, i64, bool)> = self
+            .orders
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| o.side == side)
+            .filter_map(|(i, o)| {
+                let (price, valid) = o.state(now_ts, oracle_price_lots)?;
+                Some((i, price, valid))
+            })
+            .collect();
+        book.sort_by_key(|&(i, price, _)| {
+            let price = if side == Side::Bid { -price } else { price };
+            (price, self.orders[i].seq_num)
+        });
+        book
+    }
+
+    /// Remove `order` from its owner: directly for the taker, through an out event otherwise
+    fn drop_order(
+        &mut self,
+        order: &RefOrder,
+        taker: usize,
+        now_ts: u64,
+        events: &mut Vec<AnyEvent>,
+        freed: &mut Vec<(usize, u8)>,
+    ) {
+        if order.owner == taker {
+            self.slots[taker][order.slot as usize] = false;
+            return;
+        }
+        events.push(bytemuck::cast(OutEvent::new(
+            order.side,
+            order.slot,
+            now_ts,
+            self.heap_seq_num,
+            owner_key(order.owner),
+            order.quantity,
+        )));
+        self.heap_seq_num += 1;
+        freed.push((order.owner, order.slot));
+    }
+
+    /// Native base and quote that `owner` has locked in resting orders, with maker fees
+    fn locked_native(&self, market: &Market, owner: usize) -> (i128, i128) {
+        let fees = Fees::new(market);
+        let mut base = 0;
+        let mut quote = 0;
+        for order in self.orders.iter().filter(|o| o.owner == owner) {
+            match order.side {
+                Side::Bid => {
+                    let quote_native = i128::from(order.quantity)
+                        * i128::from(order.locked_price)
+                        * i128::from(market.quote_lot_size);
+                    quote += quote_native + fees.maker_fees_ceil(quote_native);
+                }
+                Side::Ask => {
+                    base += i128::from(order.quantity) * i128::from(market.base_lot_size);
+                }
+            }
+        }
+        (base, quote)
+    }
+
+    /// Index of the order of `side` in `tree` with the earliest expiry before `now_ts`
+    fn earliest_expired(&self, side: Side, tree: BookSideOrderTree, now_ts: u64) -> Option<usize> {
+        self.orders
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| o.side == side && o.tree == tree && o.expiry() < now_ts)
+            .min_by_key(|(_, o)| (o.expiry(), o.key))
+            .map(|(i, _)| i)
+    }
+
+    /// Place `order` for `taker`, returning the new reference state or the expected error code
+    ///
+    /// The returned state assumes that all emitted events get consumed right away.
+    fn new_order(
+        &self,
+        market: &Market,
+        order: &Order,
+        taker: usize,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+        mut limit: u8,
+    ) -> std::result::Result<(Reference, Expected), u32> {
+        let mut next = self.clone();
+        let fees = Fees::new(market);
+        let base_lot_size = i128::from(market.base_lot_size);
+        let quote_lot_size = i128::from(market.quote_lot_size);
+
+        let side = order.side;
+        let other_side = side.invert_side();
+        let post_only = order.is_post_only();
+        let mut post_target = order.post_target();
+
+        let (limit_price, order_type) = match order.params {
+            OrderParams::Market => match side {
+                Side::Bid => (i64::MAX, PostOrderType::Limit),
+                Side::Ask => (1, PostOrderType::Limit),
+            },
+            OrderParams::ImmediateOrCancel { price_lots }
+            | OrderParams::FillOrKill { price_lots } => (price_lots, PostOrderType::Limit),
+            OrderParams::Fixed {
+                price_lots,
+                order_type,
+            } => (price_lots, order_type),
+            OrderParams::OraclePegged {
+                price_offset_lots,
+                order_type,
+                ..
+            } => {
+                let price_lots = oracle_price_lots
+                    .ok_or(error_code(OpenBookError::OraclePegInvalidOracleState))?
+                    .checked_add(price_offset_lots)
+                    .ok_or(error_code(OpenBookError::InvalidPriceLots))?;
+                (price_lots, order_type)
+            }
+        };
+        let best_other_price = self
+            .book(other_side, now_ts, oracle_price_lots)
+            .into_iter()
+            .find(|&(_, _, valid)| valid)
+            .map(|(_, price, _)| price);
+        let price_lots = match (order_type, best_other_price) {
+            (PostOrderType::PostOnlySlide, Some(best)) => match side {
+                Side::Bid => limit_price.min(best - 1),
+                Side::Ask => limit_price.max(best.saturating_add(1)),
+            },
+            _ => limit_price,
+        };
+        if price_lots < 1 {
+            return Err(error_code(OpenBookError::InvalidPriceLots));
+        }
+        let price_data = match order.params {
+            OrderParams::OraclePegged { .. } => {
+                oracle_pegged_price_data(price_lots - oracle_price_lots.unwrap())
+            }
+            _ => price_lots as u64,
+        };
+
+        next.seq_num += 1;
+        let order_id = new_node_key(side, price_data, next.seq_num);
+
+        let max_quote_lots = if side == Side::Bid && !post_only {
+            fees.subtract_taker_fees(order.max_quote_lots_including_fees.into()) as i64
+        } else {
+            order.max_quote_lots_including_fees
+        };
+        if order.max_base_lots > i64::MAX / market.base_lot_size
+            || max_quote_lots > i64::MAX / market.quote_lot_size
+        {
+            return Err(error_code(OpenBookError::InvalidInputLotsSize));
+        }
+
+        let mut remaining_base_lots = order.max_base_lots;
+        let mut remaining_quote_lots = max_quote_lots;
+        let mut decremented_quote_lots = 0_i64;
+        let mut maker_rebates = 0_i128;
+
+        let mut changes: Vec<(u128, i64)> = vec![];
+        let mut deletes: Vec<u128> = vec![];
+        let mut events: Vec<AnyEvent> = vec![];
+        let mut freed: Vec<(usize, u8)> = vec![];
+        let mut dropped = 0;
+
+        for (i, price, valid) in self.book(other_side, now_ts, oracle_price_lots) {
+            let maker = &self.orders[i];
+            if remaining_base_lots == 0 || remaining_quote_lots == 0 {
+                break;
+            }
+
+            if !valid {
+                if dropped < DROP_EXPIRED_ORDER_LIMIT {
+                    dropped += 1;
+                    next.drop_order(maker, taker, now_ts, &mut events, &mut freed);
+                    deletes.push(maker.key);
+                }
+                continue;
+            }
+
+            if !side.is_price_within_limit(price, price_lots) {
+                break;
+            }
+            if post_only || limit == 0 {
+                post_target = None;
+                break;
+            }
+            let max_match_by_quote = remaining_quote_lots / price;
+            if max_match_by_quote == 0 {
+                post_target = None;
+                break;
+            }
+
+            let match_base_lots = remaining_base_lots
+                .min(maker.quantity)
+                .min(max_match_by_quote);
+            let match_quote_lots = match_base_lots * price;
+
+            if maker.owner == taker {
+                match order.self_trade_behavior {
+                    SelfTradeBehavior::DecrementTake => decremented_quote_lots += match_quote_lots,
+                    SelfTradeBehavior::CancelProvide => {
+                        next.slots[taker][maker.slot as usize] = false;
+                        deletes.push(maker.key);
+                        continue;
+                    }
+                    SelfTradeBehavior::AbortTransaction => {
+                        return Err(error_code(OpenBookError::WouldSelfTrade))
+                    }
+                }
+            } else {
+                maker_rebates +=
+                    fees.maker_rebate_floor(i128::from(match_quote_lots) * quote_lot_size);
+            }
+
+            remaining_base_lots -= match_base_lots;
+            remaining_quote_lots -= match_quote_lots;
+
+            let maker_out = maker.quantity == match_base_lots;
+            if maker_out {
+                deletes.push(maker.key);
+                freed.push((maker.owner, maker.slot));
+            } else {
+                changes.push((maker.key, maker.quantity - match_base_lots));
+            }
+
+            events.push(bytemuck::cast(FillEvent::new(
+                side,
+                maker_out,
+                maker.slot,
+                now_ts,
+                next.seq_num,
+                owner_key(maker.owner),
+                maker.client_order_id,
+                maker.timestamp,
+                owner_key(taker),
+                order.client_order_id,
+                price,
+                maker.peg_limit,
+                match_base_lots,
+            )));
+            next.heap_seq_num += 1;
+
+            limit -= 1;
+        }
+
+        let total_quote_lots_taken = max_quote_lots - remaining_quote_lots;
+        let total_base_lots_taken = order.max_base_lots - remaining_base_lots;
+
+        let mut taker_fees = 0_i128;
+        let mut referrer_amount = 0_i128;
+        let quote_taken_wo_self =
+            i128::from(total_quote_lots_taken - decremented_quote_lots) * quote_lot_size;
+        if quote_taken_wo_self > 0 {
+            taker_fees = fees.taker_fees_ceil(quote_taken_wo_self);
+            referrer_amount = taker_fees - maker_rebates;
+        }
+        let taker_fees_lots = (taker_fees + quote_lot_size - 1) / quote_lot_size;
+        remaining_quote_lots =
+            order.max_quote_lots_including_fees - total_quote_lots_taken - taker_fees_lots as i64;
+
+        for (key, quantity) in changes {
+            next.orders
+                .iter_mut()
+                .find(|o| o.key == key)
+                .unwrap()
+                .quantity = quantity;
+        }
+        next.orders.retain(|o| !deletes.contains(&o.key));
+
+        let peg_limit = order.peg_limit();
+        let is_oracle_peg = peg_limit != -1;
+        let price = if is_oracle_peg && side == Side::Bid {
+            peg_limit
+        } else {
+            price_lots
+        };
+
+        remaining_quote_lots -= fees.maker_fees_ceil(remaining_quote_lots.into()) as i64;
+        let book_base_lots = remaining_base_lots.min(remaining_quote_lots / price);
+        if book_base_lots <= 0 || (is_oracle_peg && side.is_price_better(price_lots, peg_limit)) {
+            post_target = None;
+        }
+
+        if order.is_fill_or_kill() && remaining_base_lots > 0 {
+            return Err(error_code(OpenBookError::WouldExecutePartially));
+        }
+
+        let mut posted_base_native = 0_i128;
+        let mut posted_quote_native = 0_i128;
+        let mut maker_fees = 0_i128;
+        if let Some(tree) = post_target {
+            if i128::from(book_base_lots) * i128::from(price)
+                > i128::from(i64::MAX / market.quote_lot_size)
+            {
+                return Err(error_code(OpenBookError::InvalidPostAmount));
+            }
+            posted_base_native = i128::from(book_base_lots) * base_lot_size;
+            posted_quote_native = i128::from(book_base_lots) * i128::from(price) * quote_lot_size;
+            if side == Side::Bid {
+                maker_fees = fees.maker_fees_ceil(posted_quote_native);
+            }
+
+            let other_tree = match tree {
+                BookSideOrderTree::Fixed => BookSideOrderTree::OraclePegged,
+                BookSideOrderTree::OraclePegged => BookSideOrderTree::Fixed,
+            };
+            let expired = next
+                .earliest_expired(side, tree, now_ts)
+                .or_else(|| next.earliest_expired(side, other_tree, now_ts));
+            if let Some(i) = expired {
+                let expired_order = next.orders.remove(i);
+                next.drop_order(&expired_order, taker, now_ts, &mut events, &mut freed);
+            }
+
+            let slot = (0..MAX_OPEN_ORDERS)
+                .find(|&slot| !next.slots[taker][slot])
+                .ok_or(error_code(OpenBookError::OpenOrdersFull))?;
+            next.slots[taker][slot] = true;
+            next.orders.push(RefOrder {
+                side,
+                tree,
+                key: order_id,
+                seq_num: next.seq_num,
+                owner: taker,
+                slot: slot as u8,
+                quantity: book_base_lots,
+                timestamp: now_ts,
+                time_in_force: order.time_in_force,
+                peg_limit,
+                locked_price: price,
+                client_order_id: order.client_order_id,
+            });
+        }
+
+        for (owner, slot) in freed {
+            next.slots[owner][slot as usize] = false;
+        }
+
+        let amounts = OrderWithAmounts {
+            order_id: post_target.map(|_| order_id),
+            posted_base_native: posted_base_native as u64,
+            posted_quote_native: posted_quote_native as u64,
+            total_base_taken_native: (i128::from(total_base_lots_taken) * base_lot_size) as u64,
+            total_quote_taken_native: (i128::from(total_quote_lots_taken) * quote_lot_size) as u64,
+            taker_fees: taker_fees as u64,
+            maker_fees: maker_fees as u64,
+            referrer_amount: referrer_amount as u64,
+        };
+        Ok((next, Expected { amounts, events }))
+    }
+
+    fn resting(&self) -> Vec<Resting> {
+        let mut resting: Vec<Resting> = self.orders.iter().map(RefOrder::resting).collect();
+        resting.sort();
+        resting
+    }
+}
+
+fn orderbook<'a>(
+    bids: &'a RefCell<Box<BookSide>>,
+    asks: &'a RefCell<Box<BookSide>>,
+) -> Orderbook<'a> {
+    Orderbook {
+        bids: RefMut::map(bids.borrow_mut(), |b| b.as_mut()),
+        asks: RefMut::map(asks.borrow_mut(), |b| b.as_mut()),
+    }
+}
+
+fn engine_resting(book: &Orderbook) -> Vec<Resting> {
+    let mut resting = vec![];
+    for side in [Side::Bid, Side::Ask] {
+        let bookside = book.bookside(side);
+        for tree in [BookSideOrderTree::Fixed, BookSideOrderTree::OraclePegged] {
+            for (_, leaf) in bookside.nodes.iter(bookside.root(tree)) {
+                resting.push(Resting::from_leaf(side, tree, leaf));
+            }
+        }
+    }
+    resting.sort();
+    resting
+}
+
+fn run(input: &FuzzInput) {
+    let mut market = input.market();
+    let bids = RefCell::new(Box::new(BookSide::zeroed()));
+    let asks = RefCell::new(Box::new(BookSide::zeroed()));
+    orderbook(&bids, &asks).init();
+    let mut event_heap = Box::new(EventHeap::zeroed());
+    event_heap.init();
+    let mut accounts: Vec<Box<OpenOrdersAccount>> = (0..OWNERS)
+        .map(|_| OpenOrdersAccount::default_for_tests())
+        .collect();
+
+    let mut reference = Reference::new();
+    let mut now_ts = START_TS;
+
+    for fuzz_order in &input.orders {
+        now_ts += u64::from(fuzz_order.advance_secs);
+        let taker = fuzz_order.owner as usize % OWNERS;
+        let order = fuzz_order.order();
+        // oracle prices are never negative, see Market::native_price_to_lot()
+        let oracle_price_lots = fuzz_order.oracle_price_lots.map(|price| price & i64::MAX);
+
+        // place_order rejects these before matching
+        if order.max_base_lots < 0 || order.max_quote_lots_including_fees < 0 {
+            continue;
+        }
+
+        // new_order() sizes a pegged bid at its peg_limit, it must not be zero
+        if order.side == Side::Bid && order.peg_limit() == 0 {
+            continue;
+        }
+
+        let expected = reference.new_order(
+            &market,
+            &order,
+            taker,
+            now_ts,
+            oracle_price_lots,
+            fuzz_order.limit,
+        );
+
+        // The owner has to deposit what its orders lock. Skipping orders that would lock more
+        // than i64::MAX of a token keeps the position counters of the accounts in range.
+        if let Ok((next, _)) = &expected {
+            let (base, quote) = next.locked_native(&market, taker);
+            if base > i128::from(i64::MAX) || quote > i128::from(i64::MAX) {
+                continue;
+            }
+        }
+
+        // A failing instruction reverts all account changes
+        let snapshot = (
+            market,
+            bids.borrow().clone(),
+            asks.borrow().clone(),
+            event_heap.clone(),
+            accounts[taker].clone(),
+        );
+
+        let result = orderbook(&bids, &asks).new_order(
+            &order,
+            &mut market,
+            &Pubkey::default(),
+            &mut event_heap,
+            oracle_price_lots,
+            Some(accounts[taker].as_mut()),
+            &owner_key(taker),
+            now_ts,
+            fuzz_order.limit,
+            HashMap::<Pubkey, OpenOrdersAccount>::new(),
+        );
+
+        match (result, expected) {
+            (Ok(amounts), Ok((next, expected))) => {
+                assert_eq!(amounts, expected.amounts);
+
+                let mut events = vec![];
+                while !event_heap.is_empty() {
+                    events.push(event_heap.pop_front().unwrap());
+                }
+                assert_eq!(events.len(), expected.events.len(), "{events:?}");
+                for (event, expected) in events.iter().zip(expected.events.iter()) {
+                    assert_eq!(
+                        bytemuck::bytes_of(event),
+                        bytemuck::bytes_of(expected),
+                        "engine {event:?}, reference {expected:?}"
+                    );
+                }
+
+                // Consume the events like the consume_events instruction
+                for event in events.iter() {
+                    match Event::decode(event).unwrap() {
+                        Event::Fill(fill) => {
+                            accounts[owner_index(&fill.maker)].execute_maker(&mut market, &fill)
+                        }
+                        Event::Out(out) => accounts[owner_index(&out.owner)].cancel_order(
+                            out.owner_slot as usize,
+                            out.quantity,
+                            market,
+                        ),
+                    }
+                }
+
+                // Settle like the settle_funds instruction, free balances only grow otherwise
+                for account in accounts.iter_mut() {
+                    let position = &mut account.position;
+                    market.referrer_rebates_accrued -= position.referrer_rebates_available;
+                    position.base_free_native = 0;
+                    position.quote_free_native = 0;
+                    position.referrer_rebates_available = 0;
+                }
+
+                reference = next;
+            }
+            (Err(error), Err(expected_code)) => {
+                let code = match error {
+                    Error::AnchorError(error) => error.error_code_number,
+                    Error::ProgramError(error) => panic!("unexpected program error {error:?}"),
+                };
+                assert_eq!(code, expected_code);
+
+                let (old_market, old_bids, old_asks, old_event_heap, old_account) = snapshot;
+                market = old_market;
+                *bids.borrow_mut() = old_bids;
+                *asks.borrow_mut() = old_asks;
+                event_heap = old_event_heap;
+                accounts[taker] = old_account;
+            }
+            (result, expected) => {
+                panic!("engine returned {result:?}, reference expected {expected:?}")
+            }
+        }
+
+        assert_eq!(market.seq_num, reference.seq_num);
+        assert_eq!(event_heap.header.seq_num, reference.heap_seq_num);
+
+        let book = orderbook(&bids, &asks);
+        book.bids.validate().unwrap();
+        book.asks.validate().unwrap();
+        assert_eq!(engine_resting(&book), reference.resting());
+
+        for (owner, account) in accounts.iter().enumerate() {
+            for (slot, open_order) in account.open_orders.iter().enumerate() {
+                assert_eq!(
+                    !open_order.is_free(),
+                    reference.slots[owner][slot],
+                    "owner {owner} slot {slot}"
+                );
+            }
+        }
+    }
+}
+
+fuzz_target!(|input: FuzzInput| {
+    run(&input);
+});
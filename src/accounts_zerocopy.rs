@@ -106,10 +106,65 @@ impl<'info, 'a> KeyedAccountReader for AccountInfoRefMut<'info, 'a> {
     }
 }
 
+/// An AccountSharedData together with its pubkey, for use in client code
+#[cfg(feature = "client")]
+#[derive(Clone)]
+pub struct KeyedAccountSharedData {
+    pub key: Pubkey,
+    pub data: solana_sdk::account::AccountSharedData,
+}
+
+#[cfg(feature = "client")]
+impl KeyedAccountSharedData {
+    pub fn new(key: Pubkey, data: solana_sdk::account::AccountSharedData) -> Self {
+        Self { key, data }
+    }
+}
+
+#[cfg(feature = "client")]
+impl AccountReader for KeyedAccountSharedData {
+    fn owner(&self) -> &Pubkey {
+        solana_sdk::account::ReadableAccount::owner(&self.data)
+    }
+
+    fn data(&self) -> &[u8] {
+        solana_sdk::account::ReadableAccount::data(&self.data)
+    }
+}
+
+#[cfg(feature = "client")]
+impl KeyedAccountReader for KeyedAccountSharedData {
+    fn key(&self) -> &Pubkey {
+        &self.key
+    }
+}
+
 //
 // Common traits for loading from account data.
 //
 
+/// Cast the data of a program account to `T`, after checking its discriminator and length
+///
+/// For account types that don't implement ZeroCopy. The data must be 8 byte aligned, like
+/// the data of accounts is.
+pub fn cast_account_data<'a, T: bytemuck::Pod>(
+    data: &'a [u8],
+    discriminator: &[u8; 8],
+) -> Result<&'a T> {
+    if data.len() < 8 {
+        return Err(ErrorCode::AccountDiscriminatorNotFound.into());
+    }
+    let disc_bytes = array_ref![data, 0, 8];
+    if disc_bytes != discriminator {
+        return Err(ErrorCode::AccountDiscriminatorMismatch.into());
+    }
+    let end = mem::size_of::<T>() + 8;
+    if data.len() < end {
+        return Err(ErrorCode::AccountDidNotDeserialize.into());
+    }
+    bytemuck::try_from_bytes(&data[8..end]).map_err(|_| ErrorCode::AccountDidNotDeserialize.into())
+}
+
 pub trait LoadZeroCopy {
     /// Using AccountLoader forces a AccountInfo.clone() and then binds the loaded
     /// lifetime to the AccountLoader's lifetime. This function avoids both.
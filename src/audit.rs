@@ -0,0 +1,607 @@
+//! Solvency audit of a market over a full snapshot of its accounts.
+//!
+//! Every token in a market vault is owed to someone: to an open orders account, either free or
+//! locked in a resting order, to a referrer or to the fee collector. `audit_market` adds up these
+//! claims and compares them to the deposit totals of the market and to the vault balances. Events
+//! still on the heap move tokens between claims once consumed, so their effect is included.
+//!
+//! It also checks that the open order slots of the accounts and the leaves of the book agree.
+
+use anchor_lang::prelude::*;
+use std::collections::HashMap;
+
+use crate::state::*;
+
+/// All accounts of a market at one point in time
+#[derive(Clone, Copy)]
+pub struct MarketSnapshot<'a> {
+    pub market: &'a Market,
+    pub bids: &'a BookSide,
+    pub asks: &'a BookSide,
+    pub event_heap: &'a EventHeap,
+    /// Every open orders account of the market, with its address
+    pub open_orders_accounts: &'a [(Pubkey, OpenOrdersAccount)],
+    pub base_vault_balance: u64,
+    pub quote_vault_balance: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Base,
+    Quote,
+}
+
+/// The claims on one token of the market, in native units
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenClaims {
+    /// Free balances of all accounts
+    pub free: i128,
+    /// Locked in resting orders: asks_base_lots for base, bids_quote_lots for quote
+    pub locked: i128,
+    /// Maker fees locked together with resting bids (quote only)
+    pub locked_maker_fees: i128,
+    /// Referrer rebates accrued and fees available to sweep (quote only)
+    pub fees: i128,
+    /// Net change of the claims once the events on the heap are consumed
+    pub pending: i128,
+}
+
+impl TokenClaims {
+    pub fn total(&self) -> i128 {
+        self.free + self.locked + self.locked_maker_fees + self.fees + self.pending
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SolvencyIssue {
+    /// The claims on a token don't add up to the deposit total of the market
+    DepositTotalMismatch {
+        token: Token,
+        claims: i128,
+        deposit_total: u64,
+    },
+    /// The vault holds less than the deposit total of the market
+    VaultShortfall {
+        token: Token,
+        vault_balance: u64,
+        deposit_total: u64,
+    },
+    /// market.referrer_rebates_accrued differs from the sum over the accounts
+    ReferrerRebatesMismatch { accrued: u64, accounts: i128 },
+    /// A position counter of an account doesn't match its orders on the book and the heap
+    PositionMismatch {
+        open_orders_account: Pubkey,
+        field: &'static str,
+        position: i64,
+        orders: i128,
+    },
+    /// An occupied open order slot without its leaf on the book or an event that frees it
+    MissingOrder {
+        open_orders_account: Pubkey,
+        slot: usize,
+        order_id: u128,
+    },
+    /// An open order slot whose leaf has a different id, side or order tree
+    MismatchedOrder {
+        open_orders_account: Pubkey,
+        slot: usize,
+        order_id: u128,
+        leaf_order_id: u128,
+    },
+    /// A leaf on the book that no occupied open order slot refers to
+    OrphanedOrder {
+        side: Side,
+        order_id: u128,
+        owner: Pubkey,
+        owner_slot: u8,
+    },
+    /// An event on the heap for an unknown account or a free slot
+    DanglingEvent {
+        heap_slot: usize,
+        owner: Pubkey,
+        owner_slot: u8,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SolvencyReport {
+    pub base: TokenClaims,
+    pub quote: TokenClaims,
+    pub issues: Vec<SolvencyIssue>,
+}
+
+impl SolvencyReport {
+    /// Whether the market is fully collateralized and its accounts are consistent
+    pub fn is_solvent(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+struct BookOrder {
+    side: Side,
+    order_tree: BookSideOrderTree,
+    order_id: u128,
+    quantity: i64,
+}
+
+/// What the book and the heap hold for one (account, owner_slot)
+#[derive(Default)]
+struct SlotOrders {
+    leaf: Option<BookOrder>,
+    /// Base lots of fill and out events that haven't been consumed yet
+    pending_base_lots: i64,
+    /// Whether one of these events frees the slot
+    pending_removal: bool,
+    heap_slots: Vec<usize>,
+}
+
+/// Check the conservation invariants of the market in `snapshot`
+///
+/// Only fails if an event on the heap can't be decoded, everything else ends up in the report.
+pub fn audit_market(snapshot: &MarketSnapshot) -> Result<SolvencyReport> {
+    let market = snapshot.market;
+    let base_lot_size = i128::from(market.base_lot_size);
+    let quote_lot_size = i128::from(market.quote_lot_size);
+
+    let mut base = TokenClaims::default();
+    let mut quote = TokenClaims::default();
+    let mut issues = vec![];
+
+    let mut slots: HashMap<(Pubkey, u8), SlotOrders> = HashMap::new();
+
+    for (side, book_side) in [(Side::Bid, snapshot.bids), (Side::Ask, snapshot.asks)] {
+        for order_tree in [BookSideOrderTree::Fixed, BookSideOrderTree::OraclePegged] {
+            for (_, leaf) in book_side.nodes.iter(book_side.root(order_tree)) {
+                let order = BookOrder {
+                    side,
+                    order_tree,
+                    order_id: leaf.key.into(),
+                    quantity: leaf.quantity,
+                };
+                let slot = slots.entry((leaf.owner, leaf.owner_slot)).or_default();
+                if slot.leaf.is_some() {
+                    issues.push(SolvencyIssue::OrphanedOrder {
+                        side,
+                        order_id: order.order_id,
+                        owner: leaf.owner,
+                        owner_slot: leaf.owner_slot,
+                    });
+                } else {
+                    slot.leaf = Some(order);
+                }
+            }
+        }
+    }
+
+    // See OpenOrdersAccount::execute_maker() and cancel_order() for the effect of consuming
+    for item in snapshot.event_heap.iter_events() {
+        let (heap_slot, event) = item?;
+        let (owner, owner_slot, quantity, frees_slot) = match event {
+            Event::Fill(fill) => {
+                let base_native = i128::from(fill.quantity) * base_lot_size;
+                let quote_native =
+                    i128::from(fill.quantity) * i128::from(fill.price) * quote_lot_size;
+                let maker_rebate = if fill.maker == fill.taker {
+                    0
+                } else {
+                    i128::from(market.maker_rebate_floor(quote_native as u64))
+                };
+                match fill.taker_side().invert_side() {
+                    // the maker receives base and the unused part of its locked quote
+                    Side::Bid => {
+                        base.pending += base_native;
+                        quote.pending += maker_rebate - quote_native;
+                    }
+                    // the maker receives quote, base was already credited to the taker
+                    Side::Ask => {
+                        base.pending -= base_native;
+                        quote.pending += quote_native + maker_rebate;
+                    }
+                }
+                (fill.maker, fill.maker_slot, fill.quantity, fill.maker_out())
+            }
+            // canceling only moves locked funds to free ones
+            Event::Out(out) => (out.owner, out.owner_slot, out.quantity, true),
+        };
+        let slot = slots.entry((owner, owner_slot)).or_default();
+        slot.pending_base_lots += quantity;
+        slot.pending_removal |= frees_slot;
+        slot.heap_slots.push(heap_slot);
+    }
+
+    let mut referrer_rebates = 0_i128;
+    for (key, account) in snapshot.open_orders_accounts {
+        let position = &account.position;
+        base.free += i128::from(position.base_free_native);
+        base.locked += i128::from(position.asks_base_lots) * base_lot_size;
+        quote.free += i128::from(position.quote_free_native);
+        quote.locked += i128::from(position.bids_quote_lots) * quote_lot_size;
+        quote.locked_maker_fees += i128::from(position.locked_maker_fees);
+        referrer_rebates += i128::from(position.referrer_rebates_available);
+
+        let mut bids_base_lots = 0_i128;
+        let mut bids_quote_lots = 0_i128;
+        let mut asks_base_lots = 0_i128;
+        for (slot, open_order) in account.open_orders.iter().enumerate() {
+            if open_order.is_free() {
+                continue;
+            }
+            let side_and_tree = open_order.side_and_tree();
+            let orders = slots.remove(&(*key, slot as u8)).unwrap_or_default();
+
+            match &orders.leaf {
+                Some(leaf) => {
+                    if leaf.order_id != open_order.id
+                        || leaf.side != side_and_tree.side()
+                        || leaf.order_tree != side_and_tree.order_tree()
+                    {
+                        issues.push(SolvencyIssue::MismatchedOrder {
+                            open_orders_account: *key,
+                            slot,
+                            order_id: open_order.id,
+                            leaf_order_id: leaf.order_id,
+                        });
+                    }
+                }
+                None if !orders.pending_removal => {
+                    issues.push(SolvencyIssue::MissingOrder {
+                        open_orders_account: *key,
+                        slot,
+                        order_id: open_order.id,
+                    });
+                }
+                None => {}
+            }
+
+            let lots = i128::from(orders.leaf.map_or(0, |leaf| leaf.quantity))
+                + i128::from(orders.pending_base_lots);
+            match side_and_tree.side() {
+                Side::Bid => {
+                    bids_base_lots += lots;
+                    bids_quote_lots += lots * i128::from(open_order.locked_price);
+                }
+                Side::Ask => asks_base_lots += lots,
+            }
+        }
+
+        for (field, position, orders) in [
+            ("bids_base_lots", position.bids_base_lots, bids_base_lots),
+            ("bids_quote_lots", position.bids_quote_lots, bids_quote_lots),
+            ("asks_base_lots", position.asks_base_lots, asks_base_lots),
+        ] {
+            if i128::from(position) != orders {
+                issues.push(SolvencyIssue::PositionMismatch {
+                    open_orders_account: *key,
+                    field,
+                    position,
+                    orders,
+                });
+            }
+        }
+    }
+
+    // whatever is left refers to slots that aren't occupied
+    let mut leftover: Vec<((Pubkey, u8), SlotOrders)> = slots.into_iter().collect();
+    leftover.sort_by_key(|(key, _)| *key);
+    for ((owner, owner_slot), orders) in leftover {
+        if let Some(leaf) = orders.leaf {
+            issues.push(SolvencyIssue::OrphanedOrder {
+                side: leaf.side,
+                order_id: leaf.order_id,
+                owner,
+                owner_slot,
+            });
+        }
+        for heap_slot in orders.heap_slots {
+            issues.push(SolvencyIssue::DanglingEvent {
+                heap_slot,
+                owner,
+                owner_slot,
+            });
+        }
+    }
+
+    quote.fees = i128::from(market.referrer_rebates_accrued) + i128::from(market.fees_available);
+    if referrer_rebates != i128::from(market.referrer_rebates_accrued) {
+        issues.push(SolvencyIssue::ReferrerRebatesMismatch {
+            accrued: market.referrer_rebates_accrued,
+            accounts: referrer_rebates,
+        });
+    }
+
+    for (token, claims, deposit_total, vault_balance) in [
+        (
+            Token::Base,
+            &base,
+            market.base_deposit_total,
+            snapshot.base_vault_balance,
+        ),
+        (
+            Token::Quote,
+            &quote,
+            market.quote_deposit_total,
+            snapshot.quote_vault_balance,
+        ),
+    ] {
+        if claims.total() != i128::from(deposit_total) {
+            issues.push(SolvencyIssue::DepositTotalMismatch {
+                token,
+                claims: claims.total(),
+                deposit_total,
+            });
+        }
+        if vault_balance < deposit_total {
+            issues.push(SolvencyIssue::VaultShortfall {
+                token,
+                vault_balance,
+                deposit_total,
+            });
+        }
+    }
+
+    Ok(SolvencyReport {
+        base,
+        quote,
+        issues,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::tests::*;
+    use crate::executor::Executor;
+
+    fn audit(ex: &Executor, market_pk: &Pubkey) -> SolvencyReport {
+        let accounts = &ex.markets[market_pk];
+        let open_orders_accounts: Vec<(Pubkey, OpenOrdersAccount)> = ex
+            .open_orders_accounts
+            .iter()
+            .filter(|(_, account)| account.market == *market_pk)
+            .map(|(key, account)| (*key, *account))
+            .collect();
+        let bids = accounts.bids.borrow();
+        let asks = accounts.asks.borrow();
+        audit_market(&MarketSnapshot {
+            market: &accounts.market,
+            bids: &bids,
+            asks: &asks,
+            event_heap: &accounts.event_heap,
+            open_orders_accounts: &open_orders_accounts,
+            base_vault_balance: ex.tokens.balance(&accounts.market.market_base_vault),
+            quote_vault_balance: ex.tokens.balance(&accounts.market.market_quote_vault),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_audit_market() {
+        let mut ex = Executor::default();
+        ex.clock.unix_timestamp = 1_700_000_000;
+        let market_pk = create_market(&mut ex);
+        let maker = create_user(&mut ex, &market_pk, 1_000, 100_000);
+        let taker = create_user(&mut ex, &market_pk, 1_000, 100_000);
+
+        let bid_id = ex
+            .place_order(
+                &maker.open_orders_account,
+                &maker.owner,
+                &maker.quote_account,
+                order(
+                    Side::Bid,
+                    5,
+                    OrderParams::Fixed {
+                        price_lots: 1000,
+                        order_type: PostOrderType::Limit,
+                    },
+                ),
+                10,
+            )
+            .unwrap()
+            .unwrap();
+        let mut expiring_ask = order(
+            Side::Ask,
+            2,
+            OrderParams::Fixed {
+                price_lots: 2000,
+                order_type: PostOrderType::Limit,
+            },
+        );
+        expiring_ask.time_in_force = 5;
+        ex.place_order(
+            &maker.open_orders_account,
+            &maker.owner,
+            &maker.base_account,
+            expiring_ask,
+            10,
+        )
+        .unwrap();
+        assert!(audit(&ex, &market_pk).is_solvent());
+
+        // a pending fill and a pending out event
+        ex.place_order(
+            &taker.open_orders_account,
+            &taker.owner,
+            &taker.base_account,
+            order(
+                Side::Ask,
+                3,
+                OrderParams::ImmediateOrCancel { price_lots: 1000 },
+            ),
+            10,
+        )
+        .unwrap();
+        ex.clock.unix_timestamp += 10;
+        ex.place_order(
+            &taker.open_orders_account,
+            &taker.owner,
+            &taker.quote_account,
+            order(
+                Side::Bid,
+                1,
+                OrderParams::ImmediateOrCancel { price_lots: 2000 },
+            ),
+            10,
+        )
+        .unwrap();
+        assert_eq!(ex.markets[&market_pk].event_heap.len(), 2);
+
+        let report = audit(&ex, &market_pk);
+        assert_eq!(report.issues, vec![]);
+        assert_eq!(report.base.pending, 300);
+        assert_eq!(report.quote.pending, 6 - 30_000); // maker rebate minus the filled quote
+        assert_eq!(report.base.total(), 500);
+        assert_eq!(report.quote.total(), 50_000);
+
+        ex.consume_events(&market_pk, 10, None).unwrap();
+        let report = audit(&ex, &market_pk);
+        assert!(report.is_solvent());
+        assert_eq!(report.quote.pending, 0);
+
+        for user in [&maker, &taker] {
+            ex.settle_funds(
+                &user.open_orders_account,
+                &user.owner,
+                &user.base_account,
+                &user.quote_account,
+                None,
+            )
+            .unwrap();
+        }
+        let report = audit(&ex, &market_pk);
+        assert!(report.is_solvent());
+        assert_eq!(report.quote.locked, 20_000);
+        assert_eq!(report.quote.fees, 6);
+
+        // an account claims more than was deposited
+        let mut broken = ex.clone();
+        broken
+            .open_orders_accounts
+            .get_mut(&maker.open_orders_account)
+            .unwrap()
+            .position
+            .quote_free_native += 1;
+        assert_eq!(
+            audit(&broken, &market_pk).issues,
+            vec![SolvencyIssue::DepositTotalMismatch {
+                token: Token::Quote,
+                claims: 20_007,
+                deposit_total: 20_006,
+            }]
+        );
+
+        // the bid vanished from the book while the slot is still occupied
+        let mut broken = ex.clone();
+        broken.markets[&market_pk]
+            .bids
+            .borrow_mut()
+            .remove_by_key(BookSideOrderTree::Fixed, bid_id)
+            .unwrap();
+        let issues = audit(&broken, &market_pk).issues;
+        assert_eq!(
+            issues[0],
+            SolvencyIssue::MissingOrder {
+                open_orders_account: maker.open_orders_account,
+                slot: 0,
+                order_id: bid_id,
+            }
+        );
+        assert!(issues.contains(&SolvencyIssue::PositionMismatch {
+            open_orders_account: maker.open_orders_account,
+            field: "bids_base_lots",
+            position: 2,
+            orders: 0,
+        }));
+
+        // tokens left the vault without going through the market
+        let mut broken = ex.clone();
+        let market = broken.markets[&market_pk].market;
+        broken
+            .tokens
+            .transfer(
+                1,
+                &market.market_quote_vault,
+                &maker.quote_account,
+                &market.market_authority,
+            )
+            .unwrap();
+        assert_eq!(
+            audit(&broken, &market_pk).issues,
+            vec![SolvencyIssue::VaultShortfall {
+                token: Token::Quote,
+                vault_balance: 20_005,
+                deposit_total: 20_006,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_audit_pegged_bid_fill() {
+        let mut ex = Executor::default();
+        ex.clock.slot = 1000;
+        ex.clock.unix_timestamp = 1_700_000_000;
+        let oracle = create_stub_oracle(&mut ex, 10.0, 1000);
+        let mut args = market_args();
+        args.oracle_a = Some(oracle);
+        // 10.0 is exactly 100 lots
+        args.base_decimals = 6;
+        let market_pk = Pubkey::new_unique();
+        ex.create_market(market_pk, args).unwrap();
+        let maker = create_user(&mut ex, &market_pk, 0, 100_000);
+        let taker = create_user(&mut ex, &market_pk, 5_000, 0);
+
+        // the quote is locked at the peg_limit
+        ex.place_order(
+            &maker.open_orders_account,
+            &maker.owner,
+            &maker.quote_account,
+            order(
+                Side::Bid,
+                50,
+                OrderParams::OraclePegged {
+                    price_offset_lots: -1,
+                    order_type: PostOrderType::Limit,
+                    peg_limit: 100,
+                },
+            ),
+            10,
+        )
+        .unwrap();
+        let report = audit(&ex, &market_pk);
+        assert!(report.is_solvent());
+        assert_eq!(report.quote.locked, 50_000);
+
+        ex.place_order(
+            &taker.open_orders_account,
+            &taker.owner,
+            &taker.base_account,
+            order(
+                Side::Ask,
+                30,
+                OrderParams::ImmediateOrCancel { price_lots: 99 },
+            ),
+            10,
+        )
+        .unwrap();
+        assert_eq!(ex.markets[&market_pk].event_heap.len(), 1);
+
+        let report = audit(&ex, &market_pk);
+        assert_eq!(report.issues, vec![]);
+        assert_eq!(report.base.pending, 3_000);
+        assert_eq!(report.quote.pending, 5 - 29_700); // maker rebate minus the filled quote
+        assert_eq!(report.quote.total(), 50_000);
+
+        // consuming frees the quote locked above the fill price
+        ex.consume_events(&market_pk, 10, None).unwrap();
+        let report = audit(&ex, &market_pk);
+        assert!(report.is_solvent());
+        assert_eq!(report.quote.pending, 0);
+        assert_eq!(report.quote.locked, 20_000);
+        assert_eq!(
+            ex.open_orders_accounts[&maker.open_orders_account]
+                .position
+                .quote_free_native,
+            5 + 300
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Planning of consume_events calls for crankers.
+//!
+//! `consume_events` skips events whose OpenOrdersAccount isn't passed as remaining account, but
+//! still counts them against the limit. Passing the first N events blindly therefore wastes
+//! transactions. The planner instead lists the exact slots and accounts of every batch, for use
+//! with `consume_given_events`.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+
+use crate::error::*;
+use crate::instruction_builder;
+use crate::state::*;
+
+/// One `consume_given_events` call
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConsumeEventsBatch {
+    /// Event heap slots, in the order they will be consumed
+    pub slots: Vec<usize>,
+    /// The accounts the events apply to, to be passed as remaining accounts
+    pub open_orders_accounts: Vec<Pubkey>,
+}
+
+impl ConsumeEventsBatch {
+    pub fn instruction(
+        &self,
+        accounts: instruction_builder::accounts::ConsumeEvents,
+    ) -> Instruction {
+        instruction_builder::consume_given_events(
+            accounts,
+            self.slots.clone(),
+            &self.open_orders_accounts,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CrankPlanner {
+    /// Max events per batch, capped at MAX_EVENTS_CONSUME
+    pub max_events: usize,
+    /// Max remaining accounts per batch, to stay below the transaction size limit
+    pub max_accounts: usize,
+}
+
+impl Default for CrankPlanner {
+    fn default() -> Self {
+        Self {
+            max_events: MAX_EVENTS_CONSUME,
+            max_accounts: MAX_EVENTS_CONSUME,
+        }
+    }
+}
+
+impl CrankPlanner {
+    /// Split all events of the heap into batches
+    ///
+    /// Events of the same account are kept together so that each account is passed as few
+    /// times as possible. Per account, events are consumed in heap order.
+    pub fn plan(&self, event_heap: &EventHeap) -> Result<Vec<ConsumeEventsBatch>> {
+        let max_events = self.max_events.min(MAX_EVENTS_CONSUME);
+        require!(
+            max_events > 0 && self.max_accounts > 0,
+            OpenBookError::SomeError
+        );
+
+        // slots grouped by owner, in order of the owner's first event
+        let mut groups: Vec<(Pubkey, Vec<usize>)> = vec![];
+        for item in event_heap.iter_events() {
+            let (slot, event) = item?;
+            let owner = event.owner();
+            match groups.iter_mut().find(|(key, _)| *key == owner) {
+                Some((_, slots)) => slots.push(slot),
+                None => groups.push((owner, vec![slot])),
+            }
+        }
+
+        let mut batches = vec![];
+        let mut batch = ConsumeEventsBatch::default();
+        for (owner, slots) in groups {
+            for slot in slots {
+                let needs_account = !batch.open_orders_accounts.contains(&owner);
+                if batch.slots.len() == max_events
+                    || (needs_account && batch.open_orders_accounts.len() == self.max_accounts)
+                {
+                    batches.push(std::mem::take(&mut batch));
+                }
+                if !batch.open_orders_accounts.contains(&owner) {
+                    batch.open_orders_accounts.push(owner);
+                }
+                batch.slots.push(slot);
+            }
+        }
+        if !batch.slots.is_empty() {
+            batches.push(batch);
+        }
+
+        Ok(batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn out_event(owner: Pubkey) -> AnyEvent {
+        bytemuck::cast(OutEvent::new(Side::Bid, 0, 0, 0, owner, 1))
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut event_heap = EventHeap::zeroed();
+        event_heap.init();
+
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+        let c = Pubkey::new_unique();
+        // a b a c a a a a a a b
+        for owner in [a, b, a, c, a, a, a, a, a, a, b] {
+            event_heap.push_back(out_event(owner));
+        }
+
+        let batches = CrankPlanner::default().plan(&event_heap).unwrap();
+        assert_eq!(
+            batches,
+            vec![
+                ConsumeEventsBatch {
+                    slots: vec![0, 2, 4, 5, 6, 7, 8, 9],
+                    open_orders_accounts: vec![a],
+                },
+                ConsumeEventsBatch {
+                    slots: vec![1, 10, 3],
+                    open_orders_accounts: vec![b, c],
+                },
+            ]
+        );
+
+        let planner = CrankPlanner {
+            max_events: 100,
+            max_accounts: 1,
+        };
+        let batches = planner.plan(&event_heap).unwrap();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[1].slots, vec![1, 10]);
+        assert!(batches.iter().all(|b| b.open_orders_accounts.len() == 1));
+        assert!(batches.iter().all(|b| b.slots.len() <= MAX_EVENTS_CONSUME));
+
+        event_heap.init();
+        assert!(CrankPlanner::default()
+            .plan(&event_heap)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -95,6 +95,18 @@ pub enum OpenBookError {
     NonEmptyOpenOrdersPosition,
     #[msg("Fill-Or-Kill order would generate a partial execution")]
     WouldExecutePartially,
+    #[msg("The oracle price update is not fully verified")]
+    OracleNotFullyVerified,
+    #[msg("The oracle does not support the configured price mode")]
+    OraclePriceModeUnsupported,
+    #[msg("The oracles don't fit the oracle combination")]
+    InvalidOracleCombination,
+    #[msg("The order tree is corrupted")]
+    InvalidOrderTree,
+    #[msg("The oracle price update is for a different feed")]
+    OracleFeedMismatch,
+    #[msg("The oracle config is corrupted")]
+    InvalidOracleConfig,
 }
 
 impl From<OpenBookError> for ProgramError {
@@ -0,0 +1,1472 @@
+//! Offline execution of the program instructions.
+//!
+//! The handlers in `instructions` need the Solana runtime: Anchor contexts, the clock sysvar
+//! and CPIs into the token program. `Executor` applies the state transitions of `processor` to
+//! accounts kept in memory, reads the time from a `Clock` the caller controls and moves tokens
+//! in a stub ledger. That way full order lifecycles can be exercised in plain unit tests.
+//!
+//! Account constraints that only concern admin or payer signatures are not modelled. Event heap
+//! penalties are counted on the open orders account, but no lamports are moved.
+
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use anchor_spl::associated_token::get_associated_token_address;
+use bytemuck::Zeroable;
+use itertools::Itertools;
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+
+use crate::accounts_zerocopy::{KeyedAccountSharedData, LoadZeroCopy};
+use crate::error::*;
+use crate::pda;
+use crate::processor::{self, NewMarketAccounts, Settlement, TakeOrderAmounts};
+use crate::pubkey_option::NonZeroPubkeyOption;
+use crate::state::*;
+
+/// A token account in the stub ledger
+#[derive(Clone, Copy, Debug)]
+pub struct TokenAccount {
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+}
+
+/// Stand-in for the token program, holding token accounts by address
+#[derive(Clone, Default)]
+pub struct TokenLedger {
+    pub accounts: HashMap<Pubkey, TokenAccount>,
+}
+
+impl TokenLedger {
+    pub fn create_account(&mut self, key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) {
+        self.accounts.insert(
+            key,
+            TokenAccount {
+                mint,
+                owner,
+                amount,
+            },
+        );
+    }
+
+    pub fn balance(&self, key: &Pubkey) -> u64 {
+        self.accounts.get(key).map(|acc| acc.amount).unwrap_or(0)
+    }
+
+    /// Same checks as token::transfer: `authority` must own `from` and the mints must match
+    pub fn transfer(
+        &mut self,
+        amount: u64,
+        from: &Pubkey,
+        to: &Pubkey,
+        authority: &Pubkey,
+    ) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let source = *self
+            .accounts
+            .get(from)
+            .ok_or_else(|| error_msg!("token account {} not found", from))?;
+        let destination = self
+            .accounts
+            .get(to)
+            .ok_or_else(|| error_msg!("token account {} not found", to))?;
+        require_keys_eq!(source.owner, *authority);
+        require_keys_eq!(source.mint, destination.mint);
+        require_gte!(source.amount, amount);
+
+        self.accounts.get_mut(from).unwrap().amount -= amount;
+        self.accounts.get_mut(to).unwrap().amount += amount;
+        Ok(())
+    }
+}
+
+/// A market together with the accounts it owns
+#[derive(Clone)]
+pub struct MarketAccounts {
+    pub market: Market,
+    pub bids: RefCell<Box<BookSide>>,
+    pub asks: RefCell<Box<BookSide>>,
+    pub event_heap: Box<EventHeap>,
+}
+
+/// Arguments and accounts of `create_market`
+#[derive(Clone, Debug)]
+pub struct CreateMarketArgs {
+    pub name: String,
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
+    /// Addresses of the book sides and the event heap, created empty by the caller
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub oracle_a: Option<Pubkey>,
+    pub oracle_b: Option<Pubkey>,
+    /// Third oracle of the Median combination
+    pub oracle_c: Option<Pubkey>,
+    pub oracle_config: OracleConfigParams,
+    pub quote_lot_size: i64,
+    pub base_lot_size: i64,
+    pub maker_fee: i64,
+    pub taker_fee: i64,
+    pub time_expiry: i64,
+    pub collect_fee_admin: Pubkey,
+    pub open_orders_admin: Option<Pubkey>,
+    pub consume_events_admin: Option<Pubkey>,
+    pub close_market_admin: Option<Pubkey>,
+}
+
+/// In-memory account store that the instructions are executed against
+///
+/// Every instruction is atomic: if it fails, all accounts are restored to their previous
+/// state, like they would be for a failed transaction.
+#[derive(Clone, Default)]
+pub struct Executor {
+    pub clock: Clock,
+    pub markets: HashMap<Pubkey, MarketAccounts>,
+    pub open_orders_accounts: HashMap<Pubkey, OpenOrdersAccount>,
+    pub open_orders_indexers: HashMap<Pubkey, OpenOrdersIndexer>,
+    pub oracles: HashMap<Pubkey, KeyedAccountSharedData>,
+    pub tokens: TokenLedger,
+}
+
+impl Executor {
+    fn atomic<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let snapshot = self.clone();
+        let result = f(self);
+        if result.is_err() {
+            *self = snapshot;
+        }
+        result
+    }
+
+    /// Whether `key` is a market or one of the accounts of a market
+    fn is_market_account(&self, key: &Pubkey) -> bool {
+        self.markets.iter().any(|(market_pk, accounts)| {
+            let market = &accounts.market;
+            [market_pk, &market.bids, &market.asks, &market.event_heap].contains(&key)
+        })
+    }
+
+    pub fn create_market(&mut self, market_pk: Pubkey, args: CreateMarketArgs) -> Result<()> {
+        self.atomic(|ex| {
+            let keys = [market_pk, args.bids, args.asks, args.event_heap];
+            require!(
+                keys.iter().all_unique() && !keys.iter().any(|key| ex.is_market_account(key)),
+                ErrorCode::AccountDiscriminatorAlreadySet
+            );
+
+            let (market_authority, bump) = pda::market_authority(&market_pk);
+            let market_base_vault =
+                get_associated_token_address(&market_authority, &args.base_mint);
+            let market_quote_vault =
+                get_associated_token_address(&market_authority, &args.quote_mint);
+
+            let accounts = NewMarketAccounts {
+                market: market_pk,
+                market_authority,
+                market_authority_bump: bump,
+                bids: args.bids,
+                asks: args.asks,
+                event_heap: args.event_heap,
+                base_mint: args.base_mint,
+                quote_mint: args.quote_mint,
+                base_decimals: args.base_decimals,
+                quote_decimals: args.quote_decimals,
+                market_base_vault,
+                market_quote_vault,
+                collect_fee_admin: args.collect_fee_admin,
+                open_orders_admin: args.open_orders_admin.into(),
+                consume_events_admin: args.consume_events_admin.into(),
+                close_market_admin: args.close_market_admin.into(),
+            };
+            let oracles = [
+                oracle_account(&ex.oracles, args.oracle_a.into())?,
+                oracle_account(&ex.oracles, args.oracle_b.into())?,
+                oracle_account(&ex.oracles, args.oracle_c.into())?,
+            ];
+
+            let market = processor::create_market(
+                &accounts,
+                oracles,
+                &args.name,
+                &args.oracle_config,
+                args.quote_lot_size,
+                args.base_lot_size,
+                args.maker_fee,
+                args.taker_fee,
+                args.time_expiry,
+                &ex.clock,
+            )?;
+
+            ex.tokens
+                .create_account(market_base_vault, args.base_mint, market_authority, 0);
+            ex.tokens
+                .create_account(market_quote_vault, args.quote_mint, market_authority, 0);
+
+            let mut accounts = MarketAccounts {
+                market,
+                bids: RefCell::new(new_book_side()),
+                asks: RefCell::new(new_book_side()),
+                event_heap: Box::new(EventHeap::zeroed()),
+            };
+            orderbook(&accounts.bids, &accounts.asks).init();
+            accounts.event_heap.init();
+
+            ex.markets.insert(market_pk, accounts);
+            Ok(())
+        })
+    }
+
+    pub fn close_market(&mut self, market_pk: &Pubkey) -> Result<()> {
+        self.atomic(|ex| {
+            let accounts = market_accounts_mut(&mut ex.markets, market_pk)?;
+            processor::close_market(
+                &accounts.market,
+                &orderbook(&accounts.bids, &accounts.asks),
+                &accounts.event_heap,
+                &ex.clock,
+            )?;
+
+            ex.markets.remove(market_pk);
+            Ok(())
+        })
+    }
+
+    pub fn create_open_orders_indexer(&mut self, owner: &Pubkey) -> Result<Pubkey> {
+        self.atomic(|ex| {
+            let (indexer_pk, bump) = pda::open_orders_indexer(owner);
+            require!(
+                !ex.open_orders_indexers.contains_key(&indexer_pk),
+                ErrorCode::AccountDiscriminatorAlreadySet
+            );
+
+            ex.open_orders_indexers.insert(
+                indexer_pk,
+                OpenOrdersIndexer {
+                    bump,
+                    created_counter: 0,
+                    addresses: vec![],
+                },
+            );
+            Ok(indexer_pk)
+        })
+    }
+
+    pub fn close_open_orders_indexer(&mut self, owner: &Pubkey) -> Result<()> {
+        self.atomic(|ex| {
+            let (indexer_pk, _) = pda::open_orders_indexer(owner);
+            let indexer = open_orders_indexer_mut(&mut ex.open_orders_indexers, &indexer_pk)?;
+            processor::close_open_orders_indexer(indexer)?;
+
+            ex.open_orders_indexers.remove(&indexer_pk);
+            Ok(())
+        })
+    }
+
+    pub fn create_open_orders_account(
+        &mut self,
+        owner: &Pubkey,
+        market_pk: &Pubkey,
+        name: &str,
+        delegate: Option<Pubkey>,
+    ) -> Result<Pubkey> {
+        self.atomic(|ex| {
+            market_accounts_mut(&mut ex.markets, market_pk)?;
+            let (indexer_pk, _) = pda::open_orders_indexer(owner);
+            let indexer = open_orders_indexer_mut(&mut ex.open_orders_indexers, &indexer_pk)?;
+
+            let (open_orders_account_pk, bump) =
+                pda::open_orders_account(owner, indexer.created_counter + 1);
+
+            let mut account = OpenOrdersAccount::zeroed();
+            processor::create_open_orders_account(
+                &mut account,
+                &open_orders_account_pk,
+                bump,
+                indexer,
+                market_pk,
+                owner,
+                delegate.into(),
+                name,
+            )?;
+
+            ex.open_orders_accounts
+                .insert(open_orders_account_pk, account);
+            Ok(open_orders_account_pk)
+        })
+    }
+
+    pub fn close_open_orders_account(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        owner: &Pubkey,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account =
+                open_orders_account_mut(&mut ex.open_orders_accounts, open_orders_account_pk)?;
+            require_keys_eq!(open_orders_account.owner, *owner, OpenBookError::NoOwner);
+            let (indexer_pk, _) = pda::open_orders_indexer(owner);
+            let indexer = open_orders_indexer_mut(&mut ex.open_orders_indexers, &indexer_pk)?;
+
+            processor::close_open_orders_account(
+                open_orders_account,
+                open_orders_account_pk,
+                indexer,
+            )?;
+
+            ex.open_orders_accounts.remove(open_orders_account_pk);
+            Ok(())
+        })
+    }
+
+    pub fn set_delegate(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        owner: &Pubkey,
+        delegate: Option<Pubkey>,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account =
+                open_orders_account_mut(&mut ex.open_orders_accounts, open_orders_account_pk)?;
+            require_keys_eq!(open_orders_account.owner, *owner, OpenBookError::NoOwner);
+
+            processor::set_delegate(open_orders_account, open_orders_account_pk, delegate);
+            Ok(())
+        })
+    }
+
+    pub fn deposit(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        owner: &Pubkey,
+        user_base_account: &Pubkey,
+        user_quote_account: &Pubkey,
+        base_amount: u64,
+        quote_amount: u64,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account =
+                open_orders_account_mut(&mut ex.open_orders_accounts, open_orders_account_pk)?;
+            let market =
+                &mut market_accounts_mut(&mut ex.markets, &open_orders_account.market)?.market;
+
+            processor::deposit(
+                market,
+                open_orders_account,
+                open_orders_account_pk,
+                owner,
+                base_amount,
+                quote_amount,
+                &ex.clock,
+            )?;
+
+            ex.tokens.transfer(
+                base_amount,
+                user_base_account,
+                &market.market_base_vault,
+                owner,
+            )?;
+            ex.tokens.transfer(
+                quote_amount,
+                user_quote_account,
+                &market.market_quote_vault,
+                owner,
+            )?;
+
+            Ok(())
+        })
+    }
+
+    /// Like the `place_order` instruction without remaining accounts: makers are only
+    /// updated through `consume_events`
+    pub fn place_order(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        signer: &Pubkey,
+        user_token_account: &Pubkey,
+        order: Order,
+        limit: u8,
+    ) -> Result<Option<u128>> {
+        self.atomic(|ex| {
+            let open_orders_account = owned_open_orders_account_mut(
+                &mut ex.open_orders_accounts,
+                open_orders_account_pk,
+                signer,
+            )?;
+
+            let market_pk = open_orders_account.market;
+            let MarketAccounts {
+                market,
+                bids,
+                asks,
+                event_heap,
+            } = market_accounts_mut(&mut ex.markets, &market_pk)?;
+            let oracles = oracle_accounts(&ex.oracles, market)?;
+
+            let (order_id, deposit_amount) = processor::place_order(
+                market,
+                &market_pk,
+                &mut orderbook(bids, asks),
+                event_heap,
+                oracles,
+                open_orders_account,
+                open_orders_account_pk,
+                &order,
+                limit,
+                HashMap::<Pubkey, OpenOrdersAccount>::new(),
+                &ex.clock,
+            )?;
+
+            ex.tokens.transfer(
+                deposit_amount,
+                user_token_account,
+                &market.get_vault_by_side(order.side),
+                signer,
+            )?;
+
+            Ok(order_id)
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn edit_order(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        signer: &Pubkey,
+        user_token_account: &Pubkey,
+        cancel_client_order_id: u64,
+        expected_cancel_size: i64,
+        order: Order,
+        limit: u8,
+    ) -> Result<Option<u128>> {
+        self.atomic(|ex| {
+            let open_orders_account = owned_open_orders_account_mut(
+                &mut ex.open_orders_accounts,
+                open_orders_account_pk,
+                signer,
+            )?;
+
+            let market_pk = open_orders_account.market;
+            let MarketAccounts {
+                market,
+                bids,
+                asks,
+                event_heap,
+            } = market_accounts_mut(&mut ex.markets, &market_pk)?;
+            let oracles = oracle_accounts(&ex.oracles, market)?;
+
+            let (order_id, deposit_amount) = processor::edit_order(
+                market,
+                &market_pk,
+                &mut orderbook(bids, asks),
+                event_heap,
+                oracles,
+                open_orders_account,
+                open_orders_account_pk,
+                cancel_client_order_id,
+                expected_cancel_size,
+                order,
+                limit,
+                HashMap::<Pubkey, OpenOrdersAccount>::new(),
+                &ex.clock,
+            )?;
+
+            ex.tokens.transfer(
+                deposit_amount,
+                user_token_account,
+                &market.get_vault_by_side(order.side),
+                signer,
+            )?;
+
+            Ok(order_id)
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn cancel_all_and_place_orders(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        signer: &Pubkey,
+        user_base_account: &Pubkey,
+        user_quote_account: &Pubkey,
+        cancel: bool,
+        orders: Vec<Order>,
+        limit: u8,
+    ) -> Result<Vec<Option<u128>>> {
+        self.atomic(|ex| {
+            let open_orders_account = owned_open_orders_account_mut(
+                &mut ex.open_orders_accounts,
+                open_orders_account_pk,
+                signer,
+            )?;
+
+            let market_pk = open_orders_account.market;
+            let MarketAccounts {
+                market,
+                bids,
+                asks,
+                event_heap,
+            } = market_accounts_mut(&mut ex.markets, &market_pk)?;
+            let oracles = oracle_accounts(&ex.oracles, market)?;
+
+            let (order_ids, deposit_base_amount, deposit_quote_amount) =
+                processor::cancel_all_and_place_orders(
+                    market,
+                    &market_pk,
+                    &mut orderbook(bids, asks),
+                    event_heap,
+                    oracles,
+                    open_orders_account,
+                    open_orders_account_pk,
+                    ex.tokens.balance(user_base_account),
+                    ex.tokens.balance(user_quote_account),
+                    cancel,
+                    orders,
+                    limit,
+                    HashMap::<Pubkey, OpenOrdersAccount>::new(),
+                    &ex.clock,
+                )?;
+
+            ex.tokens.transfer(
+                deposit_quote_amount,
+                user_quote_account,
+                &market.market_quote_vault,
+                signer,
+            )?;
+            ex.tokens.transfer(
+                deposit_base_amount,
+                user_base_account,
+                &market.market_base_vault,
+                signer,
+            )?;
+
+            Ok(order_ids)
+        })
+    }
+
+    pub fn place_take_order(
+        &mut self,
+        market_pk: &Pubkey,
+        signer: &Pubkey,
+        user_base_account: &Pubkey,
+        user_quote_account: &Pubkey,
+        order: Order,
+        limit: u8,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let MarketAccounts {
+                market,
+                bids,
+                asks,
+                event_heap,
+            } = market_accounts_mut(&mut ex.markets, market_pk)?;
+            let oracles = oracle_accounts(&ex.oracles, market)?;
+
+            let TakeOrderAmounts {
+                deposit_amount,
+                withdraw_amount,
+                ..
+            } = processor::place_take_order(
+                market,
+                market_pk,
+                &mut orderbook(bids, asks),
+                event_heap,
+                oracles,
+                signer,
+                &order,
+                limit,
+                HashMap::<Pubkey, OpenOrdersAccount>::new(),
+                &ex.clock,
+            )?;
+
+            let (user_deposit_acc, user_withdraw_acc, market_deposit_acc, market_withdraw_acc) =
+                match order.side {
+                    Side::Bid => (
+                        user_quote_account,
+                        user_base_account,
+                        market.market_quote_vault,
+                        market.market_base_vault,
+                    ),
+                    Side::Ask => (
+                        user_base_account,
+                        user_quote_account,
+                        market.market_base_vault,
+                        market.market_quote_vault,
+                    ),
+                };
+
+            ex.tokens.transfer(
+                deposit_amount,
+                user_deposit_acc,
+                &market_deposit_acc,
+                signer,
+            )?;
+            ex.tokens.transfer(
+                withdraw_amount,
+                &market_withdraw_acc,
+                user_withdraw_acc,
+                &market.market_authority,
+            )?;
+
+            Ok(())
+        })
+    }
+
+    pub fn cancel_order(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        signer: &Pubkey,
+        order_id: u128,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account = owned_open_orders_account_mut(
+                &mut ex.open_orders_accounts,
+                open_orders_account_pk,
+                signer,
+            )?;
+            let accounts = market_accounts_mut(&mut ex.markets, &open_orders_account.market)?;
+
+            processor::cancel_order(
+                &accounts.market,
+                &mut orderbook(&accounts.bids, &accounts.asks),
+                open_orders_account,
+                open_orders_account_pk,
+                order_id,
+            )
+        })
+    }
+
+    pub fn cancel_order_by_client_order_id(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        signer: &Pubkey,
+        client_order_id: u64,
+    ) -> Result<i64> {
+        self.atomic(|ex| {
+            let open_orders_account = owned_open_orders_account_mut(
+                &mut ex.open_orders_accounts,
+                open_orders_account_pk,
+                signer,
+            )?;
+            let accounts = market_accounts_mut(&mut ex.markets, &open_orders_account.market)?;
+
+            processor::cancel_order_by_client_order_id(
+                &accounts.market,
+                &mut orderbook(&accounts.bids, &accounts.asks),
+                open_orders_account,
+                client_order_id,
+            )
+        })
+    }
+
+    pub fn cancel_all_orders(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        signer: &Pubkey,
+        side_option: Option<Side>,
+        limit: u8,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account = owned_open_orders_account_mut(
+                &mut ex.open_orders_accounts,
+                open_orders_account_pk,
+                signer,
+            )?;
+            let accounts = market_accounts_mut(&mut ex.markets, &open_orders_account.market)?;
+
+            processor::cancel_all_orders(
+                &accounts.market,
+                &mut orderbook(&accounts.bids, &accounts.asks),
+                open_orders_account,
+                side_option,
+                limit,
+            )
+        })
+    }
+
+    /// Like the `consume_events` instruction with every known open orders account passed
+    /// as a remaining account. Events of unknown accounts are skipped.
+    pub fn consume_events(
+        &mut self,
+        market_pk: &Pubkey,
+        limit: usize,
+        slots: Option<Vec<usize>>,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let MarketAccounts {
+                market, event_heap, ..
+            } = market_accounts_mut(&mut ex.markets, market_pk)?;
+
+            processor::consume_events(
+                market,
+                event_heap,
+                limit,
+                slots,
+                &mut ex.open_orders_accounts,
+            )
+        })
+    }
+
+    pub fn settle_funds(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        signer: &Pubkey,
+        user_base_account: &Pubkey,
+        user_quote_account: &Pubkey,
+        referrer_account: Option<&Pubkey>,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account = owned_open_orders_account_mut(
+                &mut ex.open_orders_accounts,
+                open_orders_account_pk,
+                signer,
+            )?;
+            let market =
+                &mut market_accounts_mut(&mut ex.markets, &open_orders_account.market)?.market;
+
+            let settlement = processor::settle_funds(
+                market,
+                open_orders_account,
+                open_orders_account_pk,
+                referrer_account,
+            )?;
+
+            ex.tokens.transfer_settlement(
+                market,
+                settlement,
+                user_base_account,
+                user_quote_account,
+                referrer_account,
+            )
+        })
+    }
+
+    /// Settle the funds of any account of an expired market
+    pub fn settle_funds_expired(
+        &mut self,
+        open_orders_account_pk: &Pubkey,
+        user_base_account: &Pubkey,
+        user_quote_account: &Pubkey,
+        referrer_account: Option<&Pubkey>,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account =
+                open_orders_account_mut(&mut ex.open_orders_accounts, open_orders_account_pk)?;
+            let market =
+                &mut market_accounts_mut(&mut ex.markets, &open_orders_account.market)?.market;
+
+            let settlement = processor::settle_funds_expired(
+                market,
+                open_orders_account,
+                open_orders_account_pk,
+                referrer_account,
+                &ex.clock,
+            )?;
+
+            // the vaults may only pay out to the owner
+            for user_account in [user_base_account, user_quote_account] {
+                let token_account = ex
+                    .tokens
+                    .accounts
+                    .get(user_account)
+                    .ok_or_else(|| error_msg!("token account {} not found", user_account))?;
+                require_keys_eq!(token_account.owner, open_orders_account.owner);
+            }
+
+            ex.tokens.transfer_settlement(
+                market,
+                settlement,
+                user_base_account,
+                user_quote_account,
+                referrer_account,
+            )
+        })
+    }
+
+    pub fn sweep_fees(
+        &mut self,
+        market_pk: &Pubkey,
+        token_receiver_account: &Pubkey,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let market = &mut market_accounts_mut(&mut ex.markets, market_pk)?.market;
+
+            let amount = processor::sweep_fees(market, market_pk, token_receiver_account);
+
+            ex.tokens.transfer(
+                amount,
+                &market.market_quote_vault,
+                token_receiver_account,
+                &market.market_authority,
+            )
+        })
+    }
+
+    pub fn set_market_expired(&mut self, market_pk: &Pubkey) -> Result<()> {
+        self.atomic(|ex| {
+            let market = &mut market_accounts_mut(&mut ex.markets, market_pk)?.market;
+            processor::set_market_expired(market, &ex.clock)
+        })
+    }
+
+    pub fn prune_orders(&mut self, open_orders_account_pk: &Pubkey, limit: u8) -> Result<()> {
+        self.atomic(|ex| {
+            let open_orders_account =
+                open_orders_account_mut(&mut ex.open_orders_accounts, open_orders_account_pk)?;
+            let accounts = market_accounts_mut(&mut ex.markets, &open_orders_account.market)?;
+
+            processor::prune_orders(
+                &accounts.market,
+                &mut orderbook(&accounts.bids, &accounts.asks),
+                open_orders_account,
+                limit,
+                &ex.clock,
+            )
+        })
+    }
+
+    /// Create the StubOracle of `owner` for `mint`, returns its address
+    pub fn stub_oracle_create(
+        &mut self,
+        owner: &Pubkey,
+        mint: &Pubkey,
+        price: f64,
+    ) -> Result<Pubkey> {
+        self.atomic(|ex| {
+            let (oracle_pk, _) = pda::stub_oracle(owner, mint);
+            require!(
+                !ex.oracles.contains_key(&oracle_pk),
+                ErrorCode::AccountDiscriminatorAlreadySet
+            );
+
+            let mut oracle = StubOracle::zeroed();
+            processor::stub_oracle_create(&mut oracle, owner, mint, price, &ex.clock);
+
+            ex.oracles
+                .insert(oracle_pk, stub_oracle_account(oracle_pk, &oracle));
+            Ok(oracle_pk)
+        })
+    }
+
+    pub fn stub_oracle_set(
+        &mut self,
+        oracle_pk: &Pubkey,
+        owner: &Pubkey,
+        price: f64,
+    ) -> Result<()> {
+        self.atomic(|ex| {
+            let mut oracle = stub_oracle(&ex.oracles, oracle_pk, owner)?;
+            processor::stub_oracle_set(&mut oracle, price, &ex.clock);
+
+            ex.oracles
+                .insert(*oracle_pk, stub_oracle_account(*oracle_pk, &oracle));
+            Ok(())
+        })
+    }
+
+    pub fn stub_oracle_close(&mut self, oracle_pk: &Pubkey, owner: &Pubkey) -> Result<()> {
+        self.atomic(|ex| {
+            stub_oracle(&ex.oracles, oracle_pk, owner)?;
+            ex.oracles.remove(oracle_pk);
+            Ok(())
+        })
+    }
+}
+
+impl TokenLedger {
+    /// Pay out what `processor::settle_funds` released from the vaults of `market`
+    fn transfer_settlement(
+        &mut self,
+        market: &Market,
+        settlement: Settlement,
+        user_base_account: &Pubkey,
+        user_quote_account: &Pubkey,
+        referrer_account: Option<&Pubkey>,
+    ) -> Result<()> {
+        if let Some(referrer_account) = referrer_account {
+            self.transfer(
+                settlement.referrer_rebate,
+                &market.market_quote_vault,
+                referrer_account,
+                &market.market_authority,
+            )?;
+        }
+
+        self.transfer(
+            settlement.base_native,
+            &market.market_base_vault,
+            user_base_account,
+            &market.market_authority,
+        )?;
+
+        self.transfer(
+            settlement.quote_native,
+            &market.market_quote_vault,
+            user_quote_account,
+            &market.market_authority,
+        )
+    }
+}
+
+fn orderbook<'a>(
+    bids: &'a RefCell<Box<BookSide>>,
+    asks: &'a RefCell<Box<BookSide>>,
+) -> Orderbook<'a> {
+    Orderbook {
+        bids: RefMut::map(bids.borrow_mut(), |bids| bids.as_mut()),
+        asks: RefMut::map(asks.borrow_mut(), |asks| asks.as_mut()),
+    }
+}
+
+fn new_book_side() -> Box<BookSide> {
+    Box::new(BookSide {
+        roots: [OrderTreeRoot::zeroed(); 2],
+        reserved_roots: [OrderTreeRoot::zeroed(); 4],
+        reserved: [0; 256],
+        nodes: OrderTreeNodes {
+            order_tree_type: 0,
+            padding: [0; 3],
+            bump_index: 0,
+            free_list_len: 0,
+            free_list_head: 0,
+            reserved: [0; 512],
+            nodes: [AnyNode::zeroed(); MAX_ORDERTREE_NODES],
+        },
+    })
+}
+
+fn market_accounts_mut<'a>(
+    markets: &'a mut HashMap<Pubkey, MarketAccounts>,
+    key: &Pubkey,
+) -> Result<&'a mut MarketAccounts> {
+    markets
+        .get_mut(key)
+        .ok_or_else(|| error_msg!("market {} not found", key))
+}
+
+fn open_orders_account_mut<'a>(
+    accounts: &'a mut HashMap<Pubkey, OpenOrdersAccount>,
+    key: &Pubkey,
+) -> Result<&'a mut OpenOrdersAccount> {
+    accounts
+        .get_mut(key)
+        .ok_or_else(|| error_msg!("open orders account {} not found", key))
+}
+
+/// The account at `key`, if `signer` is its owner or delegate
+fn owned_open_orders_account_mut<'a>(
+    accounts: &'a mut HashMap<Pubkey, OpenOrdersAccount>,
+    key: &Pubkey,
+    signer: &Pubkey,
+) -> Result<&'a mut OpenOrdersAccount> {
+    let open_orders_account = open_orders_account_mut(accounts, key)?;
+    require!(
+        open_orders_account.is_owner_or_delegate(*signer),
+        OpenBookError::NoOwnerOrDelegate
+    );
+    Ok(open_orders_account)
+}
+
+fn open_orders_indexer_mut<'a>(
+    indexers: &'a mut HashMap<Pubkey, OpenOrdersIndexer>,
+    key: &Pubkey,
+) -> Result<&'a mut OpenOrdersIndexer> {
+    indexers
+        .get_mut(key)
+        .ok_or_else(|| error_msg!("open orders indexer {} not found", key))
+}
+
+fn oracle_account(
+    oracles: &HashMap<Pubkey, KeyedAccountSharedData>,
+    key: NonZeroPubkeyOption,
+) -> Result<Option<&KeyedAccountSharedData>> {
+    match Option::<Pubkey>::from(key) {
+        Some(key) => oracles
+            .get(&key)
+            .map(Some)
+            .ok_or_else(|| error_msg!("oracle {} not found", key)),
+        None => Ok(None),
+    }
+}
+
+fn oracle_accounts<'a>(
+    oracles: &'a HashMap<Pubkey, KeyedAccountSharedData>,
+    market: &Market,
+) -> Result<[Option<&'a KeyedAccountSharedData>; 3]> {
+    Ok([
+        oracle_account(oracles, market.oracle_a)?,
+        oracle_account(oracles, market.oracle_b)?,
+        oracle_account(oracles, market.oracle_c)?,
+    ])
+}
+
+/// The StubOracle at `key`, if `owner` owns it
+fn stub_oracle(
+    oracles: &HashMap<Pubkey, KeyedAccountSharedData>,
+    key: &Pubkey,
+    owner: &Pubkey,
+) -> Result<StubOracle> {
+    let account = oracles
+        .get(key)
+        .ok_or_else(|| error_msg!("oracle {} not found", key))?;
+    let oracle = *account.load::<StubOracle>()?;
+    require_keys_eq!(oracle.owner, *owner, OpenBookError::NoOwner);
+    Ok(oracle)
+}
+
+fn stub_oracle_account(key: Pubkey, oracle: &StubOracle) -> KeyedAccountSharedData {
+    let mut data = StubOracle::discriminator().to_vec();
+    data.extend(bytemuck::bytes_of(oracle));
+    let account = solana_sdk::account::Account {
+        lamports: 1,
+        data,
+        owner: crate::ID,
+        executable: false,
+        rent_epoch: 0,
+    };
+    KeyedAccountSharedData::new(key, account.into())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::state::oracle::tests::oracle_config_params;
+    use anchor_lang::Discriminator;
+
+    pub(crate) struct User {
+        pub owner: Pubkey,
+        pub open_orders_account: Pubkey,
+        pub base_account: Pubkey,
+        pub quote_account: Pubkey,
+    }
+
+    pub(crate) fn market_args() -> CreateMarketArgs {
+        CreateMarketArgs {
+            name: "SOL-USDC".to_string(),
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            base_decimals: 9,
+            quote_decimals: 6,
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            event_heap: Pubkey::new_unique(),
+            oracle_a: None,
+            oracle_b: None,
+            oracle_c: None,
+            oracle_config: oracle_config_params(),
+            quote_lot_size: 10,
+            base_lot_size: 100,
+            maker_fee: -200,
+            taker_fee: 400,
+            time_expiry: 0,
+            collect_fee_admin: Pubkey::new_unique(),
+            open_orders_admin: None,
+            consume_events_admin: None,
+            close_market_admin: None,
+        }
+    }
+
+    pub(crate) fn create_market(ex: &mut Executor) -> Pubkey {
+        let market_pk = Pubkey::new_unique();
+        ex.create_market(market_pk, market_args()).unwrap();
+        market_pk
+    }
+
+    pub(crate) fn create_stub_oracle(
+        ex: &mut Executor,
+        price: f64,
+        last_update_slot: u64,
+    ) -> Pubkey {
+        let key = Pubkey::new_unique();
+        let mut stub = StubOracle::zeroed();
+        stub.price = price;
+        stub.last_update_slot = last_update_slot;
+        stub.last_update_ts = ex.clock.unix_timestamp;
+        let mut data = StubOracle::discriminator().to_vec();
+        data.extend(bytemuck::bytes_of(&stub));
+        let account = solana_sdk::account::Account {
+            lamports: 1,
+            data,
+            owner: crate::ID,
+            executable: false,
+            rent_epoch: 0,
+        };
+        ex.oracles
+            .insert(key, KeyedAccountSharedData::new(key, account.into()));
+        key
+    }
+
+    pub(crate) fn create_user(
+        ex: &mut Executor,
+        market_pk: &Pubkey,
+        base: u64,
+        quote: u64,
+    ) -> User {
+        let market = ex.markets[market_pk].market;
+        let owner = Pubkey::new_unique();
+        let base_account = Pubkey::new_unique();
+        let quote_account = Pubkey::new_unique();
+        ex.tokens
+            .create_account(base_account, market.base_mint, owner, base);
+        ex.tokens
+            .create_account(quote_account, market.quote_mint, owner, quote);
+
+        ex.create_open_orders_indexer(&owner).unwrap();
+        let open_orders_account = ex
+            .create_open_orders_account(&owner, market_pk, "user", None)
+            .unwrap();
+
+        User {
+            owner,
+            open_orders_account,
+            base_account,
+            quote_account,
+        }
+    }
+
+    pub(crate) fn order(side: Side, max_base_lots: i64, params: OrderParams) -> Order {
+        Order {
+            side,
+            max_base_lots,
+            max_quote_lots_including_fees: 1_000_000,
+            client_order_id: 0,
+            time_in_force: 0,
+            self_trade_behavior: SelfTradeBehavior::default(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_order_lifecycle() {
+        let mut ex = Executor::default();
+        ex.clock.unix_timestamp = 1_700_000_000;
+        let market_pk = create_market(&mut ex);
+        let maker = create_user(&mut ex, &market_pk, 0, 100_000);
+        let taker = create_user(&mut ex, &market_pk, 1_000, 0);
+
+        let order_id = ex
+            .place_order(
+                &maker.open_orders_account,
+                &maker.owner,
+                &maker.quote_account,
+                order(
+                    Side::Bid,
+                    5,
+                    OrderParams::Fixed {
+                        price_lots: 1000,
+                        order_type: PostOrderType::Limit,
+                    },
+                ),
+                10,
+            )
+            .unwrap();
+        assert!(order_id.is_some());
+        assert_eq!(ex.tokens.balance(&maker.quote_account), 50_000);
+
+        ex.place_order(
+            &taker.open_orders_account,
+            &taker.owner,
+            &taker.base_account,
+            order(
+                Side::Ask,
+                3,
+                OrderParams::ImmediateOrCancel { price_lots: 1000 },
+            ),
+            10,
+        )
+        .unwrap();
+
+        // the maker is only credited once the fill event is consumed
+        assert_eq!(ex.markets[&market_pk].event_heap.len(), 1);
+        assert_eq!(
+            ex.open_orders_accounts[&maker.open_orders_account]
+                .position
+                .base_free_native,
+            0
+        );
+        ex.consume_events(&market_pk, 10, None).unwrap();
+        assert!(ex.markets[&market_pk].event_heap.is_empty());
+
+        let maker_position = ex.open_orders_accounts[&maker.open_orders_account].position;
+        assert_eq!(maker_position.base_free_native, 300);
+        assert_eq!(maker_position.quote_free_native, 6); // maker rebate
+        assert_eq!(maker_position.bids_base_lots, 2);
+
+        for user in [&maker, &taker] {
+            ex.settle_funds(
+                &user.open_orders_account,
+                &user.owner,
+                &user.base_account,
+                &user.quote_account,
+                None,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(ex.tokens.balance(&maker.base_account), 300);
+        assert_eq!(ex.tokens.balance(&maker.quote_account), 50_006);
+        assert_eq!(ex.tokens.balance(&taker.base_account), 700);
+        assert_eq!(ex.tokens.balance(&taker.quote_account), 29_988);
+
+        // the remaining bid and the fees are all that's left in the vaults
+        let market = ex.markets[&market_pk].market;
+        assert_eq!(market.fees_available, 6);
+        assert_eq!(ex.tokens.balance(&market.market_quote_vault), 20_006);
+        assert_eq!(market.quote_deposit_total, 20_006);
+        assert_eq!(ex.tokens.balance(&market.market_base_vault), 0);
+        assert_eq!(market.base_deposit_total, 0);
+    }
+
+    #[test]
+    fn test_failed_instruction_is_rolled_back() {
+        let mut ex = Executor::default();
+        let market_pk = create_market(&mut ex);
+        let user = create_user(&mut ex, &market_pk, 0, 1_000);
+
+        // not enough quote tokens to lock for the bid
+        let result = ex.place_order(
+            &user.open_orders_account,
+            &user.owner,
+            &user.quote_account,
+            order(
+                Side::Bid,
+                5,
+                OrderParams::Fixed {
+                    price_lots: 1000,
+                    order_type: PostOrderType::Limit,
+                },
+            ),
+            10,
+        );
+        assert!(result.is_err());
+
+        assert!(ex.markets[&market_pk].bids.borrow().is_empty());
+        assert_eq!(ex.markets[&market_pk].market.seq_num, 0);
+        let position = ex.open_orders_accounts[&user.open_orders_account].position;
+        assert_eq!(position.bids_base_lots, 0);
+        assert_eq!(ex.tokens.balance(&user.quote_account), 1_000);
+    }
+
+    fn fixed(price_lots: i64) -> OrderParams {
+        OrderParams::Fixed {
+            price_lots,
+            order_type: PostOrderType::Limit,
+        }
+    }
+
+    fn bids_l2(ex: &Executor, market_pk: &Pubkey) -> Vec<(i64, i64)> {
+        let now_ts = ex.clock.unix_timestamp as u64;
+        ex.markets[market_pk]
+            .bids
+            .borrow()
+            .l2_levels(10, now_ts, None)
+            .iter()
+            .map(|level| (level.price_lots, level.quantity_lots))
+            .collect()
+    }
+
+    #[test]
+    fn test_market_account_keys_are_inputs() {
+        let mut ex = Executor::default();
+        let args = market_args();
+        let market_pk = Pubkey::new_unique();
+        ex.create_market(market_pk, args.clone()).unwrap();
+
+        let market = ex.markets[&market_pk].market;
+        assert_eq!(market.bids, args.bids);
+        assert_eq!(market.asks, args.asks);
+        assert_eq!(market.event_heap, args.event_heap);
+
+        // the accounts of a market can't be reused
+        assert!(ex
+            .create_market(Pubkey::new_unique(), args.clone())
+            .is_anchor_error_with_code(ErrorCode::AccountDiscriminatorAlreadySet.into()));
+        let mut same_sides = market_args();
+        same_sides.asks = same_sides.bids;
+        assert!(ex
+            .create_market(Pubkey::new_unique(), same_sides)
+            .is_anchor_error_with_code(ErrorCode::AccountDiscriminatorAlreadySet.into()));
+    }
+
+    #[test]
+    fn test_edit_and_replace_orders() {
+        let mut ex = Executor::default();
+        ex.clock.unix_timestamp = 1_700_000_000;
+        let market_pk = create_market(&mut ex);
+        let maker = create_user(&mut ex, &market_pk, 0, 100_000);
+        let taker = create_user(&mut ex, &market_pk, 1_000, 0);
+
+        let mut bid = order(Side::Bid, 5, fixed(1000));
+        bid.client_order_id = 7;
+        ex.place_order(
+            &maker.open_orders_account,
+            &maker.owner,
+            &maker.quote_account,
+            bid,
+            10,
+        )
+        .unwrap();
+        ex.place_take_order(
+            &market_pk,
+            &taker.owner,
+            &taker.base_account,
+            &taker.quote_account,
+            order(Side::Ask, 2, fixed(1000)),
+            10,
+        )
+        .unwrap();
+
+        // 2 of the 5 lots were filled, so the replacement is reduced to 3
+        let mut replacement = order(Side::Bid, 5, fixed(990));
+        replacement.client_order_id = 8;
+        let order_id = ex
+            .edit_order(
+                &maker.open_orders_account,
+                &maker.owner,
+                &maker.quote_account,
+                7,
+                5,
+                replacement,
+                10,
+            )
+            .unwrap();
+        assert!(order_id.is_some());
+        assert_eq!(bids_l2(&ex, &market_pk), vec![(990, 3)]);
+
+        // max_base_lots is replaced, the bid is only limited by its quote
+        let mut small_bid = order(Side::Bid, 0, fixed(980));
+        small_bid.max_quote_lots_including_fees = 3000;
+        let order_ids = ex
+            .cancel_all_and_place_orders(
+                &maker.open_orders_account,
+                &maker.owner,
+                &maker.base_account,
+                &maker.quote_account,
+                true,
+                vec![small_bid],
+                10,
+            )
+            .unwrap();
+        assert_eq!(order_ids.len(), 1);
+        assert!(order_ids[0].is_some());
+        assert_eq!(bids_l2(&ex, &market_pk), vec![(980, 3)]);
+
+        // a delegate may cancel, but only the owner can change the delegate
+        let delegate = Pubkey::new_unique();
+        assert!(ex
+            .set_delegate(&maker.open_orders_account, &delegate, Some(delegate))
+            .is_anchor_error_with_code(OpenBookError::NoOwner.error_code()));
+        assert!(ex
+            .cancel_all_orders(&maker.open_orders_account, &delegate, None, 10)
+            .is_anchor_error_with_code(OpenBookError::NoOwnerOrDelegate.error_code()));
+        ex.set_delegate(&maker.open_orders_account, &maker.owner, Some(delegate))
+            .unwrap();
+        ex.cancel_all_orders(&maker.open_orders_account, &delegate, None, 10)
+            .unwrap();
+        assert!(ex.markets[&market_pk].bids.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_expired_market_is_closed() {
+        let mut ex = Executor::default();
+        ex.clock.unix_timestamp = 1_700_000_000;
+        let market_pk = create_market(&mut ex);
+        let maker = create_user(&mut ex, &market_pk, 0, 100_000);
+
+        ex.place_order(
+            &maker.open_orders_account,
+            &maker.owner,
+            &maker.quote_account,
+            order(Side::Bid, 5, fixed(1000)),
+            10,
+        )
+        .unwrap();
+        assert!(ex
+            .prune_orders(&maker.open_orders_account, 10)
+            .is_anchor_error_with_code(OpenBookError::MarketHasNotExpired.error_code()));
+
+        ex.set_market_expired(&market_pk).unwrap();
+        assert!(ex
+            .close_market(&market_pk)
+            .is_anchor_error_with_code(OpenBookError::NonEmptyMarket.error_code()));
+        assert!(ex
+            .place_order(
+                &maker.open_orders_account,
+                &maker.owner,
+                &maker.quote_account,
+                order(Side::Bid, 5, fixed(1000)),
+                10,
+            )
+            .is_anchor_error_with_code(OpenBookError::MarketHasExpired.error_code()));
+
+        ex.prune_orders(&maker.open_orders_account, 10).unwrap();
+        ex.settle_funds_expired(
+            &maker.open_orders_account,
+            &maker.base_account,
+            &maker.quote_account,
+            None,
+        )
+        .unwrap();
+        assert_eq!(ex.tokens.balance(&maker.quote_account), 100_000);
+
+        assert!(ex
+            .close_open_orders_account(&maker.open_orders_account, &Pubkey::new_unique())
+            .is_anchor_error_with_code(OpenBookError::NoOwner.error_code()));
+        assert!(ex
+            .close_open_orders_indexer(&maker.owner)
+            .is_anchor_error_with_code(OpenBookError::IndexerActiveOO.error_code()));
+        ex.close_open_orders_account(&maker.open_orders_account, &maker.owner)
+            .unwrap();
+        ex.close_open_orders_indexer(&maker.owner).unwrap();
+        assert!(ex.open_orders_accounts.is_empty());
+        assert!(ex.open_orders_indexers.is_empty());
+
+        ex.close_market(&market_pk).unwrap();
+        assert!(!ex.markets.contains_key(&market_pk));
+    }
+
+    #[test]
+    fn test_stub_oracle_lifecycle() {
+        let mut ex = Executor::default();
+        ex.clock.unix_timestamp = 1_700_000_000;
+        ex.clock.slot = 100;
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let oracle_pk = ex.stub_oracle_create(&owner, &mint, 100.0).unwrap();
+        assert_eq!(oracle_pk, pda::stub_oracle(&owner, &mint).0);
+        assert!(ex
+            .stub_oracle_create(&owner, &mint, 100.0)
+            .is_anchor_error_with_code(ErrorCode::AccountDiscriminatorAlreadySet.into()));
+
+        ex.clock.unix_timestamp += 10;
+        ex.clock.slot += 20;
+        assert!(ex
+            .stub_oracle_set(&oracle_pk, &Pubkey::new_unique(), 120.0)
+            .is_anchor_error_with_code(OpenBookError::NoOwner.error_code()));
+        ex.stub_oracle_set(&oracle_pk, &owner, 120.0).unwrap();
+
+        let oracle = *ex.oracles[&oracle_pk].load::<StubOracle>().unwrap();
+        assert_eq!(oracle.price, 120.0);
+        assert_eq!(oracle.mint, mint);
+        assert_eq!(oracle.last_update_ts, 1_700_000_010);
+        assert_eq!(oracle.last_update_slot, 120);
+
+        let mut args = market_args();
+        args.oracle_a = Some(oracle_pk);
+        let market_pk = Pubkey::new_unique();
+        ex.create_market(market_pk, args).unwrap();
+        assert_eq!(
+            Option::<Pubkey>::from(ex.markets[&market_pk].market.oracle_a),
+            Some(oracle_pk)
+        );
+
+        ex.stub_oracle_close(&oracle_pk, &owner).unwrap();
+        assert!(!ex.oracles.contains_key(&oracle_pk));
+    }
+}
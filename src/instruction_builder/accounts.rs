@@ -0,0 +1,582 @@
+//! Accounts of each instruction, in the order the program expects them.
+//!
+//! Optional accounts that are `None` are passed as the program id, like Anchor clients do.
+//!
+//! ABI change: `oracle_c` was added after the program was deployed, so it isn't one of the
+//! named accounts of the instructions. It's passed after all of them, ahead of any maker
+//! accounts, and only if it's set:
+//! - create_market takes the first account after `program` as oracle_c
+//! - the place and edit order instructions look it up by the address stored in the market
+//!
+//! The existing accounts keep their positions, so clients of markets without a third oracle
+//! pass the same accounts as before.
+
+use anchor_lang::prelude::*;
+use anchor_lang::system_program;
+use anchor_spl::{associated_token, token};
+
+fn optional(key: Option<Pubkey>) -> AccountMeta {
+    AccountMeta::new_readonly(key.unwrap_or(crate::ID), false)
+}
+
+fn optional_mut(key: Option<Pubkey>) -> AccountMeta {
+    match key {
+        Some(key) => AccountMeta::new(key, false),
+        None => AccountMeta::new_readonly(crate::ID, false),
+    }
+}
+
+fn optional_signer(key: Option<Pubkey>) -> AccountMeta {
+    match key {
+        Some(key) => AccountMeta::new_readonly(key, true),
+        None => AccountMeta::new_readonly(crate::ID, false),
+    }
+}
+
+/// Append the third oracle, if any, after the accounts of the instruction
+fn push_oracle_c(mut metas: Vec<AccountMeta>, oracle_c: Option<Pubkey>) -> Vec<AccountMeta> {
+    metas.extend(oracle_c.map(|key| AccountMeta::new_readonly(key, false)));
+    metas
+}
+
+#[derive(Clone, Debug)]
+pub struct CreateMarket {
+    pub market: Pubkey,
+    pub market_authority: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub payer: Pubkey,
+    pub market_base_vault: Pubkey,
+    pub market_quote_vault: Pubkey,
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub oracle_a: Option<Pubkey>,
+    pub oracle_b: Option<Pubkey>,
+    /// Passed after all other accounts, see the module documentation
+    pub oracle_c: Option<Pubkey>,
+    pub collect_fee_admin: Pubkey,
+    pub open_orders_admin: Option<Pubkey>,
+    pub consume_events_admin: Option<Pubkey>,
+    pub close_market_admin: Option<Pubkey>,
+    pub event_authority: Pubkey,
+}
+
+impl ToAccountMetas for CreateMarket {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        let metas = vec![
+            AccountMeta::new(self.market, true),
+            AccountMeta::new_readonly(self.market_authority, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.event_heap, false),
+            AccountMeta::new(self.payer, true),
+            AccountMeta::new(self.market_base_vault, false),
+            AccountMeta::new(self.market_quote_vault, false),
+            AccountMeta::new_readonly(self.base_mint, false),
+            AccountMeta::new_readonly(self.quote_mint, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(token::ID, false),
+            AccountMeta::new_readonly(associated_token::ID, false),
+            optional(self.oracle_a),
+            optional(self.oracle_b),
+            AccountMeta::new_readonly(self.collect_fee_admin, false),
+            optional(self.open_orders_admin),
+            optional(self.consume_events_admin),
+            optional(self.close_market_admin),
+            AccountMeta::new_readonly(self.event_authority, false),
+            AccountMeta::new_readonly(crate::ID, false),
+        ];
+        push_oracle_c(metas, self.oracle_c)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CloseMarket {
+    pub close_market_admin: Pubkey,
+    pub market: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub sol_destination: Pubkey,
+}
+
+impl ToAccountMetas for CloseMarket {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.close_market_admin, true),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.event_heap, false),
+            AccountMeta::new(self.sol_destination, false),
+            AccountMeta::new_readonly(token::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CreateOpenOrdersIndexer {
+    pub payer: Pubkey,
+    pub owner: Pubkey,
+    pub open_orders_indexer: Pubkey,
+}
+
+impl ToAccountMetas for CreateOpenOrdersIndexer {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.payer, true),
+            AccountMeta::new_readonly(self.owner, true),
+            AccountMeta::new(self.open_orders_indexer, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CloseOpenOrdersIndexer {
+    pub owner: Pubkey,
+    pub open_orders_indexer: Pubkey,
+    pub sol_destination: Pubkey,
+}
+
+impl ToAccountMetas for CloseOpenOrdersIndexer {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.owner, true),
+            AccountMeta::new(self.open_orders_indexer, false),
+            AccountMeta::new(self.sol_destination, false),
+            AccountMeta::new_readonly(token::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CreateOpenOrdersAccount {
+    pub payer: Pubkey,
+    pub owner: Pubkey,
+    pub delegate_account: Option<Pubkey>,
+    pub open_orders_indexer: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub market: Pubkey,
+}
+
+impl ToAccountMetas for CreateOpenOrdersAccount {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.payer, true),
+            AccountMeta::new_readonly(self.owner, true),
+            optional(self.delegate_account),
+            AccountMeta::new(self.open_orders_indexer, false),
+            AccountMeta::new(self.open_orders_account, false),
+            AccountMeta::new_readonly(self.market, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CloseOpenOrdersAccount {
+    pub owner: Pubkey,
+    pub open_orders_indexer: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub sol_destination: Pubkey,
+}
+
+impl ToAccountMetas for CloseOpenOrdersAccount {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.owner, true),
+            AccountMeta::new(self.open_orders_indexer, false),
+            AccountMeta::new(self.open_orders_account, false),
+            AccountMeta::new(self.sol_destination, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]
+    }
+}
+
+/// Accounts of place_order, place_order_pegged, edit_order and edit_order_pegged
+#[derive(Clone, Debug)]
+pub struct PlaceOrder {
+    pub signer: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub open_orders_admin: Option<Pubkey>,
+    pub user_token_account: Pubkey,
+    pub market: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub market_vault: Pubkey,
+    pub oracle_a: Option<Pubkey>,
+    pub oracle_b: Option<Pubkey>,
+    /// Passed after all other accounts, see the module documentation
+    pub oracle_c: Option<Pubkey>,
+}
+
+impl ToAccountMetas for PlaceOrder {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        let metas = vec![
+            AccountMeta::new_readonly(self.signer, true),
+            AccountMeta::new(self.open_orders_account, false),
+            optional_signer(self.open_orders_admin),
+            AccountMeta::new(self.user_token_account, false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.event_heap, false),
+            AccountMeta::new(self.market_vault, false),
+            optional(self.oracle_a),
+            optional(self.oracle_b),
+            AccountMeta::new_readonly(token::ID, false),
+        ];
+        push_oracle_c(metas, self.oracle_c)
+    }
+}
+
+/// Accounts of place_orders and cancel_all_and_place_orders
+#[derive(Clone, Debug)]
+pub struct CancelAllAndPlaceOrders {
+    pub signer: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub open_orders_admin: Option<Pubkey>,
+    pub user_quote_account: Pubkey,
+    pub user_base_account: Pubkey,
+    pub market: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub market_quote_vault: Pubkey,
+    pub market_base_vault: Pubkey,
+    pub oracle_a: Option<Pubkey>,
+    pub oracle_b: Option<Pubkey>,
+    /// Passed after all other accounts, see the module documentation
+    pub oracle_c: Option<Pubkey>,
+}
+
+impl ToAccountMetas for CancelAllAndPlaceOrders {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        let metas = vec![
+            AccountMeta::new_readonly(self.signer, true),
+            AccountMeta::new(self.open_orders_account, false),
+            optional_signer(self.open_orders_admin),
+            AccountMeta::new(self.user_quote_account, false),
+            AccountMeta::new(self.user_base_account, false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.event_heap, false),
+            AccountMeta::new(self.market_quote_vault, false),
+            AccountMeta::new(self.market_base_vault, false),
+            optional(self.oracle_a),
+            optional(self.oracle_b),
+            AccountMeta::new_readonly(token::ID, false),
+        ];
+        push_oracle_c(metas, self.oracle_c)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PlaceTakeOrder {
+    pub signer: Pubkey,
+    pub penalty_payer: Pubkey,
+    pub market: Pubkey,
+    pub market_authority: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub market_base_vault: Pubkey,
+    pub market_quote_vault: Pubkey,
+    pub event_heap: Pubkey,
+    pub user_base_account: Pubkey,
+    pub user_quote_account: Pubkey,
+    pub oracle_a: Option<Pubkey>,
+    pub oracle_b: Option<Pubkey>,
+    /// Passed after all other accounts, see the module documentation
+    pub oracle_c: Option<Pubkey>,
+    pub open_orders_admin: Option<Pubkey>,
+}
+
+impl ToAccountMetas for PlaceTakeOrder {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        let metas = vec![
+            AccountMeta::new(self.signer, true),
+            AccountMeta::new(self.penalty_payer, true),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(self.market_authority, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+            AccountMeta::new(self.market_base_vault, false),
+            AccountMeta::new(self.market_quote_vault, false),
+            AccountMeta::new(self.event_heap, false),
+            AccountMeta::new(self.user_base_account, false),
+            AccountMeta::new(self.user_quote_account, false),
+            optional(self.oracle_a),
+            optional(self.oracle_b),
+            AccountMeta::new_readonly(token::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+            optional_signer(self.open_orders_admin),
+        ];
+        push_oracle_c(metas, self.oracle_c)
+    }
+}
+
+/// Accounts of consume_events and consume_given_events
+#[derive(Clone, Debug)]
+pub struct ConsumeEvents {
+    pub consume_events_admin: Option<Pubkey>,
+    pub market: Pubkey,
+    pub event_heap: Pubkey,
+}
+
+impl ToAccountMetas for ConsumeEvents {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            optional_signer(self.consume_events_admin),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.event_heap, false),
+        ]
+    }
+}
+
+/// Accounts of cancel_order, cancel_order_by_client_order_id and cancel_all_orders
+#[derive(Clone, Debug)]
+pub struct CancelOrder {
+    pub signer: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub market: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+}
+
+impl ToAccountMetas for CancelOrder {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.signer, true),
+            AccountMeta::new(self.open_orders_account, false),
+            AccountMeta::new_readonly(self.market, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Deposit {
+    pub owner: Pubkey,
+    pub user_base_account: Pubkey,
+    pub user_quote_account: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub market: Pubkey,
+    pub market_base_vault: Pubkey,
+    pub market_quote_vault: Pubkey,
+}
+
+impl ToAccountMetas for Deposit {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.owner, true),
+            AccountMeta::new(self.user_base_account, false),
+            AccountMeta::new(self.user_quote_account, false),
+            AccountMeta::new(self.open_orders_account, false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new(self.market_base_vault, false),
+            AccountMeta::new(self.market_quote_vault, false),
+            AccountMeta::new_readonly(token::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SettleFunds {
+    pub owner: Pubkey,
+    pub penalty_payer: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub market: Pubkey,
+    pub market_authority: Pubkey,
+    pub market_base_vault: Pubkey,
+    pub market_quote_vault: Pubkey,
+    pub user_base_account: Pubkey,
+    pub user_quote_account: Pubkey,
+    pub referrer_account: Option<Pubkey>,
+}
+
+impl ToAccountMetas for SettleFunds {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.owner, true),
+            AccountMeta::new(self.penalty_payer, true),
+            AccountMeta::new(self.open_orders_account, false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(self.market_authority, false),
+            AccountMeta::new(self.market_base_vault, false),
+            AccountMeta::new(self.market_quote_vault, false),
+            AccountMeta::new(self.user_base_account, false),
+            AccountMeta::new(self.user_quote_account, false),
+            optional_mut(self.referrer_account),
+            AccountMeta::new_readonly(token::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SettleFundsExpired {
+    pub close_market_admin: Pubkey,
+    pub owner: Pubkey,
+    pub penalty_payer: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub market: Pubkey,
+    pub market_authority: Pubkey,
+    pub market_base_vault: Pubkey,
+    pub market_quote_vault: Pubkey,
+    pub user_base_account: Pubkey,
+    pub user_quote_account: Pubkey,
+    pub referrer_account: Option<Pubkey>,
+}
+
+impl ToAccountMetas for SettleFundsExpired {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.close_market_admin, true),
+            AccountMeta::new(self.owner, true),
+            AccountMeta::new(self.penalty_payer, true),
+            AccountMeta::new(self.open_orders_account, false),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(self.market_authority, false),
+            AccountMeta::new(self.market_base_vault, false),
+            AccountMeta::new(self.market_quote_vault, false),
+            AccountMeta::new(self.user_base_account, false),
+            AccountMeta::new(self.user_quote_account, false),
+            optional_mut(self.referrer_account),
+            AccountMeta::new_readonly(token::ID, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SweepFees {
+    pub collect_fee_admin: Pubkey,
+    pub market: Pubkey,
+    pub market_authority: Pubkey,
+    pub market_quote_vault: Pubkey,
+    pub token_receiver_account: Pubkey,
+}
+
+impl ToAccountMetas for SweepFees {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.collect_fee_admin, true),
+            AccountMeta::new(self.market, false),
+            AccountMeta::new_readonly(self.market_authority, false),
+            AccountMeta::new(self.market_quote_vault, false),
+            AccountMeta::new(self.token_receiver_account, false),
+            AccountMeta::new_readonly(token::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SetDelegate {
+    pub owner: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub delegate_account: Option<Pubkey>,
+}
+
+impl ToAccountMetas for SetDelegate {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.owner, true),
+            AccountMeta::new(self.open_orders_account, false),
+            optional(self.delegate_account),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SetMarketExpired {
+    pub close_market_admin: Pubkey,
+    pub market: Pubkey,
+}
+
+impl ToAccountMetas for SetMarketExpired {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.close_market_admin, true),
+            AccountMeta::new(self.market, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PruneOrders {
+    pub close_market_admin: Pubkey,
+    pub open_orders_account: Pubkey,
+    pub market: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+}
+
+impl ToAccountMetas for PruneOrders {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.close_market_admin, true),
+            AccountMeta::new(self.open_orders_account, false),
+            AccountMeta::new_readonly(self.market, false),
+            AccountMeta::new(self.bids, false),
+            AccountMeta::new(self.asks, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StubOracleCreate {
+    pub payer: Pubkey,
+    pub owner: Pubkey,
+    pub oracle: Pubkey,
+    pub mint: Pubkey,
+}
+
+impl ToAccountMetas for StubOracleCreate {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new(self.payer, true),
+            AccountMeta::new_readonly(self.owner, true),
+            AccountMeta::new(self.oracle, false),
+            AccountMeta::new_readonly(self.mint, false),
+            AccountMeta::new_readonly(system_program::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StubOracleClose {
+    pub owner: Pubkey,
+    pub oracle: Pubkey,
+    pub sol_destination: Pubkey,
+}
+
+impl ToAccountMetas for StubOracleClose {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.owner, true),
+            AccountMeta::new(self.oracle, false),
+            AccountMeta::new(self.sol_destination, false),
+            AccountMeta::new_readonly(token::ID, false),
+        ]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StubOracleSet {
+    pub owner: Pubkey,
+    pub oracle: Pubkey,
+}
+
+impl ToAccountMetas for StubOracleSet {
+    fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(self.owner, true),
+            AccountMeta::new(self.oracle, false),
+        ]
+    }
+}
@@ -0,0 +1,203 @@
+//! Instruction data: the Anchor sighash followed by the borsh serialized arguments.
+//!
+//! The sighash of an instruction is the first 8 bytes of sha256("global:<instruction name>").
+
+use anchor_lang::prelude::*;
+use anchor_lang::{Discriminator, InstructionData};
+
+use super::*;
+use crate::state::*;
+
+macro_rules! instruction_data {
+    ($name:ident, $discriminator:expr) => {
+        impl Discriminator for $name {
+            const DISCRIMINATOR: [u8; 8] = $discriminator;
+        }
+
+        impl InstructionData for $name {}
+    };
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CreateMarket {
+    pub name: String,
+    pub oracle_config: OracleConfigParams,
+    pub quote_lot_size: i64,
+    pub base_lot_size: i64,
+    pub maker_fee: i64,
+    pub taker_fee: i64,
+    pub time_expiry: i64,
+}
+instruction_data!(CreateMarket, [103, 226, 97, 235, 200, 188, 251, 254]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CloseMarket {}
+instruction_data!(CloseMarket, [88, 154, 248, 186, 48, 14, 123, 244]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CreateOpenOrdersIndexer {}
+instruction_data!(
+    CreateOpenOrdersIndexer,
+    [64, 64, 153, 255, 217, 71, 249, 133]
+);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CloseOpenOrdersIndexer {}
+instruction_data!(
+    CloseOpenOrdersIndexer,
+    [103, 249, 229, 231, 247, 253, 197, 136]
+);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CreateOpenOrdersAccount {
+    pub name: String,
+}
+instruction_data!(
+    CreateOpenOrdersAccount,
+    [204, 181, 175, 222, 40, 125, 188, 71]
+);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CloseOpenOrdersAccount {}
+instruction_data!(
+    CloseOpenOrdersAccount,
+    [176, 74, 115, 210, 54, 179, 91, 103]
+);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct PlaceOrder {
+    pub args: PlaceOrderArgs,
+}
+instruction_data!(PlaceOrder, [51, 194, 155, 175, 109, 130, 96, 106]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct EditOrder {
+    pub client_order_id: u64,
+    pub expected_cancel_size: i64,
+    pub place_order: PlaceOrderArgs,
+}
+instruction_data!(EditOrder, [254, 208, 118, 29, 173, 248, 200, 70]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct EditOrderPegged {
+    pub client_order_id: u64,
+    pub expected_cancel_size: i64,
+    pub place_order: PlaceOrderPeggedArgs,
+}
+instruction_data!(EditOrderPegged, [62, 187, 125, 69, 26, 221, 157, 133]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct PlaceOrders {
+    pub orders_type: PlaceOrderType,
+    pub bids: Vec<PlaceMultipleOrdersArgs>,
+    pub asks: Vec<PlaceMultipleOrdersArgs>,
+    pub limit: u8,
+}
+instruction_data!(PlaceOrders, [60, 63, 50, 123, 12, 197, 60, 190]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CancelAllAndPlaceOrders {
+    pub orders_type: PlaceOrderType,
+    pub bids: Vec<PlaceMultipleOrdersArgs>,
+    pub asks: Vec<PlaceMultipleOrdersArgs>,
+    pub limit: u8,
+}
+instruction_data!(
+    CancelAllAndPlaceOrders,
+    [128, 155, 222, 60, 186, 40, 225, 50]
+);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct PlaceOrderPegged {
+    pub args: PlaceOrderPeggedArgs,
+}
+instruction_data!(PlaceOrderPegged, [141, 185, 251, 63, 74, 85, 210, 145]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct PlaceTakeOrder {
+    pub args: PlaceTakeOrderArgs,
+}
+instruction_data!(PlaceTakeOrder, [3, 44, 71, 3, 26, 199, 203, 85]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct ConsumeEvents {
+    pub limit: usize,
+}
+instruction_data!(ConsumeEvents, [221, 145, 177, 52, 31, 47, 63, 201]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct ConsumeGivenEvents {
+    pub slots: Vec<usize>,
+}
+instruction_data!(ConsumeGivenEvents, [209, 227, 54, 4, 109, 172, 41, 71]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CancelOrder {
+    pub order_id: u128,
+}
+instruction_data!(CancelOrder, [95, 129, 237, 240, 8, 49, 223, 132]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CancelOrderByClientOrderId {
+    pub client_order_id: u64,
+}
+instruction_data!(
+    CancelOrderByClientOrderId,
+    [115, 178, 201, 8, 175, 183, 123, 119]
+);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct CancelAllOrders {
+    pub side_option: Option<Side>,
+    pub limit: u8,
+}
+instruction_data!(CancelAllOrders, [196, 83, 243, 171, 17, 100, 160, 143]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct Deposit {
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+instruction_data!(Deposit, [242, 35, 198, 137, 82, 225, 242, 182]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct SettleFunds {}
+instruction_data!(SettleFunds, [238, 64, 163, 96, 75, 171, 16, 33]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct SettleFundsExpired {}
+instruction_data!(SettleFundsExpired, [107, 18, 56, 69, 228, 56, 55, 164]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct SweepFees {}
+instruction_data!(SweepFees, [175, 225, 98, 71, 118, 66, 34, 148]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct SetDelegate {}
+instruction_data!(SetDelegate, [242, 30, 46, 76, 108, 235, 128, 181]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct SetMarketExpired {}
+instruction_data!(SetMarketExpired, [219, 82, 219, 236, 60, 115, 197, 64]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct PruneOrders {
+    pub limit: u8,
+}
+instruction_data!(PruneOrders, [27, 213, 159, 191, 12, 116, 112, 121]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct StubOracleCreate {
+    pub price: f64,
+}
+instruction_data!(StubOracleCreate, [172, 63, 101, 83, 141, 76, 199, 216]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct StubOracleClose {}
+instruction_data!(StubOracleClose, [92, 137, 45, 3, 45, 60, 117, 224]);
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq)]
+pub struct StubOracleSet {
+    pub price: f64,
+}
+instruction_data!(StubOracleSet, [109, 198, 79, 121, 65, 202, 161, 142]);
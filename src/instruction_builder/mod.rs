@@ -0,0 +1,565 @@
+//! Builders for the instructions of the program, for use in client code.
+//!
+//! `accounts` and `instruction` mirror the modules Anchor generates for a program: the account
+//! metas and the instruction data of every instruction. The functions here combine them into
+//! a `solana_program::instruction::Instruction`.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::InstructionData;
+
+use crate::error::*;
+use crate::state::*;
+
+pub mod accounts;
+pub mod instruction;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlaceOrderArgs {
+    pub side: Side,
+    pub price_lots: i64,
+    pub max_base_lots: i64,
+    pub max_quote_lots_including_fees: i64,
+    pub client_order_id: u64,
+    pub order_type: PlaceOrderType,
+    pub expiry_timestamp: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+    // Maximum number of orders from the book to fill.
+    //
+    // Use this to limit compute used during order matching.
+    // When the limit is reached, processing stops and the instruction succeeds.
+    pub limit: u8,
+}
+
+impl PlaceOrderArgs {
+    /// The order the program places for these arguments
+    ///
+    /// On chain `time_in_force` is derived from `expiry_timestamp`, see `Order::tif_from_expiry()`.
+    pub fn to_order(&self, time_in_force: u16) -> Result<Order> {
+        let price_lots = self.price_lots;
+        Ok(Order {
+            side: self.side,
+            max_base_lots: self.max_base_lots,
+            max_quote_lots_including_fees: self.max_quote_lots_including_fees,
+            client_order_id: self.client_order_id,
+            time_in_force,
+            self_trade_behavior: self.self_trade_behavior,
+            params: match self.order_type {
+                PlaceOrderType::Market => OrderParams::Market,
+                PlaceOrderType::ImmediateOrCancel => OrderParams::ImmediateOrCancel { price_lots },
+                PlaceOrderType::FillOrKill => OrderParams::FillOrKill { price_lots },
+                _ => OrderParams::Fixed {
+                    price_lots,
+                    order_type: self.order_type.to_post_order_type()?,
+                },
+            },
+        })
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlaceMultipleOrdersArgs {
+    pub price_lots: i64,
+    pub max_quote_lots_including_fees: i64,
+    pub expiry_timestamp: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlaceOrderPeggedArgs {
+    pub side: Side,
+
+    // The adjustment from the oracle price, in lots (quote lots per base lots).
+    // Orders on the book may be filled at oracle + adjustment (depends on order type).
+    pub price_offset_lots: i64,
+
+    // The limit at which the pegged order shall expire.
+    //
+    // Example: An bid pegged to -20 with peg_limit 100 would expire if the oracle hits 121.
+    pub peg_limit: i64,
+
+    pub max_base_lots: i64,
+    pub max_quote_lots_including_fees: i64,
+    pub client_order_id: u64,
+    pub order_type: PlaceOrderType,
+
+    // Timestamp of when order expires
+    //
+    // Send 0 if you want the order to never expire.
+    // Timestamps in the past mean the instruction is skipped.
+    // Timestamps in the future are reduced to now + 65535s.
+    pub expiry_timestamp: u64,
+
+    pub self_trade_behavior: SelfTradeBehavior,
+
+    // Maximum number of orders from the book to fill.
+    //
+    // Use this to limit compute used during order matching.
+    // When the limit is reached, processing stops and the instruction succeeds.
+    pub limit: u8,
+}
+
+impl PlaceOrderPeggedArgs {
+    /// The order the program places for these arguments, see `PlaceOrderArgs::to_order()`
+    pub fn to_order(&self, time_in_force: u16) -> Result<Order> {
+        Ok(Order {
+            side: self.side,
+            max_base_lots: self.max_base_lots,
+            max_quote_lots_including_fees: self.max_quote_lots_including_fees,
+            client_order_id: self.client_order_id,
+            time_in_force,
+            self_trade_behavior: self.self_trade_behavior,
+            params: OrderParams::OraclePegged {
+                price_offset_lots: self.price_offset_lots,
+                order_type: self.order_type.to_post_order_type()?,
+                peg_limit: self.peg_limit,
+            },
+        })
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct PlaceTakeOrderArgs {
+    pub side: Side,
+    pub price_lots: i64,
+    pub max_base_lots: i64,
+    pub max_quote_lots_including_fees: i64,
+    pub order_type: PlaceOrderType,
+    // Maximum number of orders from the book to fill.
+    //
+    // Use this to limit compute used during order matching.
+    // When the limit is reached, processing stops and the instruction succeeds.
+    pub limit: u8,
+}
+
+impl PlaceTakeOrderArgs {
+    /// The order the program places for these arguments
+    pub fn to_order(&self) -> Result<Order> {
+        let price_lots = self.price_lots;
+        Ok(Order {
+            side: self.side,
+            max_base_lots: self.max_base_lots,
+            max_quote_lots_including_fees: self.max_quote_lots_including_fees,
+            client_order_id: 0,
+            time_in_force: 0,
+            self_trade_behavior: SelfTradeBehavior::default(),
+            params: match self.order_type {
+                PlaceOrderType::Market => OrderParams::Market,
+                PlaceOrderType::ImmediateOrCancel => OrderParams::ImmediateOrCancel { price_lots },
+                PlaceOrderType::FillOrKill => OrderParams::FillOrKill { price_lots },
+                _ => return Err(OpenBookError::InvalidInputOrderType.into()),
+            },
+        })
+    }
+}
+
+fn build(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: crate::ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+fn with_remaining_accounts(mut ix: Instruction, remaining: &[Pubkey]) -> Instruction {
+    ix.accounts
+        .extend(remaining.iter().map(|key| AccountMeta::new(*key, false)));
+    ix
+}
+
+pub fn create_market(
+    accounts: accounts::CreateMarket,
+    args: instruction::CreateMarket,
+) -> Instruction {
+    build(accounts, args)
+}
+
+pub fn close_market(accounts: accounts::CloseMarket) -> Instruction {
+    build(accounts, instruction::CloseMarket {})
+}
+
+pub fn create_open_orders_indexer(accounts: accounts::CreateOpenOrdersIndexer) -> Instruction {
+    build(accounts, instruction::CreateOpenOrdersIndexer {})
+}
+
+pub fn close_open_orders_indexer(accounts: accounts::CloseOpenOrdersIndexer) -> Instruction {
+    build(accounts, instruction::CloseOpenOrdersIndexer {})
+}
+
+pub fn create_open_orders_account(
+    accounts: accounts::CreateOpenOrdersAccount,
+    name: String,
+) -> Instruction {
+    build(accounts, instruction::CreateOpenOrdersAccount { name })
+}
+
+pub fn close_open_orders_account(accounts: accounts::CloseOpenOrdersAccount) -> Instruction {
+    build(accounts, instruction::CloseOpenOrdersAccount {})
+}
+
+/// `makers` are the open orders accounts of makers that may be filled. They are passed as
+/// remaining accounts so their fills are processed right away instead of via the event heap.
+pub fn place_order(
+    accounts: accounts::PlaceOrder,
+    args: PlaceOrderArgs,
+    makers: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(build(accounts, instruction::PlaceOrder { args }), makers)
+}
+
+pub fn place_order_pegged(
+    accounts: accounts::PlaceOrder,
+    args: PlaceOrderPeggedArgs,
+    makers: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(
+        build(accounts, instruction::PlaceOrderPegged { args }),
+        makers,
+    )
+}
+
+pub fn edit_order(
+    accounts: accounts::PlaceOrder,
+    client_order_id: u64,
+    expected_cancel_size: i64,
+    place_order: PlaceOrderArgs,
+    makers: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(
+        build(
+            accounts,
+            instruction::EditOrder {
+                client_order_id,
+                expected_cancel_size,
+                place_order,
+            },
+        ),
+        makers,
+    )
+}
+
+pub fn edit_order_pegged(
+    accounts: accounts::PlaceOrder,
+    client_order_id: u64,
+    expected_cancel_size: i64,
+    place_order: PlaceOrderPeggedArgs,
+    makers: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(
+        build(
+            accounts,
+            instruction::EditOrderPegged {
+                client_order_id,
+                expected_cancel_size,
+                place_order,
+            },
+        ),
+        makers,
+    )
+}
+
+pub fn place_orders(
+    accounts: accounts::CancelAllAndPlaceOrders,
+    args: instruction::PlaceOrders,
+    makers: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(build(accounts, args), makers)
+}
+
+pub fn cancel_all_and_place_orders(
+    accounts: accounts::CancelAllAndPlaceOrders,
+    args: instruction::CancelAllAndPlaceOrders,
+    makers: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(build(accounts, args), makers)
+}
+
+pub fn place_take_order(
+    accounts: accounts::PlaceTakeOrder,
+    args: PlaceTakeOrderArgs,
+    makers: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(
+        build(accounts, instruction::PlaceTakeOrder { args }),
+        makers,
+    )
+}
+
+/// `open_orders_accounts` are the owners of the events that shall be consumed
+pub fn consume_events(
+    accounts: accounts::ConsumeEvents,
+    limit: usize,
+    open_orders_accounts: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(
+        build(accounts, instruction::ConsumeEvents { limit }),
+        open_orders_accounts,
+    )
+}
+
+pub fn consume_given_events(
+    accounts: accounts::ConsumeEvents,
+    slots: Vec<usize>,
+    open_orders_accounts: &[Pubkey],
+) -> Instruction {
+    with_remaining_accounts(
+        build(accounts, instruction::ConsumeGivenEvents { slots }),
+        open_orders_accounts,
+    )
+}
+
+pub fn cancel_order(accounts: accounts::CancelOrder, order_id: u128) -> Instruction {
+    build(accounts, instruction::CancelOrder { order_id })
+}
+
+pub fn cancel_order_by_client_order_id(
+    accounts: accounts::CancelOrder,
+    client_order_id: u64,
+) -> Instruction {
+    build(
+        accounts,
+        instruction::CancelOrderByClientOrderId { client_order_id },
+    )
+}
+
+pub fn cancel_all_orders(
+    accounts: accounts::CancelOrder,
+    side_option: Option<Side>,
+    limit: u8,
+) -> Instruction {
+    build(
+        accounts,
+        instruction::CancelAllOrders { side_option, limit },
+    )
+}
+
+pub fn deposit(accounts: accounts::Deposit, base_amount: u64, quote_amount: u64) -> Instruction {
+    build(
+        accounts,
+        instruction::Deposit {
+            base_amount,
+            quote_amount,
+        },
+    )
+}
+
+pub fn settle_funds(accounts: accounts::SettleFunds) -> Instruction {
+    build(accounts, instruction::SettleFunds {})
+}
+
+pub fn settle_funds_expired(accounts: accounts::SettleFundsExpired) -> Instruction {
+    build(accounts, instruction::SettleFundsExpired {})
+}
+
+pub fn sweep_fees(accounts: accounts::SweepFees) -> Instruction {
+    build(accounts, instruction::SweepFees {})
+}
+
+pub fn set_delegate(accounts: accounts::SetDelegate) -> Instruction {
+    build(accounts, instruction::SetDelegate {})
+}
+
+pub fn set_market_expired(accounts: accounts::SetMarketExpired) -> Instruction {
+    build(accounts, instruction::SetMarketExpired {})
+}
+
+pub fn prune_orders(accounts: accounts::PruneOrders, limit: u8) -> Instruction {
+    build(accounts, instruction::PruneOrders { limit })
+}
+
+pub fn stub_oracle_create(accounts: accounts::StubOracleCreate, price: f64) -> Instruction {
+    build(accounts, instruction::StubOracleCreate { price })
+}
+
+pub fn stub_oracle_close(accounts: accounts::StubOracleClose) -> Instruction {
+    build(accounts, instruction::StubOracleClose {})
+}
+
+pub fn stub_oracle_set(accounts: accounts::StubOracleSet, price: f64) -> Instruction {
+    build(accounts, instruction::StubOracleSet { price })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::hash::hash;
+    use anchor_lang::Discriminator;
+
+    #[test]
+    fn test_sighashes() {
+        fn check<T: Discriminator>(name: &str) {
+            let preimage = format!("global:{name}");
+            assert_eq!(
+                T::DISCRIMINATOR,
+                hash(preimage.as_bytes()).to_bytes()[..8],
+                "{name}"
+            );
+        }
+
+        use instruction::*;
+        check::<CreateMarket>("create_market");
+        check::<CloseMarket>("close_market");
+        check::<CreateOpenOrdersIndexer>("create_open_orders_indexer");
+        check::<CloseOpenOrdersIndexer>("close_open_orders_indexer");
+        check::<CreateOpenOrdersAccount>("create_open_orders_account");
+        check::<CloseOpenOrdersAccount>("close_open_orders_account");
+        check::<PlaceOrder>("place_order");
+        check::<EditOrder>("edit_order");
+        check::<EditOrderPegged>("edit_order_pegged");
+        check::<PlaceOrders>("place_orders");
+        check::<CancelAllAndPlaceOrders>("cancel_all_and_place_orders");
+        check::<PlaceOrderPegged>("place_order_pegged");
+        check::<PlaceTakeOrder>("place_take_order");
+        check::<ConsumeEvents>("consume_events");
+        check::<ConsumeGivenEvents>("consume_given_events");
+        check::<CancelOrder>("cancel_order");
+        check::<CancelOrderByClientOrderId>("cancel_order_by_client_order_id");
+        check::<CancelAllOrders>("cancel_all_orders");
+        check::<Deposit>("deposit");
+        check::<SettleFunds>("settle_funds");
+        check::<SettleFundsExpired>("settle_funds_expired");
+        check::<SweepFees>("sweep_fees");
+        check::<SetDelegate>("set_delegate");
+        check::<SetMarketExpired>("set_market_expired");
+        check::<PruneOrders>("prune_orders");
+        check::<StubOracleCreate>("stub_oracle_create");
+        check::<StubOracleClose>("stub_oracle_close");
+        check::<StubOracleSet>("stub_oracle_set");
+    }
+
+    fn place_order_accounts(open_orders_admin: Option<Pubkey>) -> accounts::PlaceOrder {
+        accounts::PlaceOrder {
+            signer: Pubkey::new_unique(),
+            open_orders_account: Pubkey::new_unique(),
+            open_orders_admin,
+            user_token_account: Pubkey::new_unique(),
+            market: Pubkey::new_unique(),
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            event_heap: Pubkey::new_unique(),
+            market_vault: Pubkey::new_unique(),
+            oracle_a: None,
+            oracle_b: None,
+            oracle_c: None,
+        }
+    }
+
+    #[test]
+    fn test_place_order_round_trip() {
+        let args = PlaceOrderArgs {
+            side: Side::Ask,
+            price_lots: 1234,
+            max_base_lots: 10,
+            max_quote_lots_including_fees: 20_000,
+            client_order_id: 42,
+            order_type: PlaceOrderType::PostOnlySlide,
+            expiry_timestamp: 1_700_000_000,
+            self_trade_behavior: SelfTradeBehavior::CancelProvide,
+            limit: 7,
+        };
+        let maker = Pubkey::new_unique();
+        let ix = place_order(place_order_accounts(None), args, &[maker]);
+
+        assert_eq!(ix.program_id, crate::ID);
+        assert_eq!(ix.data[..8], instruction::PlaceOrder::DISCRIMINATOR);
+        let decoded = instruction::PlaceOrder::try_from_slice(&ix.data[8..]).unwrap();
+        assert_eq!(decoded.args, args);
+
+        let order = decoded.args.to_order(60).unwrap();
+        assert_eq!(order.side, Side::Ask);
+        assert_eq!(order.client_order_id, 42);
+        assert_eq!(order.time_in_force, 60);
+        assert_eq!(order.self_trade_behavior, SelfTradeBehavior::CancelProvide);
+        assert!(matches!(
+            order.params,
+            OrderParams::Fixed {
+                price_lots: 1234,
+                order_type: PostOrderType::PostOnlySlide,
+            }
+        ));
+
+        // 12 accounts of the instruction, then the maker
+        assert_eq!(ix.accounts.len(), 13);
+        assert_eq!(ix.accounts[12], AccountMeta::new(maker, false));
+    }
+
+    #[test]
+    fn test_optional_accounts() {
+        let admin = Pubkey::new_unique();
+        let oracle_c = Pubkey::new_unique();
+        let maker = Pubkey::new_unique();
+        let ix = place_order(
+            accounts::PlaceOrder {
+                oracle_c: Some(oracle_c),
+                ..place_order_accounts(Some(admin))
+            },
+            PlaceOrderArgs {
+                side: Side::Bid,
+                price_lots: 1,
+                max_base_lots: 1,
+                max_quote_lots_including_fees: 1,
+                client_order_id: 0,
+                order_type: PlaceOrderType::Limit,
+                expiry_timestamp: 0,
+                self_trade_behavior: SelfTradeBehavior::default(),
+                limit: 10,
+            },
+            &[maker],
+        );
+
+        // open_orders_admin must sign, missing oracles are passed as the program id
+        assert_eq!(ix.accounts[2], AccountMeta::new_readonly(admin, true));
+        assert_eq!(ix.accounts[9], AccountMeta::new_readonly(crate::ID, false));
+        assert_eq!(ix.accounts[10], AccountMeta::new_readonly(crate::ID, false));
+
+        // oracle_c follows the token program, ahead of the makers
+        assert_eq!(
+            ix.accounts[11],
+            AccountMeta::new_readonly(anchor_spl::token::ID, false)
+        );
+        assert_eq!(ix.accounts[12], AccountMeta::new_readonly(oracle_c, false));
+        assert_eq!(ix.accounts[13], AccountMeta::new(maker, false));
+    }
+
+    #[test]
+    fn test_take_order_rejects_post_types() {
+        let args = PlaceTakeOrderArgs {
+            side: Side::Bid,
+            price_lots: 100,
+            max_base_lots: 1,
+            max_quote_lots_including_fees: 100,
+            order_type: PlaceOrderType::PostOnly,
+            limit: 10,
+        };
+        assert!(args.to_order().is_err());
+
+        let ix = place_take_order(
+            accounts::PlaceTakeOrder {
+                signer: Pubkey::new_unique(),
+                penalty_payer: Pubkey::new_unique(),
+                market: Pubkey::new_unique(),
+                market_authority: Pubkey::new_unique(),
+                bids: Pubkey::new_unique(),
+                asks: Pubkey::new_unique(),
+                market_base_vault: Pubkey::new_unique(),
+                market_quote_vault: Pubkey::new_unique(),
+                event_heap: Pubkey::new_unique(),
+                user_base_account: Pubkey::new_unique(),
+                user_quote_account: Pubkey::new_unique(),
+                oracle_a: None,
+                oracle_b: None,
+                oracle_c: None,
+                open_orders_admin: None,
+            },
+            PlaceTakeOrderArgs {
+                order_type: PlaceOrderType::ImmediateOrCancel,
+                ..args
+            },
+            &[],
+        );
+        let decoded = instruction::PlaceTakeOrder::try_from_slice(&ix.data[8..]).unwrap();
+        assert!(matches!(
+            decoded.args.to_order().unwrap().params,
+            OrderParams::ImmediateOrCancel { price_lots: 100 }
+        ));
+    }
+}
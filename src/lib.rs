@@ -6,8 +6,20 @@ solana_program::declare_id!("opnb2LAfJYbRMAHHvqjCwQxanZn7ReEHp1k81EohpZb");
 pub mod util;
 
 pub mod accounts_zerocopy;
+#[cfg(feature = "client")]
+pub mod audit;
+#[cfg(feature = "client")]
+pub mod crank;
 pub mod error;
+#[cfg(feature = "client")]
+pub mod executor;
+#[cfg(feature = "client")]
+pub mod instruction_builder;
+#[cfg(feature = "client")]
+pub mod log_decoder;
 pub mod logs;
+pub mod pda;
+pub mod processor;
 pub mod pubkey_option;
 pub mod state;
 pub mod token_utils;
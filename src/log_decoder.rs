@@ -0,0 +1,224 @@
+//! Decoding of the events in logs.rs from transaction data.
+//!
+//! Events reach a transaction in two ways:
+//! - `emit_stack` logs them with `sol_log_data`, they show up as `Program data: <base64>`
+//!   log lines of the program invocation.
+//! - `emit_cpi!` passes them as instruction data of a self-CPI, they show up as inner
+//!   instructions to the program, prefixed by `EVENT_IX_TAG_LE`.
+//!
+//! Both carry the 8 byte event discriminator followed by the borsh serialized event.
+
+use anchor_lang::event::EVENT_IX_TAG_LE;
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use base64::Engine;
+
+use crate::logs::*;
+
+const PROGRAM_DATA_PREFIX: &str = "Program data: ";
+
+#[derive(Debug, Clone)]
+pub enum ProgramEvent {
+    Deposit(DepositLog),
+    Fill(FillLog),
+    TakerSignature(TakerSignatureLog),
+    MarketMetaData(MarketMetaDataLog),
+    TotalOrderFill(TotalOrderFillEvent),
+    SetDelegate(SetDelegateLog),
+    SettleFunds(SettleFundsLog),
+    SweepFees(SweepFeesLog),
+    OpenOrdersPosition(OpenOrdersPositionLog),
+}
+
+/// How an event was emitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventSource {
+    /// `sol_log_data`, used by `emit_stack`
+    LogData,
+    /// Self-CPI instruction data, used by `emit_cpi!`
+    Cpi,
+}
+
+impl ProgramEvent {
+    /// Decodes a discriminator-prefixed event
+    ///
+    /// Returns None if the discriminator doesn't belong to any known event.
+    pub fn decode(data: &[u8]) -> Result<Option<Self>> {
+        if data.len() < 8 {
+            return Ok(None);
+        }
+        let (discriminator, mut event_data) = data.split_at(8);
+
+        macro_rules! decode {
+            ($($event:ident => $variant:ident),* $(,)?) => {
+                $(
+                    if discriminator == $event::DISCRIMINATOR {
+                        let event = $event::deserialize(&mut event_data)
+                            .map_err(|e| error_msg!("could not decode {}: {}", stringify!($event), e))?;
+                        return Ok(Some(ProgramEvent::$variant(event)));
+                    }
+                )*
+            };
+        }
+
+        decode!(
+            DepositLog => Deposit,
+            FillLog => Fill,
+            TakerSignatureLog => TakerSignature,
+            MarketMetaDataLog => MarketMetaData,
+            TotalOrderFillEvent => TotalOrderFill,
+            SetDelegateLog => SetDelegate,
+            SettleFundsLog => SettleFunds,
+            SweepFeesLog => SweepFees,
+            OpenOrdersPositionLog => OpenOrdersPosition,
+        );
+
+        Ok(None)
+    }
+}
+
+/// Decodes a `sol_log_data` payload or the data of an `emit_cpi!` inner instruction
+///
+/// Inner instruction data must be from an instruction to this program, otherwise the
+/// `EVENT_IX_TAG_LE` prefix is meaningless.
+pub fn decode_event(data: &[u8]) -> Result<Option<(EventSource, ProgramEvent)>> {
+    let (source, data) = match data.strip_prefix(&EVENT_IX_TAG_LE) {
+        Some(event_data) => (EventSource::Cpi, event_data),
+        None => (EventSource::LogData, data),
+    };
+    Ok(ProgramEvent::decode(data)?.map(|event| (source, event)))
+}
+
+/// Decodes the events logged by this program from a transaction's log messages
+///
+/// Only `Program data:` lines of invocations of this program are considered, data logged by
+/// other programs is skipped. Events of `emit_cpi!` are not part of the logs, they need to be
+/// decoded from the inner instructions with `decode_event()`.
+pub fn decode_logs<S: AsRef<str>>(logs: &[S]) -> Result<Vec<ProgramEvent>> {
+    let program_id = crate::ID.to_string();
+    let mut invocations: Vec<&str> = vec![];
+    let mut events = vec![];
+
+    for line in logs.iter().map(|line| line.as_ref()) {
+        if let Some(data) = line.strip_prefix(PROGRAM_DATA_PREFIX) {
+            if invocations.last() != Some(&program_id.as_str()) {
+                continue;
+            }
+            // sol_log_data logs each field as base64, separated by spaces
+            for field in data.split(' ') {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(field)
+                    .map_err(|e| error_msg!("invalid base64 in program data: {}", e))?;
+                if let Some(event) = ProgramEvent::decode(&bytes)? {
+                    events.push(event);
+                }
+            }
+            continue;
+        }
+
+        let mut words = line.split(' ');
+        if let (Some("Program"), Some(program), Some(action)) =
+            (words.next(), words.next(), words.next())
+        {
+            match action {
+                "invoke" => invocations.push(program),
+                "success" | "failed:" => {
+                    invocations.pop();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program_data(events: &[Vec<u8>]) -> String {
+        let fields: Vec<String> = events
+            .iter()
+            .map(|e| base64::engine::general_purpose::STANDARD.encode(e))
+            .collect();
+        format!("{}{}", PROGRAM_DATA_PREFIX, fields.join(" "))
+    }
+
+    fn log_data<T: anchor_lang::Event>(event: &T) -> Vec<u8> {
+        let mut data = T::DISCRIMINATOR.to_vec();
+        event.serialize(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_decode_logs() {
+        let deposit = DepositLog {
+            open_orders_account: Pubkey::new_unique(),
+            signer: Pubkey::new_unique(),
+            base_amount: 10,
+            quote_amount: 20,
+        };
+        let sweep = SweepFeesLog {
+            market: Pubkey::new_unique(),
+            amount: 30,
+            receiver: Pubkey::new_unique(),
+        };
+        let other_program = Pubkey::new_unique().to_string();
+        let logs = vec![
+            format!("Program {} invoke [1]", crate::ID),
+            "Program log: Instruction: Deposit".to_string(),
+            format!("Program {} invoke [2]", other_program),
+            // same payload, but logged by another program
+            program_data(&[log_data(&deposit)]),
+            format!("Program {} success", other_program),
+            program_data(&[log_data(&deposit), log_data(&sweep)]),
+            format!(
+                "Program {} consumed 1000 of 200000 compute units",
+                crate::ID
+            ),
+            format!("Program {} success", crate::ID),
+        ];
+
+        let events = decode_logs(&logs).unwrap();
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            ProgramEvent::Deposit(log) => {
+                assert_eq!(log.open_orders_account, deposit.open_orders_account);
+                assert_eq!(log.base_amount, 10);
+                assert_eq!(log.quote_amount, 20);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(matches!(&events[1], ProgramEvent::SweepFees(log) if log.amount == 30));
+    }
+
+    #[test]
+    fn test_decode_event_source() {
+        let meta = MarketMetaDataLog {
+            market: Pubkey::new_unique(),
+            name: "SOL-USDC".to_string(),
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            base_decimals: 9,
+            quote_decimals: 6,
+            base_lot_size: 100,
+            quote_lot_size: 10,
+        };
+
+        let mut cpi_data = EVENT_IX_TAG_LE.to_vec();
+        cpi_data.extend(log_data(&meta));
+        let (source, event) = decode_event(&cpi_data).unwrap().unwrap();
+        assert_eq!(source, EventSource::Cpi);
+        assert!(matches!(event, ProgramEvent::MarketMetaData(log) if log.name == "SOL-USDC"));
+
+        let (source, _) = decode_event(&log_data(&meta)).unwrap().unwrap();
+        assert_eq!(source, EventSource::LogData);
+
+        // unknown discriminators are skipped, truncated events are errors
+        assert!(decode_event(&[1, 2, 3, 4, 5, 6, 7, 8, 9])
+            .unwrap()
+            .is_none());
+        assert!(decode_event(&log_data(&meta)[..20]).is_err());
+    }
+}
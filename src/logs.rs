@@ -18,6 +18,7 @@ pub fn emit_stack<T: anchor_lang::Event>(e: T) {
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct DepositLog {
     pub open_orders_account: Pubkey,
     pub signer: Pubkey,
@@ -26,6 +27,7 @@ pub struct DepositLog {
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct FillLog {
     pub market: Pubkey,
     pub taker_side: u8, // side from the taker's POV
@@ -50,12 +52,14 @@ pub struct FillLog {
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct TakerSignatureLog {
     pub market: Pubkey,
     pub seq_num: u64,
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct MarketMetaDataLog {
     pub market: Pubkey,
     pub name: String,
@@ -68,6 +72,7 @@ pub struct MarketMetaDataLog {
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct TotalOrderFillEvent {
     pub side: u8,
     pub taker: Pubkey,
@@ -77,12 +82,14 @@ pub struct TotalOrderFillEvent {
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct SetDelegateLog {
     pub open_orders_account: Pubkey,
     pub delegate: Option<Pubkey>,
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct SettleFundsLog {
     pub open_orders_account: Pubkey,
     pub base_native: u64,
@@ -92,6 +99,7 @@ pub struct SettleFundsLog {
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct SweepFeesLog {
     pub market: Pubkey,
     pub amount: u64,
@@ -99,6 +107,7 @@ pub struct SweepFeesLog {
 }
 
 #[event]
+#[derive(Debug, Clone)]
 pub struct OpenOrdersPositionLog {
     pub owner: Pubkey,
     pub open_orders_account_num: u32,
@@ -0,0 +1,110 @@
+//! Derivation of the program derived addresses used by the instructions.
+//!
+//! Every function returns the address together with its bump seed.
+
+use anchor_lang::prelude::*;
+
+/// Seed of the market authority, which owns the market vaults and signs with `market_seeds!`
+pub const MARKET_SEED: &[u8] = b"Market";
+pub const OPEN_ORDERS_INDEXER_SEED: &[u8] = b"OpenOrdersIndexer";
+pub const OPEN_ORDERS_SEED: &[u8] = b"OpenOrders";
+pub const STUB_ORACLE_SEED: &[u8] = b"StubOracle";
+/// Seed of the signer of the self-CPI that `emit_cpi!` uses to log events
+pub const EVENT_AUTHORITY_SEED: &[u8] = b"__event_authority";
+
+pub fn market_authority(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[MARKET_SEED, market.as_ref()], &crate::ID)
+}
+
+pub fn open_orders_indexer(owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[OPEN_ORDERS_INDEXER_SEED, owner.as_ref()], &crate::ID)
+}
+
+/// `account_num` starts at 1 and is the indexer's `created_counter` after creation
+pub fn open_orders_account(owner: &Pubkey, account_num: u32) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[OPEN_ORDERS_SEED, owner.as_ref(), &account_num.to_le_bytes()],
+        &crate::ID,
+    )
+}
+
+pub fn stub_oracle(owner: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[STUB_ORACLE_SEED, owner.as_ref(), mint.as_ref()],
+        &crate::ID,
+    )
+}
+
+pub fn event_authority() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[EVENT_AUTHORITY_SEED], &crate::ID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn pubkey(s: &str) -> Pubkey {
+        Pubkey::from_str(s).unwrap()
+    }
+
+    // Regression vectors: the expected addresses were computed with this module for arbitrary
+    // keys. They catch accidental changes to the seeds, but weren't checked against deployed
+    // accounts.
+    const MARKET: &str = "CFSMrBssNG8Ud1edW59jNLnq2cwrQ9uY5cM3wXmqRJj3";
+    const OWNER: &str = "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM";
+
+    #[test]
+    fn test_market_authority_unchanged() {
+        assert_eq!(
+            market_authority(&pubkey(MARKET)),
+            (pubkey("B44ts4KVwst9dYSYqGB5vY4Wee2KB3AK3e92yEdJzwrw"), 255)
+        );
+    }
+
+    #[test]
+    fn test_open_orders_unchanged() {
+        let owner = pubkey(OWNER);
+        assert_eq!(
+            open_orders_indexer(&owner),
+            (pubkey("GWYrbqLoPHk32ZhgfAWuWpXUDNCLyBBX24MABjKovdH3"), 255)
+        );
+        assert_eq!(
+            open_orders_account(&owner, 1),
+            (pubkey("BZSb2am9ErYBkFXXCj6tx1zSbcaRXRoBFQPP4rb7M9kK"), 253)
+        );
+        assert_eq!(
+            open_orders_account(&owner, 2),
+            (pubkey("Cpsp4DNJZwRtsJSBEYuKVyqnN9xSNmz7TtJ4RxeQURPj"), 247)
+        );
+    }
+
+    #[test]
+    fn test_other_addresses_unchanged() {
+        assert_eq!(
+            stub_oracle(
+                &pubkey(OWNER),
+                &pubkey("So11111111111111111111111111111111111111112")
+            ),
+            (pubkey("AXbujJtVuKhS6Ufrvmh264Qou4S4cNVsqr9b6oGK2xc4"), 255)
+        );
+        assert_eq!(
+            event_authority(),
+            (pubkey("8rDvL1qM41mYVNYS6oKLfGXyBGov3jLtbELToxjSwShj"), 255)
+        );
+    }
+
+    #[test]
+    fn test_market_seeds() {
+        let market_pk = pubkey(MARKET);
+        let (authority, bump) = market_authority(&market_pk);
+        struct MarketBump {
+            bump: u8,
+        }
+        let seeds = crate::market_seeds!(MarketBump { bump }, market_pk);
+        assert_eq!(
+            Pubkey::create_program_address(seeds, &crate::ID).unwrap(),
+            authority
+        );
+    }
+}
@@ -0,0 +1,937 @@
+//! State transitions of the instructions on plain account references.
+//!
+//! Each function holds the logic of one instruction, without the token and lamport CPIs, which
+//! are left to the caller. `executor::Executor` calls them on accounts kept in memory. The
+//! handlers in `instructions` aren't built as part of this crate, as their Anchor account
+//! structs aren't, so they don't call into this module yet.
+//!
+//! Anchor account constraints (signers, `has_one`, PDAs, vault addresses) are checked before
+//! these functions are called and aren't repeated here.
+
+use anchor_lang::prelude::*;
+use itertools::Itertools;
+use std::cmp;
+
+use crate::accounts_zerocopy::KeyedAccountReader;
+use crate::error::*;
+use crate::logs::{emit_stack, DepositLog, SetDelegateLog, SettleFundsLog, SweepFeesLog};
+use crate::pubkey_option::NonZeroPubkeyOption;
+use crate::state::*;
+use crate::util::fill_from_str;
+
+/// Accounts of a new market that end up stored in it
+#[derive(Clone, Copy, Debug)]
+pub struct NewMarketAccounts {
+    pub market: Pubkey,
+    pub market_authority: Pubkey,
+    pub market_authority_bump: u8,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub event_heap: Pubkey,
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
+    pub market_base_vault: Pubkey,
+    pub market_quote_vault: Pubkey,
+    pub collect_fee_admin: Pubkey,
+    pub open_orders_admin: NonZeroPubkeyOption,
+    pub consume_events_admin: NonZeroPubkeyOption,
+    pub close_market_admin: NonZeroPubkeyOption,
+}
+
+/// Validate the parameters and oracles of a new market and build it
+///
+/// The bids, asks and event heap still have to be initialized by the caller.
+#[allow(clippy::too_many_arguments)]
+pub fn create_market<T: KeyedAccountReader>(
+    accounts: &NewMarketAccounts,
+    oracles: [Option<&T>; 3],
+    name: &str,
+    oracle_config: &OracleConfigParams,
+    quote_lot_size: i64,
+    base_lot_size: i64,
+    maker_fee: i64,
+    taker_fee: i64,
+    time_expiry: i64,
+    clock: &Clock,
+) -> Result<Market> {
+    require!(
+        maker_fee.unsigned_abs() as i128 <= FEES_SCALE_FACTOR,
+        OpenBookError::InvalidInputMarketFees
+    );
+    require!(
+        taker_fee.unsigned_abs() as i128 <= FEES_SCALE_FACTOR,
+        OpenBookError::InvalidInputMarketFees
+    );
+    require!(
+        taker_fee >= 0 && (maker_fee >= 0 || maker_fee.abs() <= taker_fee),
+        OpenBookError::InvalidInputMarketFees
+    );
+
+    require!(
+        time_expiry == 0 || time_expiry > clock.unix_timestamp,
+        OpenBookError::InvalidInputMarketExpired
+    );
+
+    require_gt!(quote_lot_size, 0, OpenBookError::InvalidInputLots);
+    require_gt!(base_lot_size, 0, OpenBookError::InvalidInputLots);
+
+    let [oracle_a_acc, oracle_b_acc, oracle_c_acc] = oracles;
+    let oracle_a: NonZeroPubkeyOption = oracle_a_acc.map(|acc| *acc.key()).into();
+    let oracle_b: NonZeroPubkeyOption = oracle_b_acc.map(|acc| *acc.key()).into();
+    let oracle_c: NonZeroPubkeyOption = oracle_c_acc.map(|acc| *acc.key()).into();
+
+    let base_mint = &accounts.base_mint;
+    let quote_mint = &accounts.quote_mint;
+    let oracle_config = oracle_config.to_oracle_config();
+    if oracle_config.combination()? == OracleCombination::Median {
+        require!(
+            oracle_a.is_some() && oracle_b.is_some(),
+            OpenBookError::InvalidOracleCombination
+        );
+        for (i, oracle) in oracles.iter().enumerate() {
+            if let Some(oracle) = oracle {
+                require!(
+                    oracles[..i]
+                        .iter()
+                        .flatten()
+                        .all(|other| other.key() != oracle.key()),
+                    OpenBookError::InvalidOracleCombination
+                );
+                check_oracle_source(*oracle, &oracle_config, base_mint, quote_mint)?;
+            }
+        }
+    } else if let Some(oracle_a_acc) = oracle_a_acc.filter(|acc| is_openbook_market(*acc)) {
+        // the book of the market in oracle_b and oracle_c
+        match (oracle_b_acc, oracle_c_acc) {
+            (Some(oracle_b_acc), Some(oracle_c_acc)) => check_openbook_market_oracle(
+                oracle_a_acc,
+                oracle_b_acc,
+                oracle_c_acc,
+                &oracle_config,
+                &accounts.market,
+                base_mint,
+                quote_mint,
+            )?,
+            _ => return Err(OpenBookError::InvalidOracleCombination.into()),
+        }
+    } else if oracle_c_acc.is_some() {
+        // oracle_c is either a third oracle of the median or the asks of an OpenBook market,
+        // see Market::oracle_c
+        return Err(OpenBookError::InvalidOracleCombination.into());
+    } else if let (Some(oracle_a_acc), Some(oracle_b_acc)) = (oracle_a_acc, oracle_b_acc) {
+        require_keys_neq!(*oracle_a_acc.key(), *oracle_b_acc.key());
+        check_oracle_pair(oracle_a_acc, oracle_b_acc, &oracle_config)?;
+        if is_raydium_observation(oracle_b_acc) {
+            check_raydium_clmm_mints(oracle_a_acc, base_mint, quote_mint)?;
+        }
+    } else if let Some(oracle_a_acc) = oracle_a_acc {
+        check_oracle_source(oracle_a_acc, &oracle_config, base_mint, quote_mint)?;
+    } else if oracle_b_acc.is_some() {
+        return Err(OpenBookError::InvalidSecondOracle.into());
+    }
+
+    let mut oracle_feed_ids = [[0; 32]; 3];
+    for (feed_id, oracle) in oracle_feed_ids.iter_mut().zip(oracles) {
+        if let Some(oracle) = oracle {
+            *feed_id = pyth_pull_feed_id(oracle)?.unwrap_or_default();
+        }
+    }
+
+    Ok(Market {
+        market_authority: accounts.market_authority,
+        collect_fee_admin: accounts.collect_fee_admin,
+        open_orders_admin: accounts.open_orders_admin,
+        consume_events_admin: accounts.consume_events_admin,
+        close_market_admin: accounts.close_market_admin,
+        bump: accounts.market_authority_bump,
+        base_decimals: accounts.base_decimals,
+        quote_decimals: accounts.quote_decimals,
+        padding1: Default::default(),
+        time_expiry,
+        name: fill_from_str(name)?,
+        bids: accounts.bids,
+        asks: accounts.asks,
+        event_heap: accounts.event_heap,
+        oracle_a,
+        oracle_b,
+        oracle_config,
+        quote_lot_size,
+        base_lot_size,
+        seq_num: 0,
+        registration_time: clock.unix_timestamp,
+        maker_fee,
+        taker_fee,
+        fees_accrued: 0u128.into(),
+        fees_to_referrers: 0u128.into(),
+        maker_volume: 0u128.into(),
+        taker_volume_wo_oo: 0u128.into(),
+        base_mint: accounts.base_mint,
+        quote_mint: accounts.quote_mint,
+        market_base_vault: accounts.market_base_vault,
+        base_deposit_total: 0,
+        market_quote_vault: accounts.market_quote_vault,
+        quote_deposit_total: 0,
+        fees_available: 0,
+        referrer_rebates_accrued: 0,
+
+        oracle_c,
+        oracle_feed_ids,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_open_orders_account(
+    account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    bump: u8,
+    indexer: &mut OpenOrdersIndexer,
+    market_pk: &Pubkey,
+    owner: &Pubkey,
+    delegate: NonZeroPubkeyOption,
+    name: &str,
+) -> Result<()> {
+    indexer.addresses.push(*open_orders_account_pk);
+    indexer.created_counter += 1;
+
+    account.name = fill_from_str(name)?;
+    account.account_num = indexer.created_counter;
+    account.market = *market_pk;
+    account.bump = bump;
+    account.owner = *owner;
+    account.delegate = delegate;
+    account.version = 1;
+    account.open_orders = [OpenOrder::default(); MAX_OPEN_ORDERS];
+
+    Ok(())
+}
+
+/// Credit the free balances, the caller moves the tokens into the vaults
+pub fn deposit(
+    market: &mut Market,
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    signer: &Pubkey,
+    base_amount: u64,
+    quote_amount: u64,
+    clock: &Clock,
+) -> Result<()> {
+    require!(
+        !market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasExpired
+    );
+
+    open_orders_account.position.base_free_native += base_amount;
+    market.base_deposit_total += base_amount;
+
+    open_orders_account.position.quote_free_native += quote_amount;
+    market.quote_deposit_total += quote_amount;
+
+    if base_amount > 0 || quote_amount > 0 {
+        emit_stack(DepositLog {
+            open_orders_account: *open_orders_account_pk,
+            signer: *signer,
+            base_amount,
+            quote_amount,
+        });
+    }
+
+    Ok(())
+}
+
+fn oracle_price_lots<T: KeyedAccountReader>(
+    market: &Market,
+    [oracle_a, oracle_b, oracle_c]: [Option<&T>; 3],
+    clock: &Clock,
+) -> Result<Option<i64>> {
+    market.oracle_price_lots(
+        oracle_a,
+        oracle_b,
+        oracle_c,
+        clock.slot,
+        clock.unix_timestamp,
+    )
+}
+
+/// Match `order` and post the remainder for `open_orders_account`
+///
+/// Returns the order id and the amount the signer has to deposit into the vault of the
+/// order's side, after using up the free balance.
+#[allow(clippy::too_many_arguments)]
+pub fn place_order<T: KeyedAccountReader>(
+    market: &mut Market,
+    market_pk: &Pubkey,
+    book: &mut Orderbook,
+    event_heap: &mut EventHeap,
+    oracles: [Option<&T>; 3],
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    order: &Order,
+    limit: u8,
+    makers: impl MakerAccountResolver,
+    clock: &Clock,
+) -> Result<(Option<u128>, u64)> {
+    require_gte!(order.max_base_lots, 0, OpenBookError::InvalidInputLots);
+    require_gte!(
+        order.max_quote_lots_including_fees,
+        0,
+        OpenBookError::InvalidInputLots
+    );
+    require!(
+        !market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasExpired
+    );
+
+    let event_heap_size_before = event_heap.len();
+
+    let now_ts: u64 = clock.unix_timestamp.try_into().unwrap();
+
+    let oracle_price_lots = oracle_price_lots(market, oracles, clock)?;
+
+    let OrderWithAmounts {
+        order_id,
+        total_base_taken_native,
+        total_quote_taken_native,
+        posted_base_native,
+        posted_quote_native,
+        taker_fees,
+        maker_fees,
+        ..
+    } = book.new_order(
+        order,
+        market,
+        market_pk,
+        event_heap,
+        oracle_price_lots,
+        Some(&mut *open_orders_account),
+        open_orders_account_pk,
+        now_ts,
+        limit,
+        makers,
+    )?;
+
+    let position = &mut open_orders_account.position;
+    let deposit_amount = match order.side {
+        Side::Bid => {
+            let free_quote = position.quote_free_native;
+            let max_quote_including_fees =
+                total_quote_taken_native + posted_quote_native + taker_fees + maker_fees;
+
+            let free_qty_to_lock = cmp::min(max_quote_including_fees, free_quote);
+            let deposit_amount = max_quote_including_fees - free_qty_to_lock;
+
+            // Update market deposit total
+            position.quote_free_native -= free_qty_to_lock;
+            market.quote_deposit_total += deposit_amount;
+
+            deposit_amount
+        }
+
+        Side::Ask => {
+            let free_base = position.base_free_native;
+            let max_base_native = total_base_taken_native + posted_base_native;
+
+            let free_qty_to_lock = cmp::min(max_base_native, free_base);
+            let deposit_amount = max_base_native - free_qty_to_lock;
+
+            // Update market deposit total
+            position.base_free_native -= free_qty_to_lock;
+            market.base_deposit_total += deposit_amount;
+
+            deposit_amount
+        }
+    };
+
+    if event_heap.len() > event_heap_size_before {
+        position.penalty_heap_count += 1;
+    }
+
+    Ok((order_id, deposit_amount))
+}
+
+/// Cancel the order with `cancel_client_order_id` and place `order`, reduced by what
+/// was filled of the canceled one
+///
+/// Returns the same as `place_order`.
+#[allow(clippy::too_many_arguments)]
+pub fn edit_order<T: KeyedAccountReader>(
+    market: &mut Market,
+    market_pk: &Pubkey,
+    book: &mut Orderbook,
+    event_heap: &mut EventHeap,
+    oracles: [Option<&T>; 3],
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    cancel_client_order_id: u64,
+    expected_cancel_size: i64,
+    mut order: Order,
+    limit: u8,
+    makers: impl MakerAccountResolver,
+    clock: &Clock,
+) -> Result<(Option<u128>, u64)> {
+    require_gte!(
+        expected_cancel_size,
+        0,
+        OpenBookError::InvalidInputCancelSize
+    );
+
+    let leaf_node_quantity =
+        cancel_order_by_client_order_id(market, book, open_orders_account, cancel_client_order_id)?;
+
+    let filled_amount = expected_cancel_size - leaf_node_quantity;
+    // note that order.max_base_lots is checked to be > 0 inside `place_order`
+    if filled_amount > 0 && order.max_base_lots > filled_amount {
+        // Do not reduce max_quote_lots_including_fees as implicitly it's limited by max_base_lots.
+        order.max_base_lots -= filled_amount;
+        return place_order(
+            market,
+            market_pk,
+            book,
+            event_heap,
+            oracles,
+            open_orders_account,
+            open_orders_account_pk,
+            &order,
+            limit,
+            makers,
+            clock,
+        );
+    }
+    Ok((None, 0))
+}
+
+/// Optionally cancel all orders of `open_orders_account`, then place `orders`
+///
+/// Orders are capped by the free balance plus `user_base_amount` / `user_quote_amount`,
+/// the balances of the user token accounts. Returns the order ids and the base and quote
+/// amounts the signer has to deposit.
+#[allow(clippy::too_many_arguments)]
+pub fn cancel_all_and_place_orders<T: KeyedAccountReader>(
+    market: &mut Market,
+    market_pk: &Pubkey,
+    book: &mut Orderbook,
+    event_heap: &mut EventHeap,
+    oracles: [Option<&T>; 3],
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    user_base_amount: u64,
+    user_quote_amount: u64,
+    cancel: bool,
+    mut orders: Vec<Order>,
+    limit: u8,
+    mut makers: impl MakerAccountResolver,
+    clock: &Clock,
+) -> Result<(Vec<Option<u128>>, u64, u64)> {
+    require!(
+        !market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasExpired
+    );
+
+    let event_heap_size_before = event_heap.len();
+
+    let now_ts: u64 = clock.unix_timestamp.try_into().unwrap();
+
+    let oracle_price_lots = oracle_price_lots(market, oracles, clock)?;
+
+    if cancel {
+        book.cancel_all_orders(open_orders_account, *market, u8::MAX, None, None)?;
+    }
+
+    let mut base_amount = 0_u64;
+    let mut quote_amount = 0_u64;
+    let mut order_ids = Vec::new();
+    for order in orders.iter_mut() {
+        order.max_base_lots = market.max_base_lots();
+        require_gte!(
+            order.max_quote_lots_including_fees,
+            0,
+            OpenBookError::InvalidInputLots
+        );
+
+        match order.side {
+            Side::Ask => {
+                let max_available_base =
+                    user_base_amount + open_orders_account.position.base_free_native - base_amount;
+                order.max_base_lots = std::cmp::min(
+                    order.max_base_lots,
+                    market.max_base_lots_from_lamports(max_available_base),
+                );
+            }
+            Side::Bid => {
+                let max_available_quote = user_quote_amount
+                    + open_orders_account.position.quote_free_native
+                    - quote_amount;
+                order.max_quote_lots_including_fees = std::cmp::min(
+                    order.max_quote_lots_including_fees,
+                    market.max_quote_lots_from_lamports(max_available_quote),
+                );
+            }
+        }
+
+        let OrderWithAmounts {
+            order_id,
+            total_base_taken_native,
+            total_quote_taken_native,
+            posted_base_native,
+            posted_quote_native,
+            taker_fees,
+            maker_fees,
+            ..
+        } = book.new_order(
+            order,
+            market,
+            market_pk,
+            event_heap,
+            oracle_price_lots,
+            Some(&mut *open_orders_account),
+            open_orders_account_pk,
+            now_ts,
+            limit,
+            &mut makers,
+        )?;
+
+        match order.side {
+            Side::Bid => {
+                quote_amount = quote_amount
+                    .checked_add(
+                        total_quote_taken_native + posted_quote_native + taker_fees + maker_fees,
+                    )
+                    .ok_or(OpenBookError::InvalidInputOrdersAmounts)?;
+            }
+            Side::Ask => {
+                base_amount = base_amount
+                    .checked_add(total_base_taken_native + posted_base_native)
+                    .ok_or(OpenBookError::InvalidInputOrdersAmounts)?;
+            }
+        };
+
+        order_ids.push(order_id);
+    }
+
+    let position = &mut open_orders_account.position;
+
+    let free_base_to_lock = cmp::min(base_amount, position.base_free_native);
+    let free_quote_to_lock = cmp::min(quote_amount, position.quote_free_native);
+
+    let deposit_base_amount = base_amount - free_base_to_lock;
+    let deposit_quote_amount = quote_amount - free_quote_to_lock;
+
+    position.base_free_native -= free_base_to_lock;
+    position.quote_free_native -= free_quote_to_lock;
+
+    market.base_deposit_total += deposit_base_amount;
+    market.quote_deposit_total += deposit_quote_amount;
+
+    if event_heap.len() > event_heap_size_before {
+        position.penalty_heap_count += 1;
+    }
+
+    Ok((order_ids, deposit_base_amount, deposit_quote_amount))
+}
+
+/// Tokens moved by `place_take_order`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TakeOrderAmounts {
+    /// Into the vault of the order's side, from the signer
+    pub deposit_amount: u64,
+    /// Out of the other vault, to the signer
+    pub withdraw_amount: u64,
+    /// Whether events were added to the heap, which costs the penalty payer lamports
+    pub event_heap_penalty: bool,
+}
+
+/// Match `order` without an open orders account, nothing is posted
+#[allow(clippy::too_many_arguments)]
+pub fn place_take_order<T: KeyedAccountReader>(
+    market: &mut Market,
+    market_pk: &Pubkey,
+    book: &mut Orderbook,
+    event_heap: &mut EventHeap,
+    oracles: [Option<&T>; 3],
+    signer: &Pubkey,
+    order: &Order,
+    limit: u8,
+    makers: impl MakerAccountResolver,
+    clock: &Clock,
+) -> Result<TakeOrderAmounts> {
+    require_gte!(order.max_base_lots, 0, OpenBookError::InvalidInputLots);
+    require_gte!(
+        order.max_quote_lots_including_fees,
+        0,
+        OpenBookError::InvalidInputLots
+    );
+    require!(
+        !market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasExpired
+    );
+
+    let event_heap_size_before = event_heap.len();
+
+    let now_ts: u64 = clock.unix_timestamp.try_into().unwrap();
+
+    let oracle_price_lots = oracle_price_lots(market, oracles, clock)?;
+
+    let OrderWithAmounts {
+        total_base_taken_native,
+        total_quote_taken_native,
+        referrer_amount,
+        taker_fees,
+        ..
+    } = book.new_order(
+        order,
+        market,
+        market_pk,
+        event_heap,
+        oracle_price_lots,
+        None,
+        signer,
+        now_ts,
+        limit,
+        makers,
+    )?;
+
+    // place_take_orders doesnt pay to referrers
+    let makers_rebates = taker_fees - referrer_amount;
+
+    let (deposit_amount, withdraw_amount) = match order.side {
+        Side::Bid => {
+            let total_quote_including_fees = total_quote_taken_native + makers_rebates;
+            market.base_deposit_total -= total_base_taken_native;
+            market.quote_deposit_total += total_quote_including_fees;
+            (total_quote_including_fees, total_base_taken_native)
+        }
+        Side::Ask => {
+            let total_quote_discounting_fees = total_quote_taken_native - makers_rebates;
+            market.base_deposit_total += total_base_taken_native;
+            market.quote_deposit_total -= total_quote_discounting_fees;
+            (total_base_taken_native, total_quote_discounting_fees)
+        }
+    };
+
+    Ok(TakeOrderAmounts {
+        deposit_amount,
+        withdraw_amount,
+        event_heap_penalty: event_heap.len() > event_heap_size_before,
+    })
+}
+
+pub fn cancel_order(
+    market: &Market,
+    book: &mut Orderbook,
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    order_id: u128,
+) -> Result<()> {
+    require_gt!(order_id, 0, OpenBookError::InvalidInputOrderId);
+
+    let oo = open_orders_account
+        .find_order_with_order_id(order_id)
+        .ok_or_else(|| {
+            error_msg_typed!(OpenBookError::OpenOrdersOrderNotFound, "id = {order_id}")
+        })?;
+
+    let order_id = oo.id;
+    let order_side_and_tree = oo.side_and_tree();
+
+    book.cancel_order(
+        open_orders_account,
+        order_id,
+        order_side_and_tree,
+        *market,
+        Some(*open_orders_account_pk),
+    )?;
+
+    Ok(())
+}
+
+/// Returns the quantity left on the book of the canceled orders
+pub fn cancel_order_by_client_order_id(
+    market: &Market,
+    book: &mut Orderbook,
+    open_orders_account: &mut OpenOrdersAccount,
+    client_order_id: u64,
+) -> Result<i64> {
+    book.cancel_all_orders(
+        open_orders_account,
+        *market,
+        u8::MAX,
+        None,
+        Some(client_order_id),
+    )
+}
+
+pub fn cancel_all_orders(
+    market: &Market,
+    book: &mut Orderbook,
+    open_orders_account: &mut OpenOrdersAccount,
+    side_option: Option<Side>,
+    limit: u8,
+) -> Result<()> {
+    book.cancel_all_orders(open_orders_account, *market, limit, side_option, None)?;
+
+    Ok(())
+}
+
+/// Apply up to `limit` events to the accounts of their owners and remove them from the heap
+///
+/// The events in `slots` go first. Events whose owner `makers` can't resolve stay on the heap.
+pub fn consume_events(
+    market: &mut Market,
+    event_heap: &mut EventHeap,
+    limit: usize,
+    slots: Option<Vec<usize>>,
+    mut makers: impl MakerAccountResolver,
+) -> Result<()> {
+    let limit = cmp::min(limit, MAX_EVENTS_CONSUME);
+
+    let slots_to_consume = slots
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|slot| !event_heap.nodes[*slot].is_free())
+        .chain(event_heap.iter().map(|(_event, slot)| slot))
+        .unique()
+        .take(limit)
+        .collect_vec();
+
+    for slot in slots_to_consume {
+        let event = Event::decode(event_heap.at_slot(slot).unwrap())?;
+        let consumed = match event {
+            Event::Fill(fill) => {
+                makers.with_maker(&fill.maker, |maker| maker.execute_maker(market, &fill))?
+            }
+            Event::Out(out) => makers.with_maker(&out.owner, |owner| {
+                owner.cancel_order(out.owner_slot as usize, out.quantity, *market)
+            })?,
+        };
+        if consumed.is_none() {
+            msg!(
+                "Unable to find open orders account {}, skipping",
+                event.owner()
+            );
+            continue;
+        }
+
+        // consume this event
+        event_heap.delete_slot(slot)?;
+    }
+
+    Ok(())
+}
+
+/// Funds paid out by `settle_funds`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settlement {
+    pub base_native: u64,
+    pub quote_native: u64,
+    /// Quote to the referrer account if there is one, otherwise added to the market fees
+    pub referrer_rebate: u64,
+    /// Events this account added to the heap, each one costs PENALTY_EVENT_HEAP lamports
+    pub penalty_heap_count: u64,
+}
+
+/// Empty the free balances of `open_orders_account`, the caller moves the tokens out of the
+/// vaults
+pub fn settle_funds(
+    market: &mut Market,
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    referrer: Option<&Pubkey>,
+) -> Result<Settlement> {
+    let mut roundoff_maker_fees = 0;
+
+    if market.maker_fee.is_positive() && open_orders_account.position.bids_base_lots == 0 {
+        roundoff_maker_fees = open_orders_account.position.locked_maker_fees;
+        open_orders_account.position.locked_maker_fees = 0;
+    }
+
+    let pa = &mut open_orders_account.position;
+    let referrer_rebate = pa.referrer_rebates_available + roundoff_maker_fees;
+
+    if referrer.is_some() {
+        market.fees_to_referrers += referrer_rebate as u128;
+        market.quote_deposit_total -= referrer_rebate;
+    } else {
+        market.fees_available += referrer_rebate;
+    }
+
+    market.base_deposit_total -= pa.base_free_native;
+    market.quote_deposit_total -= pa.quote_free_native;
+    market.referrer_rebates_accrued -= pa.referrer_rebates_available;
+
+    let settlement = Settlement {
+        base_native: pa.base_free_native,
+        quote_native: pa.quote_free_native,
+        referrer_rebate,
+        penalty_heap_count: pa.penalty_heap_count,
+    };
+
+    emit_stack(SettleFundsLog {
+        open_orders_account: *open_orders_account_pk,
+        base_native: settlement.base_native,
+        quote_native: settlement.quote_native,
+        referrer_rebate,
+        referrer: referrer.copied(),
+    });
+
+    pa.base_free_native = 0;
+    pa.quote_free_native = 0;
+    pa.referrer_rebates_available = 0;
+    pa.penalty_heap_count = 0;
+
+    Ok(settlement)
+}
+
+pub fn settle_funds_expired(
+    market: &mut Market,
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    referrer: Option<&Pubkey>,
+    clock: &Clock,
+) -> Result<Settlement> {
+    require!(
+        market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasNotExpired
+    );
+
+    settle_funds(
+        market,
+        open_orders_account,
+        open_orders_account_pk,
+        referrer,
+    )
+}
+
+/// Returns the amount of quote to move from the vault to `token_receiver`
+pub fn sweep_fees(market: &mut Market, market_pk: &Pubkey, token_receiver: &Pubkey) -> u64 {
+    let amount = market.fees_available;
+    market.fees_available = 0;
+    market.quote_deposit_total -= amount;
+
+    emit_stack(SweepFeesLog {
+        market: *market_pk,
+        amount,
+        receiver: *token_receiver,
+    });
+
+    amount
+}
+
+pub fn set_market_expired(market: &mut Market, clock: &Clock) -> Result<()> {
+    require!(
+        !market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasExpired
+    );
+
+    market.time_expiry = -1;
+
+    Ok(())
+}
+
+pub fn prune_orders(
+    market: &Market,
+    book: &mut Orderbook,
+    open_orders_account: &mut OpenOrdersAccount,
+    limit: u8,
+    clock: &Clock,
+) -> Result<()> {
+    require!(
+        market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasNotExpired
+    );
+
+    book.cancel_all_orders(open_orders_account, *market, limit, None, None)?;
+
+    Ok(())
+}
+
+/// Check that the market can be closed, the caller reclaims the accounts
+pub fn close_market(
+    market: &Market,
+    book: &Orderbook,
+    event_heap: &EventHeap,
+    clock: &Clock,
+) -> Result<()> {
+    require!(
+        market.is_expired(clock.unix_timestamp),
+        OpenBookError::MarketHasNotExpired
+    );
+    require!(market.is_empty(), OpenBookError::NonEmptyMarket);
+    require!(book.is_empty(), OpenBookError::BookContainsElements);
+    require!(
+        event_heap.is_empty(),
+        OpenBookError::EventHeapContainsElements
+    );
+
+    Ok(())
+}
+
+/// Check that the account can be closed and remove it from the indexer of its owner
+pub fn close_open_orders_account(
+    open_orders_account: &OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    indexer: &mut OpenOrdersIndexer,
+) -> Result<()> {
+    require!(
+        open_orders_account
+            .position
+            .is_empty(open_orders_account.version),
+        OpenBookError::NonEmptyOpenOrdersPosition
+    );
+
+    let index = indexer
+        .addresses
+        .iter()
+        .position(|x| x == open_orders_account_pk)
+        .ok_or_else(|| {
+            error_msg!(
+                "open orders account {} not in indexer",
+                open_orders_account_pk
+            )
+        })?;
+    indexer.addresses.remove(index);
+
+    Ok(())
+}
+
+pub fn close_open_orders_indexer(indexer: &OpenOrdersIndexer) -> Result<()> {
+    require!(
+        !indexer.has_active_open_orders_accounts(),
+        OpenBookError::IndexerActiveOO
+    );
+
+    Ok(())
+}
+
+pub fn set_delegate(
+    open_orders_account: &mut OpenOrdersAccount,
+    open_orders_account_pk: &Pubkey,
+    delegate: Option<Pubkey>,
+) {
+    let delegate_account: NonZeroPubkeyOption = delegate.into();
+
+    open_orders_account.delegate = delegate_account;
+
+    emit_stack(SetDelegateLog {
+        open_orders_account: *open_orders_account_pk,
+        delegate: delegate_account.into(),
+    });
+}
+
+pub fn stub_oracle_create(
+    oracle: &mut StubOracle,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    price: f64,
+    clock: &Clock,
+) {
+    oracle.owner = *owner;
+    oracle.mint = *mint;
+    stub_oracle_set(oracle, price, clock);
+}
+
+pub fn stub_oracle_set(oracle: &mut StubOracle, price: f64, clock: &Clock) {
+    oracle.price = price;
+    oracle.last_update_ts = clock.unix_timestamp;
+    oracle.last_update_slot = clock.slot;
+}
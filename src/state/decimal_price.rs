@@ -0,0 +1,525 @@
+use std::cmp::Ordering;
+
+/// Significant digits kept by the operations that can't be exact, like divisions
+const PRECISION_DIGITS: u32 = 38;
+
+/// Rounding direction of a conversion that can't be exact
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Rounding {
+    /// Towards zero
+    Down,
+    /// Away from zero
+    Up,
+    /// To the nearest integer, halfway cases away from zero
+    Nearest,
+}
+
+/// A non-negative price of `mantissa * 10^exponent`, computed without floating point
+///
+/// Oracles publish integer prices with a decimal exponent, which are stored as they are. Prices
+/// of concentrated liquidity pools and the results of divisions are rounded to the nearest
+/// value with 38 significant digits.
+#[derive(Copy, Clone, Debug)]
+pub struct DecimalPrice {
+    pub mantissa: u128,
+    pub exponent: i32,
+}
+
+impl DecimalPrice {
+    pub const ZERO: Self = Self {
+        mantissa: 0,
+        exponent: 0,
+    };
+    pub const ONE: Self = Self {
+        mantissa: 1,
+        exponent: 0,
+    };
+
+    pub fn new(mantissa: u128, exponent: i32) -> Self {
+        Self { mantissa, exponent }
+    }
+
+    /// None for negative mantissas
+    pub fn from_signed(mantissa: i128, exponent: i32) -> Option<Self> {
+        u128::try_from(mantissa)
+            .ok()
+            .map(|mantissa| Self::new(mantissa, exponent))
+    }
+
+    /// Rounds to the 17 significant digits a f64 can hold, None for negative or non-finite values
+    pub fn from_f64(value: f64) -> Option<Self> {
+        if !value.is_finite() || value < 0.0 {
+            return None;
+        }
+        if value == 0.0 {
+            return Some(Self::ZERO);
+        }
+        let exponent = value.log10().floor() as i32 - 16;
+        let mantissa = if exponent >= 0 {
+            (value / 10f64.powi(exponent)).round()
+        } else {
+            (value * 10f64.powi(-exponent)).round()
+        };
+        if !mantissa.is_finite() {
+            return None;
+        }
+        Some(Self::new(mantissa as u128, exponent))
+    }
+
+    /// Square of a Q64.64 fixed point number, like the sqrt price of a concentrated liquidity pool
+    pub fn from_sqrt_x64(sqrt_price_x64: u128) -> Self {
+        Self::from_x128(widening_mul(sqrt_price_x64, sqrt_price_x64))
+    }
+
+    /// Price of a 256 bit Q128.128 fixed point number
+    fn from_x128(value: U256) -> Self {
+        if value == (0, 0) {
+            return Self::ZERO;
+        }
+        let bits = 256 - leading_zeros(value);
+        // 10^decimals < 2^(256 - bits), so the product fits into 256 bits
+        let decimals = ((256 - bits) * 3 / 10).min(PRECISION_DIGITS);
+        let (hi, lo) = checked_mul_u256(value, pow10(decimals).unwrap()).unwrap();
+        // the high half is the integer part, round to nearest with the top bit of the low half
+        let mantissa = hi + (lo >> 127);
+        Self::new(mantissa, -(decimals as i32))
+    }
+
+    /// The price multiplied by `10^decimals`
+    pub fn mul_pow10(self, decimals: i32) -> Self {
+        Self::new(self.mantissa, self.exponent.saturating_add(decimals))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+
+    /// Same price, with exactly 38 digits in the mantissa unless it's zero
+    ///
+    /// Mantissas with 39 digits lose their last digit. None if the exponent overflows.
+    fn normalized(self) -> Option<Self> {
+        if self.mantissa == 0 {
+            return Some(Self::ZERO);
+        }
+        let digits = digits(self.mantissa);
+        if digits > PRECISION_DIGITS {
+            let mantissa = mul_div(self.mantissa, 1, 10, Rounding::Nearest).unwrap();
+            Some(Self::new(mantissa, self.exponent.checked_add(1)?))
+        } else {
+            let shift = PRECISION_DIGITS - digits;
+            Some(Self::new(
+                self.mantissa * pow10(shift).unwrap(),
+                self.exponent.checked_sub(shift as i32)?,
+            ))
+        }
+    }
+
+    /// `self / other`, None if `other` is zero
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        if other.mantissa == 0 {
+            return None;
+        }
+        if self.mantissa == 0 {
+            return Some(Self::ZERO);
+        }
+        let numerator = self.normalized()?;
+        // 10^shift <= other.mantissa, so the quotient keeps numerator's number of digits
+        let shift = digits(other.mantissa) - 1;
+        let mantissa = mul_div(
+            numerator.mantissa,
+            pow10(shift).unwrap(),
+            other.mantissa,
+            Rounding::Nearest,
+        )?;
+        let exponent = numerator
+            .exponent
+            .checked_sub(shift as i32)?
+            .checked_sub(other.exponent)?;
+        Some(Self::new(mantissa, exponent))
+    }
+
+    /// `1 / self`, None if `self` is zero
+    pub fn checked_inverse(self) -> Option<Self> {
+        Self::ONE.checked_div(self)
+    }
+
+    /// `(self + other) / 2`, None if the exponent overflows
+    pub fn checked_mean(self, other: Self) -> Option<Self> {
+        let (a, b) = (self.normalized()?, other.normalized()?);
+        if a.is_zero() || b.is_zero() {
+            let sum = if a.is_zero() { b } else { a };
+            let mantissa = mul_div(sum.mantissa, 1, 2, Rounding::Nearest).unwrap();
+            return Some(Self::new(mantissa, sum.exponent));
+        }
+        let (large, small) = if a.exponent >= b.exponent {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        let shift = large.exponent.abs_diff(small.exponent);
+        let small_mantissa = match pow10(shift) {
+            Some(scale) => mul_div(small.mantissa, 1, scale, Rounding::Nearest).unwrap(),
+            None => 0,
+        };
+        // both mantissas are below 10^38, so the sum fits
+        let sum = large.mantissa + small_mantissa;
+        let mantissa = mul_div(sum, 1, 2, Rounding::Nearest).unwrap();
+        Some(Self::new(mantissa, large.exponent))
+    }
+
+    /// The price multiplied by `numerator / denominator`, as an integer rounded in the given
+    /// direction
+    ///
+    /// The result is exact before the rounding, for any exponent. None on overflow or if the
+    /// denominator is zero.
+    pub fn checked_mul_div(
+        self,
+        numerator: u128,
+        denominator: u128,
+        rounding: Rounding,
+    ) -> Option<u128> {
+        if denominator == 0 {
+            return None;
+        }
+        if rounding == Rounding::Nearest {
+            // round(x) == floor(2x + 1) / 2
+            let twice =
+                self.checked_mul_div(numerator.checked_mul(2)?, denominator, Rounding::Down)?;
+            return Some(twice / 2 + twice % 2);
+        }
+
+        let mut value = if self.exponent >= 0 {
+            let mantissa = self.mantissa.checked_mul(pow10(self.exponent as u32)?)?;
+            widening_mul(mantissa, numerator)
+        } else {
+            widening_mul(self.mantissa, numerator)
+        };
+        value = div_round(value, denominator, rounding);
+
+        // dividing in steps rounds the same as dividing at once
+        let mut remaining = if self.exponent < 0 {
+            self.exponent.unsigned_abs()
+        } else {
+            0
+        };
+        while remaining > 0 {
+            if value == (0, 0) || (rounding == Rounding::Up && value == (0, 1)) {
+                break;
+            }
+            let step = remaining.min(PRECISION_DIGITS);
+            value = div_round(value, pow10(step).unwrap(), rounding);
+            remaining -= step;
+        }
+
+        match value {
+            (0, lo) => Some(lo),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for DecimalPrice {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DecimalPrice {}
+
+impl PartialOrd for DecimalPrice {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DecimalPrice {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.is_zero(), other.is_zero()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => {
+                let (a_digits, b_digits) = (digits(self.mantissa), digits(other.mantissa));
+                // orders of magnitude, in i64 so that exponents at the edges of i32 don't overflow
+                let a_magnitude = self.exponent as i64 + a_digits as i64;
+                let b_magnitude = other.exponent as i64 + b_digits as i64;
+                a_magnitude.cmp(&b_magnitude).then_with(|| {
+                    // same magnitude, compare the mantissas scaled to the same number of digits
+                    let a = widening_mul(
+                        self.mantissa,
+                        pow10(b_digits.saturating_sub(a_digits)).unwrap(),
+                    );
+                    let b = widening_mul(
+                        other.mantissa,
+                        pow10(a_digits.saturating_sub(b_digits)).unwrap(),
+                    );
+                    a.cmp(&b)
+                })
+            }
+        }
+    }
+}
+
+/// A 256 bit unsigned integer as (high, low) halves
+type U256 = (u128, u128);
+
+fn pow10(exponent: u32) -> Option<u128> {
+    10u128.checked_pow(exponent)
+}
+
+/// Number of decimal digits of a non-zero value
+fn digits(value: u128) -> u32 {
+    value.ilog10() + 1
+}
+
+fn leading_zeros(value: U256) -> u32 {
+    match value {
+        (0, lo) => 128 + lo.leading_zeros(),
+        (hi, _) => hi.leading_zeros(),
+    }
+}
+
+fn widening_mul(a: u128, b: u128) -> U256 {
+    const MASK: u128 = u64::MAX as u128;
+    let (a1, a0) = (a >> 64, a & MASK);
+    let (b1, b0) = (b >> 64, b & MASK);
+    let p00 = a0 * b0;
+    let p01 = a0 * b1;
+    let p10 = a1 * b0;
+    let p11 = a1 * b1;
+    // each term is below 2^64, the sum can't overflow
+    let mid = (p00 >> 64) + (p01 & MASK) + (p10 & MASK);
+    let lo = (p00 & MASK) | (mid << 64);
+    let hi = p11 + (p01 >> 64) + (p10 >> 64) + (mid >> 64);
+    (hi, lo)
+}
+
+fn checked_mul_u256((hi, lo): U256, factor: u128) -> Option<U256> {
+    let (lo_carry, lo) = widening_mul(lo, factor);
+    let (hi_overflow, hi) = widening_mul(hi, factor);
+    if hi_overflow != 0 {
+        return None;
+    }
+    Some((hi.checked_add(lo_carry)?, lo))
+}
+
+/// Quotient and remainder of a 256 bit value divided by a non-zero divisor
+fn div_rem((hi, lo): U256, divisor: u128) -> (U256, u128) {
+    let quotient_hi = hi / divisor;
+    let mut remainder = hi % divisor;
+    let mut quotient_lo = 0u128;
+    for i in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((lo >> i) & 1);
+        quotient_lo <<= 1;
+        // with the carry, the shifted remainder is above 2^128 and thus above the divisor
+        if carry == 1 || remainder >= divisor {
+            remainder = remainder.wrapping_sub(divisor);
+            quotient_lo |= 1;
+        }
+    }
+    ((quotient_hi, quotient_lo), remainder)
+}
+
+fn div_round(value: U256, divisor: u128, rounding: Rounding) -> U256 {
+    let ((hi, lo), remainder) = div_rem(value, divisor);
+    let round_up = match rounding {
+        Rounding::Down => false,
+        Rounding::Up => remainder != 0,
+        Rounding::Nearest => remainder >= divisor - remainder,
+    };
+    if !round_up {
+        (hi, lo)
+    } else {
+        // the quotient of a division by at least two can't be the max value
+        match lo.checked_add(1) {
+            Some(lo) => (hi, lo),
+            None => (hi + 1, 0),
+        }
+    }
+}
+
+/// `a * b / divisor` with a 256 bit intermediate, None on overflow or division by zero
+fn mul_div(a: u128, b: u128, divisor: u128, rounding: Rounding) -> Option<u128> {
+    if divisor == 0 {
+        return None;
+    }
+    match div_round(widening_mul(a, b), divisor, rounding) {
+        (0, lo) => Some(lo),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_widening_mul_and_div() {
+        assert_eq!(widening_mul(u128::MAX, u128::MAX), (u128::MAX - 1, 1));
+        assert_eq!(widening_mul(1 << 64, 1 << 64), (1, 0));
+
+        let value = widening_mul(u128::MAX, 12345);
+        assert_eq!(div_rem(value, 12345), ((0, u128::MAX), 0));
+        assert_eq!(div_rem(value, u128::MAX), ((0, 12345), 0));
+
+        assert_eq!(mul_div(7, 1, 2, Rounding::Down), Some(3));
+        assert_eq!(mul_div(7, 1, 2, Rounding::Up), Some(4));
+        assert_eq!(mul_div(7, 1, 2, Rounding::Nearest), Some(4));
+        assert_eq!(mul_div(5, 1, 3, Rounding::Nearest), Some(2));
+        assert_eq!(mul_div(4, 1, 3, Rounding::Nearest), Some(1));
+        assert_eq!(
+            mul_div(u128::MAX, u128::MAX, u128::MAX, Rounding::Down),
+            Some(u128::MAX)
+        );
+        assert_eq!(mul_div(u128::MAX, 2, 1, Rounding::Down), None);
+        assert_eq!(mul_div(1, 1, 0, Rounding::Down), None);
+    }
+
+    #[test]
+    fn test_checked_mul_div_rounding() {
+        // 12.345
+        let price = DecimalPrice::new(12345, -3);
+        assert_eq!(price.checked_mul_div(1, 1, Rounding::Down), Some(12));
+        assert_eq!(price.checked_mul_div(1, 1, Rounding::Up), Some(13));
+        assert_eq!(price.checked_mul_div(1, 1, Rounding::Nearest), Some(12));
+        assert_eq!(price.checked_mul_div(100, 1, Rounding::Down), Some(1234));
+        assert_eq!(price.checked_mul_div(100, 1, Rounding::Up), Some(1235));
+        assert_eq!(price.checked_mul_div(100, 1, Rounding::Nearest), Some(1235));
+        assert_eq!(price.checked_mul_div(1000, 1, Rounding::Up), Some(12345));
+        assert_eq!(price.checked_mul_div(1, 0, Rounding::Down), None);
+
+        let exact = DecimalPrice::new(12, 3);
+        assert_eq!(exact.checked_mul_div(10, 3, Rounding::Down), Some(40000));
+        assert_eq!(exact.checked_mul_div(10, 3, Rounding::Up), Some(40000));
+        assert_eq!(exact.checked_mul_div(1, 7, Rounding::Down), Some(1714));
+        assert_eq!(exact.checked_mul_div(1, 7, Rounding::Up), Some(1715));
+    }
+
+    #[test]
+    fn test_checked_mul_div_any_exponent() {
+        // exponents beyond what Pyth and Switchboard emit today
+        let tiny = DecimalPrice::new(5, -60);
+        assert_eq!(tiny.checked_mul_div(1, 1, Rounding::Down), Some(0));
+        assert_eq!(tiny.checked_mul_div(1, 1, Rounding::Up), Some(1));
+        assert_eq!(tiny.checked_mul_div(1, 1, Rounding::Nearest), Some(0));
+        assert_eq!(
+            DecimalPrice::new(1, i32::MIN).checked_mul_div(1, 1, Rounding::Up),
+            Some(1)
+        );
+
+        let small = DecimalPrice::new(123_456_789, -20);
+        assert_eq!(
+            small.checked_mul_div(10u128.pow(20), 1, Rounding::Down),
+            Some(123_456_789)
+        );
+        assert_eq!(
+            small.checked_mul_div(10u128.pow(14), 1, Rounding::Down),
+            Some(123)
+        );
+        assert_eq!(
+            small.checked_mul_div(10u128.pow(14), 1, Rounding::Up),
+            Some(124)
+        );
+
+        let large = DecimalPrice::new(7, 20);
+        assert_eq!(
+            large.checked_mul_div(1, 10u128.pow(15), Rounding::Down),
+            Some(700_000)
+        );
+        assert_eq!(
+            large.checked_mul_div(1, 1, Rounding::Down),
+            Some(7 * 10u128.pow(20))
+        );
+        assert_eq!(
+            DecimalPrice::new(1, 40).checked_mul_div(1, 1, Rounding::Down),
+            None
+        );
+    }
+
+    #[test]
+    fn test_division_and_ordering() {
+        let a = DecimalPrice::new(3, 0);
+        let b = DecimalPrice::new(2, 0);
+        let quotient = a.checked_div(b).unwrap();
+        assert_eq!(quotient, DecimalPrice::new(15, -1));
+        assert_eq!(quotient.checked_mul_div(10, 1, Rounding::Down), Some(15));
+
+        let third = DecimalPrice::ONE.checked_div(a).unwrap();
+        assert_eq!(
+            third.checked_mul_div(10u128.pow(37), 1, Rounding::Down),
+            Some(3_333_333_333_333_333_333_333_333_333_333_333_333)
+        );
+        assert_eq!(third.checked_mul_div(3, 1, Rounding::Up), Some(1));
+        assert!(a.checked_div(DecimalPrice::ZERO).is_none());
+        assert!(DecimalPrice::ZERO.checked_inverse().is_none());
+
+        assert_eq!(DecimalPrice::new(1, 2), DecimalPrice::new(100, 0));
+        assert!(DecimalPrice::new(1, 2) > DecimalPrice::new(99, 0));
+        assert!(DecimalPrice::new(1, -50) > DecimalPrice::ZERO);
+        assert!(DecimalPrice::new(1, -50) < DecimalPrice::new(1, -49));
+
+        assert_eq!(a.checked_mean(b).unwrap(), DecimalPrice::new(25, -1));
+        assert_eq!(
+            a.checked_mean(DecimalPrice::ZERO).unwrap(),
+            DecimalPrice::new(15, -1)
+        );
+    }
+
+    #[test]
+    fn test_conversions() {
+        // sqrt price of 1.5, price 2.25
+        assert_eq!(
+            DecimalPrice::from_sqrt_x64(3 << 63),
+            DecimalPrice::new(225, -2)
+        );
+        assert_eq!(DecimalPrice::from_sqrt_x64(0), DecimalPrice::ZERO);
+
+        assert_eq!(
+            DecimalPrice::from_f64(0.25).unwrap(),
+            DecimalPrice::new(25, -2)
+        );
+        assert_eq!(
+            DecimalPrice::from_f64(1e20).unwrap(),
+            DecimalPrice::new(1, 20)
+        );
+        assert_eq!(
+            DecimalPrice::from_f64(1e-20).unwrap(),
+            DecimalPrice::new(1, -20)
+        );
+        assert!(DecimalPrice::from_f64(-1.0).is_none());
+        assert!(DecimalPrice::from_f64(f64::NAN).is_none());
+
+        assert!(DecimalPrice::from_signed(-1, 0).is_none());
+        assert_eq!(
+            DecimalPrice::from_signed(42, -30).unwrap(),
+            DecimalPrice::new(42, -30)
+        );
+    }
+
+    #[test]
+    fn test_exponent_boundaries() {
+        let min = DecimalPrice::new(1, i32::MIN);
+        let max = DecimalPrice::new(u128::MAX, i32::MAX);
+        assert!(min.normalized().is_none());
+        assert!(max.normalized().is_none());
+        // nothing to shift, the exponents stay in range
+        let min_normalized = DecimalPrice::new(10u128.pow(37), i32::MIN);
+        assert_eq!(min_normalized.normalized().unwrap().exponent, i32::MIN);
+        let max_normalized = DecimalPrice::new(10u128.pow(37), i32::MAX);
+        assert_eq!(max_normalized.normalized().unwrap().exponent, i32::MAX);
+
+        assert!(min.checked_div(DecimalPrice::ONE).is_none());
+        assert!(max.checked_div(DecimalPrice::ONE).is_none());
+        assert!(DecimalPrice::ONE.checked_div(max).is_none());
+        assert!(min.checked_mean(DecimalPrice::ONE).is_none());
+        assert!(DecimalPrice::ONE.checked_mean(max).is_none());
+
+        assert!(min > DecimalPrice::ZERO);
+        assert!(min < DecimalPrice::new(1, i32::MIN + 1));
+        assert!(min < min_normalized);
+        assert!(max > DecimalPrice::new(1, i32::MAX));
+        assert!(max > max_normalized);
+        assert_eq!(max, max);
+    }
+}
@@ -1,19 +1,28 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use fixed::types::I80F48;
 use std::convert::{TryFrom, TryInto};
 
 use crate::error::OpenBookError;
 use crate::pubkey_option::NonZeroPubkeyOption;
 use crate::state::oracle;
-use crate::{accounts_zerocopy::KeyedAccountReader, state::orderbook::Side};
+use crate::types::aligned_u128;
+use crate::{
+    accounts_zerocopy::{cast_account_data, AccountReader, KeyedAccountReader},
+    state::orderbook::Side,
+};
 
-use super::{orderbook, OracleConfig};
+use super::{
+    orderbook, BookSide, DecimalPrice, L2Level, L3Order, OracleCombination, OracleConfig,
+    OracleState, OracleType, Rounding,
+};
 
 // For a 1bps taker fee, set taker_fee to 100, so taker_fee/FEES_SCALE_FACTOR = 10e-4
 pub const FEES_SCALE_FACTOR: i128 = 1_000_000;
 // taker pays 500 lamports penalty for every transaction that adds to the event heap
 pub const PENALTY_EVENT_HEAP: u64 = 500;
 
+#[repr(C)]
 #[derive(Clone, Copy, Debug, anchor_lang::AnchorSerialize, anchor_lang::AnchorDeserialize)]
 pub struct Market {
     /// PDA bump
@@ -88,9 +97,9 @@ pub struct Market {
     pub taker_fee: i64,
 
     /// Total fees accrued in native quote
-    pub fees_accrued: u128,
+    pub fees_accrued: aligned_u128,
     /// Total fees settled in native quote
-    pub fees_to_referrers: u128,
+    pub fees_to_referrers: aligned_u128,
 
     /// Referrer rebates to be distributed
     pub referrer_rebates_accrued: u64,
@@ -99,10 +108,10 @@ pub struct Market {
     pub fees_available: u64,
 
     /// Cumulative maker volume (same as taker volume) in quote native units
-    pub maker_volume: u128,
+    pub maker_volume: aligned_u128,
 
     /// Cumulative taker volume in quote native units due to place take orders
-    pub taker_volume_wo_oo: u128,
+    pub taker_volume_wo_oo: aligned_u128,
 
     pub base_mint: Pubkey,
     pub quote_mint: Pubkey,
@@ -113,16 +122,40 @@ pub struct Market {
     pub market_quote_vault: Pubkey,
     pub quote_deposit_total: u64,
 
-    pub reserved: [u8; 128],
+    /// Third account of the oracle configuration
+    ///
+    /// With OracleCombination::Median it's the third oracle. With an OpenBook market in
+    /// oracle_a it's the asks of that market, whose bids are in oracle_b. create_market rejects
+    /// it in all other configurations. There is no room left for separate fields of the
+    /// OpenBook market oracle.
+    pub oracle_c: NonZeroPubkeyOption,
+
+    /// Feed ids of oracle_a, oracle_b and oracle_c if they are Pyth pull oracles, zero otherwise
+    pub oracle_feed_ids: [[u8; 32]; 3],
+}
+
+// same layout as on chain, and no padding
+const _: () = assert!(std::mem::size_of::<Market>() == 840);
+const _: () = assert!(std::mem::align_of::<Market>() == 8);
+
+impl anchor_lang::Discriminator for Market {
+    const DISCRIMINATOR: [u8; 8] = [219, 190, 213, 55, 0, 227, 198, 154];
+}
+
+unsafe impl bytemuck::Pod for Market {}
+unsafe impl bytemuck::Zeroable for Market {}
+
+impl anchor_lang::ZeroCopy for Market {}
+impl anchor_lang::Owner for Market {
+    fn owner() -> Pubkey {
+        crate::ID
+    }
 }
 
 impl Market {
     /// Length of the state
     pub const LEN: usize = std::mem::size_of::<Self>();
 
-    /// Discriminator used to differentiate between Market and other account types
-    pub const DISCRIMINATOR: [u8; 8] = [219, 190, 213, 55, 0, 227, 198, 154];
-
     pub fn name(&self) -> &str {
         std::str::from_utf8(&self.name)
             .unwrap()
@@ -184,6 +217,59 @@ impl Market {
             / I80F48::from_num(self.base_lot_size)
     }
 
+    /// Convert from the price stored on the book to a price in whole quote tokens per
+    /// whole base token
+    pub fn lot_to_ui_price(&self, price: i64) -> f64 {
+        let decimals = i32::from(self.base_decimals) - i32::from(self.quote_decimals);
+        self.lot_to_native_price(price).to_num::<f64>() * 10f64.powi(decimals)
+    }
+
+    /// Convert a quantity of base lots to whole base tokens
+    pub fn base_lots_to_ui(&self, base_lots: i64) -> f64 {
+        (base_lots as f64 * self.base_lot_size as f64) / 10f64.powi(self.base_decimals.into())
+    }
+
+    /// Aggregated price levels of `book_side`, see BookSide::l2_levels()
+    pub fn l2_levels(
+        &self,
+        book_side: &BookSide,
+        depth: usize,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+    ) -> Vec<MarketL2Level> {
+        book_side
+            .l2_levels(depth, now_ts, oracle_price_lots)
+            .into_iter()
+            .map(|level| MarketL2Level {
+                native_price: self.lot_to_native_price(level.price_lots),
+                native_quantity: level.quantity_lots * self.base_lot_size,
+                ui_price: self.lot_to_ui_price(level.price_lots),
+                ui_quantity: self.base_lots_to_ui(level.quantity_lots),
+                level,
+            })
+            .collect()
+    }
+
+    /// Orders of `book_side`, see BookSide::l3_orders()
+    pub fn l3_orders(
+        &self,
+        book_side: &BookSide,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+    ) -> Vec<MarketL3Order> {
+        book_side
+            .l3_orders(now_ts, oracle_price_lots)
+            .into_iter()
+            .map(|order| MarketL3Order {
+                native_price: self.lot_to_native_price(order.price_lots),
+                native_quantity: order.quantity_lots * self.base_lot_size,
+                ui_price: self.lot_to_ui_price(order.price_lots),
+                ui_quantity: self.base_lots_to_ui(order.quantity_lots),
+                order,
+            })
+            .collect()
+    }
+
     pub fn native_price_to_lot(&self, price: I80F48) -> Result<i64> {
         price
             .checked_mul(I80F48::from_num(self.base_lot_size))
@@ -196,46 +282,280 @@ impl Market {
         &self,
         oracle_a_acc: Option<&impl KeyedAccountReader>,
         oracle_b_acc: Option<&impl KeyedAccountReader>,
+        oracle_c_acc: Option<&impl KeyedAccountReader>,
         slot: u64,
+        now_ts: i64,
     ) -> Result<Option<i64>> {
-        let oracle_price = self.oracle_price(oracle_a_acc, oracle_b_acc, slot)?;
+        let oracle_price =
+            self.oracle_price(oracle_a_acc, oracle_b_acc, oracle_c_acc, slot, now_ts)?;
         match oracle_price {
             Some(p) => Ok(Some(self.native_price_to_lot(p)?)),
             None => Ok(None),
         }
     }
 
+    /// Like native_price_to_lot(), without floating point and with explicit rounding
+    pub fn native_price_to_lot_exact(
+        &self,
+        price: DecimalPrice,
+        rounding: Rounding,
+    ) -> Result<i64> {
+        price
+            .checked_mul_div(
+                self.base_lot_size as u128,
+                self.quote_lot_size as u128,
+                rounding,
+            )
+            .and_then(|lots| i64::try_from(lots).ok())
+            .ok_or_else(|| OpenBookError::InvalidOraclePrice.into())
+    }
+
+    /// Like oracle_price_lots(), but the integer prices of the oracles are converted into lots
+    /// without floating point, rounding only at the end
+    ///
+    /// Stub and Switchboard V1 oracles publish floats, which are taken with 17 significant
+    /// digits. Divisions, as for oracle_a / oracle_b and for inverted pools, keep 38.
+    pub fn oracle_price_lots_exact(
+        &self,
+        oracle_a_acc: Option<&impl KeyedAccountReader>,
+        oracle_b_acc: Option<&impl KeyedAccountReader>,
+        oracle_c_acc: Option<&impl KeyedAccountReader>,
+        slot: u64,
+        now_ts: i64,
+        rounding: Rounding,
+    ) -> Result<Option<i64>> {
+        let oracle_price = if self.oracle_config.combination()? == OracleCombination::Median {
+            self.median_oracle_decimal_price(
+                oracle_a_acc,
+                oracle_b_acc,
+                oracle_c_acc,
+                slot,
+                now_ts,
+            )?
+        } else {
+            self.ratio_oracle_price(oracle_a_acc, oracle_b_acc, oracle_c_acc, slot, now_ts)?
+                .map(|price| price.decimal_price)
+        };
+        match oracle_price {
+            Some(p) => {
+                let decimals = (self.quote_decimals as i32) - (self.base_decimals as i32);
+                Ok(Some(self.native_price_to_lot_exact(
+                    p.mul_pow10(decimals),
+                    rounding,
+                )?))
+            }
+            None => Ok(None),
+        }
+    }
+
     pub fn oracle_price(
         &self,
         oracle_a_acc: Option<&impl KeyedAccountReader>,
         oracle_b_acc: Option<&impl KeyedAccountReader>,
+        oracle_c_acc: Option<&impl KeyedAccountReader>,
         slot: u64,
+        now_ts: i64,
     ) -> Result<Option<I80F48>> {
+        if self.oracle_config.combination()? == OracleCombination::Median {
+            let median =
+                self.median_oracle_price(oracle_a_acc, oracle_b_acc, oracle_c_acc, slot, now_ts);
+            Ok(median.map(|median| median.price))
+        } else {
+            let price =
+                self.ratio_oracle_price(oracle_a_acc, oracle_b_acc, oracle_c_acc, slot, now_ts)?;
+            Ok(price.and_then(|price| self.native_oracle_price(price.price)))
+        }
+    }
+
+    /// Median of the usable prices of oracle_a, oracle_b and oracle_c
+    ///
+    /// Oracles that are stale, not confident enough or fail to load are left out, so a single
+    /// provider outage doesn't take away the oracle price. With two usable prices, their mean
+    /// is returned.
+    pub fn median_oracle_price(
+        &self,
+        oracle_a_acc: Option<&impl KeyedAccountReader>,
+        oracle_b_acc: Option<&impl KeyedAccountReader>,
+        oracle_c_acc: Option<&impl KeyedAccountReader>,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> Option<MedianOraclePrice> {
+        let sources =
+            self.median_sources(oracle_a_acc, oracle_b_acc, oracle_c_acc, now_slot, now_ts);
+        let sources =
+            sources.map(|source| source.and_then(|price| self.native_oracle_price(price.price)));
+        let used = sources.map(|price| price.is_some());
+        let mut prices: Vec<I80F48> = sources.into_iter().flatten().collect();
+
+        prices.sort();
+        let price = match prices.len() {
+            0 => return None,
+            2 => (prices[0] + prices[1]) / 2,
+            n => prices[n / 2],
+        };
+        Some(MedianOraclePrice { price, used })
+    }
+
+    /// Like median_oracle_price(), without floating point, in quote per base tokens
+    fn median_oracle_decimal_price(
+        &self,
+        oracle_a_acc: Option<&impl KeyedAccountReader>,
+        oracle_b_acc: Option<&impl KeyedAccountReader>,
+        oracle_c_acc: Option<&impl KeyedAccountReader>,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> Result<Option<DecimalPrice>> {
+        let sources =
+            self.median_sources(oracle_a_acc, oracle_b_acc, oracle_c_acc, now_slot, now_ts);
+        let mut prices: Vec<DecimalPrice> = sources
+            .into_iter()
+            .flatten()
+            .map(|price| price.decimal_price)
+            .collect();
+
+        prices.sort();
+        match prices.len() {
+            0 => Ok(None),
+            2 => Ok(Some(
+                prices[0]
+                    .checked_mean(prices[1])
+                    .ok_or(OpenBookError::InvalidOraclePrice)?,
+            )),
+            n => Ok(Some(prices[n / 2])),
+        }
+    }
+
+    fn median_sources(
+        &self,
+        oracle_a_acc: Option<&impl KeyedAccountReader>,
+        oracle_b_acc: Option<&impl KeyedAccountReader>,
+        oracle_c_acc: Option<&impl KeyedAccountReader>,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> [Option<UsableOraclePrice>; 3] {
+        [
+            self.median_source(self.oracle_a, oracle_a_acc, now_slot, now_ts),
+            self.median_source(self.oracle_b, oracle_b_acc, now_slot, now_ts),
+            self.median_source(self.oracle_c, oracle_c_acc, now_slot, now_ts),
+        ]
+    }
+
+    /// Price of one oracle of the median, None if it's not configured or not usable
+    fn median_source(
+        &self,
+        oracle: NonZeroPubkeyOption,
+        oracle_acc: Option<&impl KeyedAccountReader>,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> Option<UsableOraclePrice> {
+        if oracle.is_none() {
+            return None;
+        }
+        let oracle_acc = oracle_acc.unwrap();
+        assert_eq!(oracle, *oracle_acc.key());
+        match self.oracle_price_from_single(oracle_acc, now_slot, now_ts) {
+            Ok(price) => price,
+            Err(error) => {
+                msg!("Oracle {} not usable: {}", oracle_acc.key(), error);
+                None
+            }
+        }
+    }
+
+    /// Price of oracle_a, or of oracle_a / oracle_b if both are set
+    ///
+    /// An OpenBook market in oracle_a is priced by its book, in oracle_b and oracle_c.
+    fn ratio_oracle_price(
+        &self,
+        oracle_a_acc: Option<&impl KeyedAccountReader>,
+        oracle_b_acc: Option<&impl KeyedAccountReader>,
+        oracle_c_acc: Option<&impl KeyedAccountReader>,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> Result<Option<UsableOraclePrice>> {
         if self.oracle_a.is_some() && self.oracle_b.is_some() {
-            self.oracle_price_from_a_and_b(oracle_a_acc.unwrap(), oracle_b_acc.unwrap(), slot)
+            let oracle_a_acc = oracle_a_acc.unwrap();
+            let oracle_b_acc = oracle_b_acc.unwrap();
+            // oracle_c means the asks only for an OpenBook market, see Market::oracle_c
+            let is_openbook_market = oracle::is_openbook_market(oracle_a_acc);
+            require!(
+                is_openbook_market == self.oracle_c.is_some(),
+                OpenBookError::InvalidOracleCombination
+            );
+            if is_openbook_market {
+                let oracle_c_acc = oracle_c_acc.unwrap();
+                self.oracle_price_from_openbook_market(
+                    oracle_a_acc,
+                    oracle_b_acc,
+                    oracle_c_acc,
+                    now_slot,
+                    now_ts,
+                )
+            } else if oracle::is_raydium_observation(oracle_b_acc) {
+                self.oracle_price_from_raydium(oracle_a_acc, oracle_b_acc, now_slot, now_ts)
+            } else {
+                self.oracle_price_from_a_and_b(oracle_a_acc, oracle_b_acc, now_slot, now_ts)
+            }
         } else if self.oracle_a.is_some() {
-            self.oracle_price_from_a(oracle_a_acc.unwrap(), slot)
+            let oracle_a_acc = oracle_a_acc.unwrap();
+            assert_eq!(self.oracle_a, *oracle_a_acc.key());
+            self.oracle_price_from_single(oracle_a_acc, now_slot, now_ts)
         } else {
             Ok(None)
         }
     }
 
-    fn oracle_price_from_a(
+    /// Native quote per native base, for a price in quote per base tokens
+    fn native_oracle_price(&self, price: f64) -> Option<I80F48> {
+        let decimals = self.quote_decimals as i32 - self.base_decimals as i32;
+        let decimal_adj = oracle::power_of_ten_float(decimals);
+        I80F48::checked_from_num(price * decimal_adj)
+    }
+
+    /// Like oracle::oracle_state_for_mode_unchecked(), checking the feed of Pyth pull oracles
+    fn oracle_state_for_mode(&self, oracle_acc: &impl KeyedAccountReader) -> Result<OracleState> {
+        let oracle = oracle::oracle_state_for_mode_unchecked(oracle_acc, &self.oracle_config)?;
+        if oracle.oracle_type == OracleType::PythPull {
+            let expected = [self.oracle_a, self.oracle_b, self.oracle_c]
+                .iter()
+                .position(|oracle| *oracle == *oracle_acc.key())
+                .map(|i| self.oracle_feed_ids[i]);
+            require!(
+                expected.is_some() && oracle::pyth_pull_feed_id(oracle_acc)? == expected,
+                OpenBookError::OracleFeedMismatch
+            );
+        }
+        Ok(oracle)
+    }
+
+    /// Price of an oracle that prices base in quote on its own
+    fn oracle_price_from_single(
         &self,
         oracle_acc: &impl KeyedAccountReader,
         now_slot: u64,
-    ) -> Result<Option<I80F48>> {
-        assert_eq!(self.oracle_a, *oracle_acc.key());
-        let oracle = oracle::oracle_state_unchecked(oracle_acc)?;
+        now_ts: i64,
+    ) -> Result<Option<UsableOraclePrice>> {
+        let oracle = match oracle::determine_oracle_type(oracle_acc)? {
+            OracleType::OrcaWhirlpool => match self.oriented_orca_whirlpool(oracle_acc)? {
+                Some(oracle) => oracle,
+                None => return Ok(None),
+            },
+            _ => self.oracle_state_for_mode(oracle_acc)?,
+        };
+        let oracle = match oracle.oracle_type {
+            OracleType::RaydiumCLMM => match self.oriented_raydium_clmm(oracle_acc, oracle)? {
+                Some(oracle) => oracle,
+                None => return Ok(None),
+            },
+            _ => oracle,
+        };
 
-        if oracle.is_stale(oracle_acc.key(), &self.oracle_config, now_slot)
+        if oracle.is_stale(oracle_acc.key(), &self.oracle_config, now_slot, now_ts)
             || !oracle.has_valid_confidence(oracle_acc.key(), &self.oracle_config)
         {
             Ok(None)
         } else {
-            let decimals = (self.quote_decimals as i8) - (self.base_decimals as i8);
-            let decimal_adj = oracle::power_of_ten_float(decimals);
-            Ok(I80F48::checked_from_num(oracle.price * decimal_adj))
+            Ok(Some(UsableOraclePrice::from(&oracle)))
         }
     }
 
@@ -244,23 +564,141 @@ impl Market {
         oracle_a_acc: &impl KeyedAccountReader,
         oracle_b_acc: &impl KeyedAccountReader,
         now_slot: u64,
-    ) -> Result<Option<I80F48>> {
+        now_ts: i64,
+    ) -> Result<Option<UsableOraclePrice>> {
         assert_eq!(self.oracle_a, *oracle_a_acc.key());
         assert_eq!(self.oracle_b, *oracle_b_acc.key());
 
-        let oracle_a = oracle::oracle_state_unchecked(oracle_a_acc)?;
-        let oracle_b = oracle::oracle_state_unchecked(oracle_b_acc)?;
+        let oracle_a = self.oracle_state_for_mode(oracle_a_acc)?;
+        let oracle_b = self.oracle_state_for_mode(oracle_b_acc)?;
 
-        if oracle_a.is_stale(oracle_a_acc.key(), &self.oracle_config, now_slot)
-            || oracle_b.is_stale(oracle_b_acc.key(), &self.oracle_config, now_slot)
+        if oracle_a.oracle_type == OracleType::RaydiumCLMM
+            && !(oracle::raydium_clmm_pool_is_valid(oracle_a_acc, &self.oracle_config)
+                && oracle::raydium_clmm_pool_is_valid(oracle_b_acc, &self.oracle_config))
+        {
+            return Ok(None);
+        }
+
+        if oracle_a.is_stale(oracle_a_acc.key(), &self.oracle_config, now_slot, now_ts)
+            || oracle_b.is_stale(oracle_b_acc.key(), &self.oracle_config, now_slot, now_ts)
             || !oracle_a.has_valid_combined_confidence(&oracle_b, &self.oracle_config)
         {
             Ok(None)
         } else {
-            let price = oracle_a.price / oracle_b.price;
-            let decimals = (self.quote_decimals as i8) - (self.base_decimals as i8);
-            let decimal_adj = oracle::power_of_ten_float(decimals);
-            Ok(I80F48::checked_from_num(price * decimal_adj))
+            let decimal_price = oracle_a.decimal_price.checked_div(oracle_b.decimal_price);
+            Ok(decimal_price.map(|decimal_price| UsableOraclePrice {
+                price: oracle_a.price / oracle_b.price,
+                decimal_price,
+            }))
+        }
+    }
+
+    /// Price of the Raydium CLMM pool in oracle_a, with its ObservationState in oracle_b
+    fn oracle_price_from_raydium(
+        &self,
+        pool_acc: &impl KeyedAccountReader,
+        observation_acc: &impl KeyedAccountReader,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> Result<Option<UsableOraclePrice>> {
+        assert_eq!(self.oracle_a, *pool_acc.key());
+        assert_eq!(self.oracle_b, *observation_acc.key());
+        let oracle =
+            oracle::raydium_clmm_state_unchecked(pool_acc, observation_acc, &self.oracle_config)?;
+        let oracle = match self.oriented_raydium_clmm(pool_acc, oracle)? {
+            Some(oracle) => oracle,
+            None => return Ok(None),
+        };
+
+        if oracle.is_stale(pool_acc.key(), &self.oracle_config, now_slot, now_ts)
+            || !oracle.has_valid_confidence(pool_acc.key(), &self.oracle_config)
+        {
+            Ok(None)
+        } else {
+            Ok(Some(UsableOraclePrice::from(&oracle)))
+        }
+    }
+
+    /// Price of the book of the OpenBook market in oracle_a, with its bids in oracle_b and its
+    /// asks in oracle_c
+    fn oracle_price_from_openbook_market(
+        &self,
+        market_acc: &impl KeyedAccountReader,
+        bids_acc: &impl KeyedAccountReader,
+        asks_acc: &impl KeyedAccountReader,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> Result<Option<UsableOraclePrice>> {
+        assert_eq!(self.oracle_a, *market_acc.key());
+        assert_eq!(self.oracle_b, *bids_acc.key());
+        assert_eq!(self.oracle_c, *asks_acc.key());
+        let oracle = match oracle::openbook_market_state_unchecked(
+            market_acc,
+            bids_acc,
+            asks_acc,
+            &self.oracle_config,
+            now_ts,
+        )? {
+            Some(oracle) => oracle,
+            None => return Ok(None),
+        };
+        let oracle = if oracle::openbook_market_is_inverted(
+            market_acc,
+            &self.base_mint,
+            &self.quote_mint,
+        )? {
+            oracle.inverted()?
+        } else {
+            oracle
+        };
+
+        if oracle.is_stale(market_acc.key(), &self.oracle_config, now_slot, now_ts)
+            || !oracle.has_valid_confidence(market_acc.key(), &self.oracle_config)
+        {
+            Ok(None)
+        } else {
+            Ok(Some(UsableOraclePrice::from(&oracle)))
+        }
+    }
+
+    /// The pool price as quote per base, None if the pool isn't usable as oracle
+    fn oriented_raydium_clmm(
+        &self,
+        pool_acc: &impl KeyedAccountReader,
+        oracle: OracleState,
+    ) -> Result<Option<OracleState>> {
+        if !oracle::raydium_clmm_pool_is_valid(pool_acc, &self.oracle_config) {
+            Ok(None)
+        } else if oracle::raydium_clmm_is_inverted(pool_acc, &self.base_mint, &self.quote_mint) {
+            Ok(Some(oracle.inverted()?))
+        } else {
+            Ok(Some(oracle))
+        }
+    }
+
+    /// The whirlpool price as quote per base, None if the pool isn't usable as oracle
+    ///
+    /// The pool trades the market's mints, so their decimals are the market's.
+    fn oriented_orca_whirlpool(
+        &self,
+        pool_acc: &impl KeyedAccountReader,
+    ) -> Result<Option<OracleState>> {
+        if !oracle::orca_whirlpool_is_valid(pool_acc, &self.oracle_config) {
+            Ok(None)
+        } else if oracle::orca_whirlpool_is_inverted(pool_acc, &self.base_mint, &self.quote_mint) {
+            let oracle = oracle::orca_whirlpool_state_unchecked(
+                pool_acc,
+                self.quote_decimals,
+                self.base_decimals,
+            )?;
+            Ok(Some(oracle.inverted()?))
+        } else {
+            let oracle = oracle::orca_whirlpool_state_unchecked(
+                pool_acc,
+                self.base_decimals,
+                self.quote_decimals,
+            )?;
+            Ok(Some(oracle))
         }
     }
 
@@ -320,3 +758,468 @@ impl Market {
             .unwrap()
     }
 }
+
+/// A price level with its price and quantity in native and ui units of the market
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarketL2Level {
+    pub level: L2Level,
+    /// Native quote per native base
+    pub native_price: I80F48,
+    /// Native base
+    pub native_quantity: i64,
+    /// Quote tokens per base token
+    pub ui_price: f64,
+    /// Base tokens
+    pub ui_quantity: f64,
+}
+
+/// An order with its price and quantity in native and ui units of the market
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarketL3Order {
+    pub order: L3Order,
+    /// Native quote per native base
+    pub native_price: I80F48,
+    /// Native base
+    pub native_quantity: i64,
+    /// Quote tokens per base token
+    pub ui_price: f64,
+    /// Base tokens
+    pub ui_quantity: f64,
+}
+
+/// Result of Market::median_oracle_price()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MedianOraclePrice {
+    /// Native quote per native base
+    pub price: I80F48,
+    /// Whether the prices of oracle_a, oracle_b and oracle_c were used
+    pub used: [bool; 3],
+}
+
+/// A Market borrowed from the data of its account, see BookSideRef
+#[derive(Clone, Copy, Debug)]
+pub struct MarketRef<'a> {
+    market: &'a Market,
+}
+
+impl<'a> MarketRef<'a> {
+    /// Checks the discriminator and length of the account data
+    pub fn from_bytes(data: &'a [u8]) -> Result<Self> {
+        let market = cast_account_data::<Market>(data, &Market::DISCRIMINATOR)?;
+        Ok(Self { market })
+    }
+
+    /// Like from_bytes(), and checks that the account is owned by the program
+    pub fn from_account(account: &'a impl AccountReader) -> Result<Self> {
+        if account.owner() != &crate::ID {
+            return Err(ErrorCode::AccountOwnedByWrongProgram.into());
+        }
+        Self::from_bytes(account.data())
+    }
+}
+
+impl std::ops::Deref for MarketRef<'_> {
+    type Target = Market;
+
+    fn deref(&self) -> &Market {
+        self.market
+    }
+}
+
+/// Validated oracle price in quote per base tokens, before the decimals adjustment
+#[derive(Clone, Copy, Debug)]
+struct UsableOraclePrice {
+    price: f64,
+    decimal_price: DecimalPrice,
+}
+
+impl From<&OracleState> for UsableOraclePrice {
+    fn from(oracle: &OracleState) -> Self {
+        Self {
+            price: oracle.price,
+            decimal_price: oracle.decimal_price,
+        }
+    }
+}
+
+/// Generate signed seeds for the market authority
+#[macro_export]
+macro_rules! market_seeds {
+    ($market:expr,$key:expr) => {
+        &[
+            $crate::pda::MARKET_SEED,
+            &$key.to_bytes(),
+            &[$market.bump],
+        ]
+    };
+}
+
+pub use market_seeds;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts_zerocopy::LoadZeroCopy;
+    use crate::error::IsAnchorErrorWithCode;
+    use crate::processor::{self, NewMarketAccounts};
+    use crate::state::oracle::tests::oracle_config_params;
+    use crate::state::*;
+    use bytemuck::Zeroable;
+
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        data: Vec<u8>,
+    }
+
+    impl AccountReader for TestAccount {
+        fn owner(&self) -> &Pubkey {
+            &self.owner
+        }
+
+        fn data(&self) -> &[u8] {
+            &self.data
+        }
+    }
+
+    impl KeyedAccountReader for TestAccount {
+        fn key(&self) -> &Pubkey {
+            &self.key
+        }
+    }
+
+    fn program_account(key: Pubkey, discriminator: [u8; 8], bytes: &[u8]) -> TestAccount {
+        let mut data = discriminator.to_vec();
+        data.extend_from_slice(bytes);
+        TestAccount {
+            key,
+            owner: crate::ID,
+            data,
+        }
+    }
+
+    fn stub_oracle(price: f64, last_update_slot: u64) -> TestAccount {
+        let mut stub = StubOracle::zeroed();
+        stub.price = price;
+        stub.last_update_slot = last_update_slot;
+        program_account(
+            Pubkey::new_unique(),
+            StubOracle::DISCRIMINATOR,
+            bytemuck::bytes_of(&stub),
+        )
+    }
+
+    fn new_market_accounts() -> NewMarketAccounts {
+        NewMarketAccounts {
+            market: Pubkey::new_unique(),
+            market_authority: Pubkey::new_unique(),
+            market_authority_bump: 0,
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            event_heap: Pubkey::new_unique(),
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            base_decimals: 9,
+            quote_decimals: 6,
+            market_base_vault: Pubkey::new_unique(),
+            market_quote_vault: Pubkey::new_unique(),
+            collect_fee_admin: Pubkey::new_unique(),
+            open_orders_admin: NonZeroPubkeyOption::default(),
+            consume_events_admin: NonZeroPubkeyOption::default(),
+            close_market_admin: NonZeroPubkeyOption::default(),
+        }
+    }
+
+    fn create_market(
+        accounts: &NewMarketAccounts,
+        oracles: [Option<&TestAccount>; 3],
+        oracle_config: &OracleConfigParams,
+        base_lot_size: i64,
+    ) -> Result<Market> {
+        processor::create_market(
+            accounts,
+            oracles,
+            "SOL-USDC",
+            oracle_config,
+            10,
+            base_lot_size,
+            -200,
+            400,
+            0,
+            &Clock::default(),
+        )
+    }
+
+    #[test]
+    fn test_load_zero_copy() {
+        let mut market = Market::zeroed();
+        market.bids = Pubkey::new_unique();
+        market.base_lot_size = 100;
+        market.fees_accrued = (u128::MAX - 1).into();
+
+        // the on-chain layout is the serialized one
+        assert_eq!(bytemuck::bytes_of(&market), market.try_to_vec().unwrap());
+        let mut account = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: crate::ID,
+            data: Market::DISCRIMINATOR.to_vec(),
+        };
+        account.data.extend(bytemuck::bytes_of(&market));
+
+        let loaded = account.load::<Market>().unwrap();
+        assert_eq!(loaded.bids, market.bids);
+        assert_eq!(loaded.base_lot_size, 100);
+        assert_eq!(u128::from(loaded.fees_accrued), u128::MAX - 1);
+        assert!(account.load::<BookSide>().is_err());
+
+        account.owner = Pubkey::new_unique();
+        assert!(account.load::<Market>().is_err());
+    }
+
+    #[test]
+    fn test_median_oracle_price() {
+        let now_ts = 1_700_000_000;
+        let oracle_a = stub_oracle(20.0, 1000);
+        let oracle_b = stub_oracle(21.0, 1000);
+        let oracle_c = stub_oracle(25.0, 900);
+        let oracles = [Some(&oracle_a), Some(&oracle_b), Some(&oracle_c)];
+        let accounts = new_market_accounts();
+        let mut config = OracleConfigParams {
+            max_staleness_slots: Some(50),
+            ..oracle_config_params()
+        };
+
+        // only the median uses a third oracle
+        assert!(create_market(&accounts, oracles, &config, 100)
+            .is_anchor_error_with_code(OpenBookError::InvalidOracleCombination.error_code()));
+
+        config.combination = OracleCombination::Median;
+        let market = create_market(&accounts, oracles, &config, 100).unwrap();
+        let median = |slot: u64| {
+            market
+                .median_oracle_price(
+                    Some(&oracle_a),
+                    Some(&oracle_b),
+                    Some(&oracle_c),
+                    slot,
+                    now_ts,
+                )
+                .unwrap()
+        };
+
+        // oracle_c is stale: the mean of the others, 20.5 USDC/SOL
+        let price = median(1000);
+        assert_eq!(price.used, [true, true, false]);
+        assert!((price.price.to_num::<f64>() - 0.0205).abs() < 1e-9);
+
+        let price = median(950);
+        assert_eq!(price.used, [true, true, true]);
+        assert!((price.price.to_num::<f64>() - 0.021).abs() < 1e-9);
+        let oracle_price = market
+            .oracle_price(
+                Some(&oracle_a),
+                Some(&oracle_b),
+                Some(&oracle_c),
+                950,
+                now_ts,
+            )
+            .unwrap();
+        assert_eq!(oracle_price, Some(price.price));
+    }
+
+    #[test]
+    fn test_openbook_market_oracle() {
+        let now_ts = 1_700_000_000;
+        let accounts = new_market_accounts();
+        let mut reference_market = Market::zeroed();
+        reference_market.base_mint = accounts.base_mint;
+        reference_market.quote_mint = accounts.quote_mint;
+        reference_market.base_decimals = 9;
+        reference_market.quote_decimals = 6;
+        reference_market.quote_lot_size = 10;
+        reference_market.base_lot_size = 100;
+        reference_market.bids = Pubkey::new_unique();
+        reference_market.asks = Pubkey::new_unique();
+
+        // 5 lots bid at 1000 and 3 lots asked at 1010
+        let book_side = |side: Side, quantity: i64, price_lots: i64| {
+            let mut book_side: Box<BookSide> = Box::new(Zeroable::zeroed());
+            book_side.nodes.order_tree_type = match side {
+                Side::Bid => OrderTreeType::Bids.into(),
+                Side::Ask => OrderTreeType::Asks.into(),
+            };
+            let leaf = LeafNode::new(
+                0,
+                new_node_key(side, fixed_price_data(price_lots).unwrap(), 0),
+                Pubkey::new_unique(),
+                quantity,
+                0,
+                0,
+                -1,
+                0,
+            );
+            book_side
+                .insert_leaf(BookSideOrderTree::Fixed, &leaf)
+                .unwrap();
+            book_side
+        };
+        let bids = book_side(Side::Bid, 5, 1000);
+        let asks = book_side(Side::Ask, 3, 1010);
+
+        // the reference market and its book as oracle accounts
+        let reference = program_account(
+            Pubkey::new_unique(),
+            Market::DISCRIMINATOR,
+            bytemuck::bytes_of(&reference_market),
+        );
+        let bids = program_account(
+            reference_market.bids,
+            BookSide::DISCRIMINATOR,
+            bytemuck::bytes_of(&*bids),
+        );
+        let asks = program_account(
+            reference_market.asks,
+            BookSide::DISCRIMINATOR,
+            bytemuck::bytes_of(&*asks),
+        );
+
+        let config = OracleConfigParams {
+            book_spread_deviation: 0.5,
+            ..oracle_config_params()
+        };
+
+        // the book is needed
+        assert!(
+            create_market(&accounts, [Some(&reference), None, None], &config, 100)
+                .is_anchor_error_with_code(OpenBookError::InvalidOracleCombination.error_code())
+        );
+        assert!(create_market(
+            &accounts,
+            [Some(&reference), Some(&asks), Some(&bids)],
+            &config,
+            100
+        )
+        .is_anchor_error_with_code(OpenBookError::InvalidOracleTypes.error_code()));
+
+        let market = create_market(
+            &accounts,
+            [Some(&reference), Some(&bids), Some(&asks)],
+            &config,
+            100,
+        )
+        .unwrap();
+
+        let lots = |market: &Market, rounding: Rounding| {
+            market
+                .oracle_price_lots_exact(
+                    Some(&reference),
+                    Some(&bids),
+                    Some(&asks),
+                    0,
+                    now_ts,
+                    rounding,
+                )
+                .unwrap()
+        };
+
+        // same lot sizes as the reference market
+        assert_eq!(lots(&market, Rounding::Down), Some(1005));
+        let native_price = market
+            .oracle_price(Some(&reference), Some(&bids), Some(&asks), 0, now_ts)
+            .unwrap()
+            .unwrap();
+        assert!((native_price.to_num::<f64>() - 100.5).abs() < 1e-9);
+
+        // 1006.25, closer to the ask with less quantity behind it
+        let mut micro = market;
+        micro.oracle_config.book_price_method = BookPriceMethod::MicroPrice.into();
+        assert_eq!(lots(&micro, Rounding::Down), Some(1006));
+        assert_eq!(lots(&micro, Rounding::Up), Some(1007));
+
+        let mut depth = market;
+        depth.oracle_config.book_price_method = BookPriceMethod::DepthWeightedMid.into();
+        depth.oracle_config.book_depth_base_lots = 3;
+        assert_eq!(lots(&depth, Rounding::Down), Some(1005));
+        // not enough asks
+        depth.oracle_config.book_depth_base_lots = 4;
+        assert_eq!(lots(&depth, Rounding::Down), None);
+
+        // a spread of 10 lots is too wide when the price is uncertain by 20 times the spread
+        let mut wide = market;
+        wide.oracle_config.book_spread_deviation = 20.0;
+        assert_eq!(lots(&wide, Rounding::Down), None);
+
+        // oracle_c is only the asks of an OpenBook market in oracle_a
+        let mut without_asks = market;
+        without_asks.oracle_c = NonZeroPubkeyOption::default();
+        assert!(without_asks
+            .oracle_price_lots(
+                Some(&reference),
+                Some(&bids),
+                None::<&TestAccount>,
+                0,
+                now_ts,
+            )
+            .is_anchor_error_with_code(OpenBookError::InvalidOracleCombination.error_code()));
+    }
+
+    #[test]
+    fn test_oracle_price_lots_exact() {
+        let oracle_a = stub_oracle(20.5678, 1000);
+        let oracle_b = stub_oracle(2.0, 1000);
+        let accounts = new_market_accounts();
+        let config = oracle_config_params();
+        let market =
+            create_market(&accounts, [Some(&oracle_a), None, None], &config, 1_000_000).unwrap();
+
+        let lots =
+            |market: &Market, oracle_b: Option<&TestAccount>, slot: u64, rounding: Rounding| {
+                market
+                    .oracle_price_lots_exact(
+                        Some(&oracle_a),
+                        oracle_b,
+                        None::<&TestAccount>,
+                        slot,
+                        0,
+                        rounding,
+                    )
+                    .unwrap()
+            };
+
+        // 20.5678 USDC/SOL is 2056.78 lots
+        assert_eq!(lots(&market, None, 1000, Rounding::Down), Some(2056));
+        assert_eq!(lots(&market, None, 1000, Rounding::Up), Some(2057));
+        assert_eq!(lots(&market, None, 1000, Rounding::Nearest), Some(2057));
+        assert_eq!(
+            market
+                .oracle_price_lots(
+                    Some(&oracle_a),
+                    None::<&TestAccount>,
+                    None::<&TestAccount>,
+                    1000,
+                    0,
+                )
+                .unwrap(),
+            Some(2056)
+        );
+
+        // 1028.39 lots for oracle_a / oracle_b
+        let oracles = [Some(&oracle_a), Some(&oracle_b), None];
+        let mut market = create_market(&accounts, oracles, &config, 1_000_000).unwrap();
+        assert_eq!(
+            lots(&market, Some(&oracle_b), 1000, Rounding::Down),
+            Some(1028)
+        );
+        assert_eq!(
+            lots(&market, Some(&oracle_b), 1000, Rounding::Up),
+            Some(1029)
+        );
+
+        // stale oracles give no price either way
+        market.oracle_config.max_staleness_slots = 10;
+        assert_eq!(
+            lots(&market, Some(&oracle_b), 1_000_000, Rounding::Down),
+            None
+        );
+    }
+}
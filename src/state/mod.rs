@@ -1,13 +1,18 @@
+pub use decimal_price::*;
 pub use market::*;
 pub use open_orders_account::*;
 pub use open_orders_indexer::*;
 pub use oracle::*;
 pub use orderbook::*;
 
+mod decimal_price;
 mod market;
 mod open_orders_account;
 mod open_orders_indexer;
 mod orderbook;
 
 pub mod oracle;
+mod orca_internal;
+mod pyth_pull_internal;
 mod raydium_internal;
+mod switchboard_on_demand_internal;
@@ -6,7 +6,9 @@ use crate::logs::{emit_stack, FillLog};
 use crate::pubkey_option::NonZeroPubkeyOption;
 use crate::{error::*, logs::OpenOrdersPositionLog};
 
-use super::{BookSideOrderTree, FillEvent, LeafNode, Market, Side, SideAndOrderTree};
+use super::{
+    BookSideOrderTree, Event, EventHeap, FillEvent, LeafNode, Market, Side, SideAndOrderTree,
+};
 
 pub const MAX_OPEN_ORDERS: usize = 24;
 
@@ -130,6 +132,55 @@ impl OpenOrdersAccount {
     }
 
     pub fn execute_maker(&mut self, market: &mut Market, fill: &FillEvent) {
+        let maker_fees = self.apply_fill(market, fill);
+        let quote_native = (fill.quantity * fill.price * market.quote_lot_size) as u64;
+
+        // Calculate taker fee, ignoring self trades
+        let taker_fee_ceil = if quote_native > 0 && fill.maker != fill.taker {
+            market.taker_fees_ceil(quote_native)
+        } else {
+            0
+        };
+
+        emit_stack(FillLog {
+            market: self.market,
+            taker_side: fill.taker_side,
+            maker_slot: fill.maker_slot,
+            maker_out: fill.maker_out(),
+            timestamp: fill.timestamp,
+            seq_num: fill.market_seq_num,
+            maker: fill.maker,
+            maker_client_order_id: fill.maker_client_order_id,
+            maker_fee: maker_fees,
+            maker_timestamp: fill.maker_timestamp,
+            taker: fill.taker,
+            taker_client_order_id: fill.taker_client_order_id,
+            taker_fee_ceil,
+            price: fill.price,
+            quantity: fill.quantity,
+        });
+
+        let pa = &self.position;
+        emit_stack(OpenOrdersPositionLog {
+            owner: self.owner,
+            open_orders_account_num: self.account_num,
+            market: self.market,
+            bids_base_lots: pa.bids_base_lots,
+            bids_quote_lots: pa.bids_quote_lots,
+            asks_base_lots: pa.asks_base_lots,
+            base_free_native: pa.base_free_native,
+            quote_free_native: pa.quote_free_native,
+            locked_maker_fees: pa.locked_maker_fees,
+            referrer_rebates_available: pa.referrer_rebates_available,
+            maker_volume: pa.maker_volume,
+            taker_volume: pa.taker_volume,
+        })
+    }
+
+    /// The position and fee accounting of execute_maker(), without logging
+    ///
+    /// Returns the maker fees charged for the fill.
+    fn apply_fill(&mut self, market: &mut Market, fill: &FillEvent) -> u64 {
         let is_self_trade = fill.maker == fill.taker;
 
         let side = fill.taker_side().invert_side();
@@ -196,46 +247,7 @@ impl OpenOrdersAccount {
             }
         }
 
-        // Calculate taker fee, ignoring self trades
-        let taker_fee_ceil = if quote_native > 0 && fill.maker != fill.taker {
-            market.taker_fees_ceil(quote_native)
-        } else {
-            0
-        };
-
-        emit_stack(FillLog {
-            market: self.market,
-            taker_side: fill.taker_side,
-            maker_slot: fill.maker_slot,
-            maker_out: fill.maker_out(),
-            timestamp: fill.timestamp,
-            seq_num: fill.market_seq_num,
-            maker: fill.maker,
-            maker_client_order_id: fill.maker_client_order_id,
-            maker_fee: maker_fees,
-            maker_timestamp: fill.maker_timestamp,
-            taker: fill.taker,
-            taker_client_order_id: fill.taker_client_order_id,
-            taker_fee_ceil,
-            price: fill.price,
-            quantity: fill.quantity,
-        });
-
-        let pa = &self.position;
-        emit_stack(OpenOrdersPositionLog {
-            owner: self.owner,
-            open_orders_account_num: self.account_num,
-            market: self.market,
-            bids_base_lots: pa.bids_base_lots,
-            bids_quote_lots: pa.bids_quote_lots,
-            asks_base_lots: pa.asks_base_lots,
-            base_free_native: pa.base_free_native,
-            quote_free_native: pa.quote_free_native,
-            locked_maker_fees: pa.locked_maker_fees,
-            referrer_rebates_available: pa.referrer_rebates_available,
-            maker_volume: pa.maker_volume,
-            taker_volume: pa.taker_volume,
-        })
+        maker_fees
     }
 
     /// Release funds and apply taker fees to the taker account. Account fees for referrer
@@ -340,6 +352,33 @@ impl OpenOrdersAccount {
 
         self.remove_order(slot, base_quantity, price);
     }
+
+    /// The account as it will be once the events for it on `event_heap` are consumed
+    ///
+    /// Applies the pending fill and out events of the account at `open_orders_account_pk` to a
+    /// copy, the same way consume_events does. The free balances of the result include fills
+    /// that haven't been cranked yet and its open orders no longer list filled or expired ones.
+    pub fn with_pending_events(
+        &self,
+        open_orders_account_pk: &Pubkey,
+        market: &Market,
+        event_heap: &EventHeap,
+    ) -> Result<OpenOrdersAccount> {
+        let mut account = *self;
+        // apply_fill() also updates market statistics, which must not leak out
+        let mut market = *market;
+        for item in event_heap.iter_events_for_owner(open_orders_account_pk) {
+            match item?.1 {
+                Event::Fill(fill) => {
+                    account.apply_fill(&mut market, &fill);
+                }
+                Event::Out(out) => {
+                    account.cancel_order(out.owner_slot as usize, out.quantity, market)
+                }
+            }
+        }
+        Ok(account)
+    }
 }
 
 #[derive(Derivative)]
@@ -454,3 +493,77 @@ impl OpenOrder {
         SideAndOrderTree::try_from(self.side_and_tree).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{new_node_key, OutEvent};
+    use bytemuck::Zeroable;
+
+    #[test]
+    fn test_with_pending_events() {
+        let mut market = Market::zeroed();
+        market.quote_lot_size = 10;
+        market.base_lot_size = 100;
+        market.maker_fee = -200;
+        market.taker_fee = 400;
+
+        let maker_pk = Pubkey::new_unique();
+        let taker_pk = Pubkey::new_unique();
+        let mut maker = OpenOrdersAccount::default_for_tests();
+        for (slot, side, price_lots, quantity) in [(0, Side::Bid, 1000, 5), (1, Side::Ask, 2000, 2)]
+        {
+            let key = new_node_key(side, price_lots as u64, slot as u64 + 1);
+            let leaf = LeafNode::new(slot, key, maker_pk, quantity, 0, 0, -1, 0);
+            maker.add_order(side, BookSideOrderTree::Fixed, &leaf, 0, price_lots);
+        }
+
+        // the bid is filled, the ask expired and one event is for another account
+        let fill = FillEvent::new(
+            Side::Ask,
+            true,
+            0,
+            10,
+            3,
+            maker_pk,
+            0,
+            0,
+            taker_pk,
+            0,
+            1000,
+            -1,
+            5,
+        );
+        let out = OutEvent::new(Side::Ask, 1, 10, 1, maker_pk, 2);
+        let other = OutEvent::new(Side::Bid, 0, 10, 2, taker_pk, 1);
+        let mut event_heap = EventHeap::zeroed();
+        event_heap.init();
+        event_heap.push_back(bytemuck::cast(fill));
+        event_heap.push_back(bytemuck::cast(other));
+        event_heap.push_back(bytemuck::cast(out));
+
+        let projected = maker
+            .with_pending_events(&maker_pk, &market, &event_heap)
+            .unwrap();
+        assert_eq!(maker.position.base_free_native, 0);
+        assert_eq!(projected.position.base_free_native, 700);
+        assert_eq!(projected.position.quote_free_native, 10); // maker rebate
+        assert!(projected.all_orders_in_use().next().is_none());
+
+        let mut consumed = *maker;
+        consumed.execute_maker(&mut market, &fill);
+        consumed.cancel_order(1, 2, market);
+        assert_eq!(
+            bytemuck::bytes_of(&consumed.position),
+            bytemuck::bytes_of(&projected.position)
+        );
+        for (consumed, projected) in consumed
+            .open_orders
+            .iter()
+            .zip(projected.open_orders.iter())
+        {
+            assert_eq!(consumed.is_free(), projected.is_free());
+            assert_eq!(consumed.id, projected.id);
+        }
+    }
+}
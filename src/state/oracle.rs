@@ -1,22 +1,43 @@
 use anchor_lang::prelude::*;
 use anchor_lang::Discriminator;
 use fixed::types::U64F64;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 use switchboard_program::FastRoundResultAccountData;
 use switchboard_solana::AggregatorAccountData;
 
 use crate::accounts_zerocopy::*;
 use crate::error::*;
+use crate::state::orca_internal;
+use crate::state::orca_internal::Whirlpool;
+use crate::state::pyth_pull_internal;
+use crate::state::pyth_pull_internal::{PriceUpdateV2, VerificationLevel};
 use crate::state::raydium_internal;
-use crate::state::raydium_internal::PoolState;
+use crate::state::raydium_internal::{ObservationState, PoolState, OBSERVATION_NUM};
+use crate::state::switchboard_on_demand_internal;
+use crate::state::switchboard_on_demand_internal::PullFeedAccountData;
+use crate::state::{BookSide, BookSideRef, DecimalPrice, Market, MarketRef};
 
-const DECIMAL_CONSTANT_ZERO_INDEX: i8 = 12;
+const DECIMAL_CONSTANT_ZERO_INDEX: i32 = 12;
 const DECIMAL_CONSTANTS_F64: [f64; 25] = [
     1e-12, 1e-11, 1e-10, 1e-9, 1e-8, 1e-7, 1e-6, 1e-5, 1e-4, 1e-3, 1e-2, 1e-1, 1e0, 1e1, 1e2, 1e3,
     1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12,
 ];
 
-pub const fn power_of_ten_float(decimals: i8) -> f64 {
-    DECIMAL_CONSTANTS_F64[(decimals + DECIMAL_CONSTANT_ZERO_INDEX) as usize]
+pub fn power_of_ten_float(decimals: i32) -> f64 {
+    let index = decimals as i64 + DECIMAL_CONSTANT_ZERO_INDEX as i64;
+    match usize::try_from(index)
+        .ok()
+        .and_then(|i| DECIMAL_CONSTANTS_F64.get(i))
+    {
+        Some(constant) => *constant,
+        None => 10f64.powi(decimals),
+    }
+}
+
+/// The integer price of an oracle, negative prices are invalid
+fn decimal_price(mantissa: i128, exponent: i32) -> Result<DecimalPrice> {
+    DecimalPrice::from_signed(mantissa, exponent)
+        .ok_or_else(|| OpenBookError::InvalidOraclePrice.into())
 }
 
 pub mod switchboard_v1_devnet_oracle {
@@ -28,17 +49,157 @@ pub mod switchboard_v2_mainnet_oracle {
     declare_id!("DtmE9D2CSB4L5D6A15mraeEjrGMm6auWVzgaD8hK2tZM");
 }
 
+/// Which price of the oracle a market uses
+#[derive(
+    Eq,
+    PartialEq,
+    Copy,
+    Clone,
+    Debug,
+    TryFromPrimitive,
+    IntoPrimitive,
+    AnchorSerialize,
+    AnchorDeserialize,
+)]
+#[repr(u8)]
+pub enum OraclePriceMode {
+    /// The latest price
+    Spot = 0,
+    /// The EMA price for Pyth oracles, a TWAP over `twap_window_secs` for Raydium CLMM pools
+    Smoothed = 1,
+}
+
+/// How the price of an OpenBook market oracle is derived from its book
+///
+/// Oracle pegged orders of the reference market are left out, their price depends on its
+/// own oracle.
+#[derive(
+    Eq,
+    PartialEq,
+    Copy,
+    Clone,
+    Debug,
+    TryFromPrimitive,
+    IntoPrimitive,
+    AnchorSerialize,
+    AnchorDeserialize,
+)]
+#[repr(u8)]
+pub enum BookPriceMethod {
+    /// Middle between the best bid and the best ask
+    Mid = 0,
+    /// Best bid and best ask weighted by the quantity on the opposite side, which moves the
+    /// price towards the side that is about to be taken out
+    MicroPrice = 1,
+    /// Middle between the prices at which `book_depth_base_lots` could be sold and bought
+    DepthWeightedMid = 2,
+}
+
+/// How a market combines its oracles into a price
+#[derive(
+    Eq,
+    PartialEq,
+    Copy,
+    Clone,
+    Debug,
+    TryFromPrimitive,
+    IntoPrimitive,
+    AnchorSerialize,
+    AnchorDeserialize,
+)]
+#[repr(u8)]
+pub enum OracleCombination {
+    /// The price of oracle_a, or of oracle_a / oracle_b if both are set
+    Ratio = 0,
+    /// The median of the usable prices of oracle_a, oracle_b and oracle_c, each of which
+    /// prices base in quote on its own
+    Median = 1,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, anchor_lang::AnchorSerialize, anchor_lang::AnchorDeserialize)]
 pub struct OracleConfig {
     pub conf_filter: f64,
     pub max_staleness_slots: i64,
-    pub reserved: [u8; 72],
+    /// OraclePriceMode
+    pub price_mode: u8,
+    /// OracleCombination
+    pub combination: u8,
+    pub padding: [u8; 2],
+    /// Window of the Raydium CLMM TWAP in the Smoothed price mode
+    pub twap_window_secs: u32,
+    /// Max age of the oracle's publish timestamp, disabled if zero or negative
+    ///
+    /// Zero is the value of markets created before this field was carved from `reserved`.
+    /// Oracles without a timestamp, like a Raydium CLMM pool without its ObservationState,
+    /// are always stale if enabled.
+    pub max_staleness_secs: i64,
+    /// Min in-range liquidity of Raydium CLMM and Orca Whirlpool pools
+    ///
+    /// See raydium_clmm_pool_is_valid() and orca_whirlpool_is_valid().
+    pub clmm_min_liquidity: u64,
+    /// Depth of the DepthWeightedMid price of OpenBook market oracles
+    pub book_depth_base_lots: u64,
+    /// Deviation of OpenBook market oracles, as a fraction of the spread the price is taken from
+    pub book_spread_deviation: f64,
+    /// BookPriceMethod
+    pub book_price_method: u8,
+    pub reserved: [u8; 31],
 }
 
 unsafe impl bytemuck::Pod for OracleConfig {}
 unsafe impl bytemuck::Zeroable for OracleConfig {}
 
+impl OracleConfig {
+    pub fn price_mode(&self) -> Result<OraclePriceMode> {
+        OraclePriceMode::try_from(self.price_mode)
+            .map_err(|_| OpenBookError::InvalidOracleConfig.into())
+    }
+
+    pub fn combination(&self) -> Result<OracleCombination> {
+        OracleCombination::try_from(self.combination)
+            .map_err(|_| OpenBookError::InvalidOracleConfig.into())
+    }
+
+    pub fn book_price_method(&self) -> Result<BookPriceMethod> {
+        BookPriceMethod::try_from(self.book_price_method)
+            .map_err(|_| OpenBookError::InvalidOracleConfig.into())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub struct OracleConfigParams {
+    pub conf_filter: f32,
+    pub max_staleness_slots: Option<u32>,
+    pub price_mode: OraclePriceMode,
+    pub twap_window_secs: u32,
+    pub max_staleness_secs: Option<u32>,
+    pub clmm_min_liquidity: u64,
+    pub combination: OracleCombination,
+    pub book_price_method: BookPriceMethod,
+    pub book_depth_base_lots: u64,
+    pub book_spread_deviation: f32,
+}
+
+impl OracleConfigParams {
+    pub fn to_oracle_config(&self) -> OracleConfig {
+        OracleConfig {
+            conf_filter: self.conf_filter as f64,
+            max_staleness_slots: self.max_staleness_slots.map(|v| v as i64).unwrap_or(-1),
+            price_mode: self.price_mode.into(),
+            combination: self.combination.into(),
+            padding: Default::default(),
+            twap_window_secs: self.twap_window_secs,
+            max_staleness_secs: self.max_staleness_secs.map(|v| v as i64).unwrap_or(0),
+            clmm_min_liquidity: self.clmm_min_liquidity,
+            book_depth_base_lots: self.book_depth_base_lots,
+            book_spread_deviation: self.book_spread_deviation as f64,
+            book_price_method: self.book_price_method.into(),
+            reserved: [0; 31],
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, AnchorSerialize, AnchorDeserialize)]
 pub enum OracleType {
     Pyth,
@@ -46,17 +207,31 @@ pub enum OracleType {
     SwitchboardV1,
     SwitchboardV2,
     RaydiumCLMM,
+    PythPull,
+    SwitchboardOnDemand,
+    OrcaWhirlpool,
+    OpenBookV2Market,
 }
 
 pub struct OracleState {
     pub price: f64,
+    /// The price as published by the oracle, without floating point rounding
+    pub decimal_price: DecimalPrice,
     pub deviation: f64,
     pub last_update_slot: u64,
+    /// Publish timestamp of the price, if the oracle has one
+    pub last_update_ts: Option<i64>,
     pub oracle_type: OracleType,
 }
 
 impl OracleState {
-    pub fn is_stale(&self, oracle_pk: &Pubkey, config: &OracleConfig, now_slot: u64) -> bool {
+    pub fn is_stale(
+        &self,
+        oracle_pk: &Pubkey,
+        config: &OracleConfig,
+        now_slot: u64,
+        now_ts: i64,
+    ) -> bool {
         if config.max_staleness_slots >= 0
             && self
                 .last_update_slot
@@ -70,10 +245,42 @@ impl OracleState {
                 self.last_update_slot,
                 now_slot,
             );
-            true
-        } else {
-            false
+            return true;
         }
+
+        if config.max_staleness_secs > 0 {
+            match self.last_update_ts {
+                Some(last_update_ts)
+                    if last_update_ts.saturating_add(config.max_staleness_secs) >= now_ts => {}
+                _ => {
+                    msg!(
+                        "Oracle is stale; pubkey {}, price: {}, last_update_ts: {:?}, now_ts: {}",
+                        oracle_pk,
+                        self.price,
+                        self.last_update_ts,
+                        now_ts,
+                    );
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// The price of the quote token in base tokens, fails for a zero price
+    pub fn inverted(self) -> Result<Self> {
+        let decimal_price = self
+            .decimal_price
+            .checked_inverse()
+            .ok_or(OpenBookError::InvalidOraclePrice)?;
+        Ok(Self {
+            price: 1.0 / self.price,
+            decimal_price,
+            // same relative deviation
+            deviation: self.deviation / (self.price * self.price),
+            ..self
+        })
     }
 
     pub fn has_valid_confidence(&self, oracle_pk: &Pubkey, config: &OracleConfig) -> bool {
@@ -132,6 +339,18 @@ pub fn determine_oracle_type(acc_info: &impl KeyedAccountReader) -> Result<Oracl
         return Ok(OracleType::Pyth);
     } else if data[0..8] == StubOracle::discriminator() {
         return Ok(OracleType::Stub);
+    } else if data[0..8] == PriceUpdateV2::discriminator()
+        && acc_info.owner() == &pyth_pull_internal::ID
+    {
+        return Ok(OracleType::PythPull);
+    } else if data[0..8] == PullFeedAccountData::discriminator()
+        && is_switchboard_on_demand_program(acc_info.owner())
+    {
+        return Ok(OracleType::SwitchboardOnDemand);
+    } else if data[0..8] == Whirlpool::discriminator() && acc_info.owner() == &orca_internal::ID {
+        return Ok(OracleType::OrcaWhirlpool);
+    } else if data[0..8] == Market::DISCRIMINATOR && acc_info.owner() == &crate::ID {
+        return Ok(OracleType::OpenBookV2Market);
     }
     // https://github.com/switchboard-xyz/switchboard-v2/blob/main/libraries/rs/src/aggregator.rs#L114
     // note: disc is not public, hence the copy pasta
@@ -150,6 +369,14 @@ pub fn determine_oracle_type(acc_info: &impl KeyedAccountReader) -> Result<Oracl
     Err(OpenBookError::UnknownOracleType.into())
 }
 
+fn is_switchboard_on_demand_program(owner: &Pubkey) -> bool {
+    #[cfg(feature = "devnet")]
+    if owner == &switchboard_on_demand_internal::devnet::ID {
+        return true;
+    }
+    owner == &switchboard_on_demand_internal::ID
+}
+
 /// Get the pyth agg price if it's available, otherwise take the prev price.
 ///
 /// Returns the publish slot in addition to the price info.
@@ -182,6 +409,28 @@ fn pyth_get_price(
     }
 }
 
+/// Deserialize a PriceUpdateV2, only fully verified updates are accepted
+fn pyth_pull_price_update(data: &[u8]) -> Result<PriceUpdateV2> {
+    let update = PriceUpdateV2::try_deserialize(&mut &data[..])?;
+    require!(
+        update.verification_level == VerificationLevel::Full,
+        OpenBookError::OracleNotFullyVerified
+    );
+    Ok(update)
+}
+
+/// The feed id of a Pyth pull oracle, None for other accounts
+///
+/// Anyone can post a verified update of any feed to an account they own, so markets record
+/// the feed of their oracles at creation and check it on every read.
+pub fn pyth_pull_feed_id(acc_info: &impl KeyedAccountReader) -> Result<Option<[u8; 32]>> {
+    if determine_oracle_type(acc_info).ok() != Some(OracleType::PythPull) {
+        return Ok(None);
+    }
+    let update = PriceUpdateV2::try_deserialize(&mut &acc_info.data()[..])?;
+    Ok(Some(update.price_message.feed_id))
+}
+
 /// Returns the price of one native base token, in native quote tokens
 ///
 /// Example: The for SOL at 40 USDC/SOL it would return 0.04 (the unit is USDC-native/SOL-native)
@@ -201,9 +450,13 @@ pub fn oracle_state_unchecked(acc_info: &impl KeyedAccountReader) -> Result<Orac
             } else {
                 stub.last_update_slot
             };
+            let decimal_price =
+                DecimalPrice::from_f64(stub.price).ok_or(OpenBookError::InvalidOraclePrice)?;
             OracleState {
                 price: stub.price,
+                decimal_price,
                 last_update_slot,
+                last_update_ts: Some(stub.last_update_ts),
                 deviation: stub.deviation,
                 oracle_type: OracleType::Stub,
             }
@@ -212,18 +465,36 @@ pub fn oracle_state_unchecked(acc_info: &impl KeyedAccountReader) -> Result<Orac
             let price_account = pyth_sdk_solana::state::load_price_account(data).unwrap();
             let (price_data, last_update_slot) = pyth_get_price(price_account);
 
-            let decimals = price_account.expo as i8;
-            let decimal_adj = power_of_ten_float(decimals);
+            let decimal_adj = power_of_ten_float(price_account.expo);
             let price = price_data.price as f64 * decimal_adj;
             let deviation = price_data.conf as f64 * decimal_adj;
             require_gte!(price, 0f64);
             OracleState {
                 price,
+                decimal_price: decimal_price(price_data.price as i128, price_account.expo)?,
                 last_update_slot,
+                last_update_ts: Some(price_data.publish_time),
                 deviation,
                 oracle_type: OracleType::Pyth,
             }
         }
+        OracleType::PythPull => {
+            let update = pyth_pull_price_update(data)?;
+            let message = update.price_message;
+
+            let decimal_adj = power_of_ten_float(message.exponent);
+            let price = message.price as f64 * decimal_adj;
+            let deviation = message.conf as f64 * decimal_adj;
+            require_gte!(price, 0f64);
+            OracleState {
+                price,
+                decimal_price: decimal_price(message.price as i128, message.exponent)?,
+                last_update_slot: update.posted_slot,
+                last_update_ts: Some(message.publish_time),
+                deviation,
+                oracle_type: OracleType::PythPull,
+            }
+        }
         OracleType::SwitchboardV2 => {
             fn from_foreign_error(e: impl std::fmt::Display) -> Error {
                 error_msg!("{}", e)
@@ -231,6 +502,7 @@ pub fn oracle_state_unchecked(acc_info: &impl KeyedAccountReader) -> Result<Orac
 
             let feed = bytemuck::from_bytes::<AggregatorAccountData>(&data[8..]);
             let feed_result = feed.get_result().map_err(from_foreign_error)?;
+            let decimal_price = decimal_price(feed_result.mantissa, -(feed_result.scale as i32))?;
             let price: f64 = feed_result.try_into().map_err(from_foreign_error)?;
             let deviation: f64 = feed
                 .latest_confirmed_round
@@ -241,25 +513,57 @@ pub fn oracle_state_unchecked(acc_info: &impl KeyedAccountReader) -> Result<Orac
             // The round_open_slot is an underestimate of the last update slot: Reporters will see
             // the round opening and only then start executing the price tasks.
             let last_update_slot = feed.latest_confirmed_round.round_open_slot;
+            let last_update_ts = feed.latest_confirmed_round.round_open_timestamp;
 
             require_gte!(price, 0f64);
             OracleState {
                 price,
+                decimal_price,
                 last_update_slot,
+                last_update_ts: Some(last_update_ts),
                 deviation,
                 oracle_type: OracleType::SwitchboardV2,
             }
         }
+        OracleType::SwitchboardOnDemand => {
+            let feed_data = data
+                .get(8..8 + std::mem::size_of::<PullFeedAccountData>())
+                .ok_or(ErrorCode::AccountDidNotDeserialize)?;
+            let feed = bytemuck::from_bytes::<PullFeedAccountData>(feed_data);
+            let result = feed.result;
+            let last_update_slot = result.slot;
+            require_neq!(last_update_slot, 0, OpenBookError::OracleStale);
+
+            let precision = 10f64.powi(switchboard_on_demand_internal::PRECISION);
+            let price = result.value as f64 / precision;
+            let deviation = result.std_dev as f64 / precision;
+            require_gte!(price, 0f64);
+            OracleState {
+                price,
+                decimal_price: decimal_price(
+                    result.value,
+                    -switchboard_on_demand_internal::PRECISION,
+                )?,
+                last_update_slot,
+                last_update_ts: Some(feed.last_update_timestamp),
+                deviation,
+                oracle_type: OracleType::SwitchboardOnDemand,
+            }
+        }
         OracleType::SwitchboardV1 => {
             let result = FastRoundResultAccountData::deserialize(data).unwrap();
             let price = result.result.result;
 
             let deviation = result.result.max_response - result.result.min_response;
             let last_update_slot = result.result.round_open_slot;
+            let last_update_ts = result.result.round_open_timestamp;
             require_gte!(price, 0f64);
             OracleState {
                 price,
+                decimal_price: DecimalPrice::from_f64(price)
+                    .ok_or(OpenBookError::InvalidOraclePrice)?,
                 last_update_slot,
+                last_update_ts: Some(last_update_ts),
                 deviation,
                 oracle_type: OracleType::SwitchboardV1,
             }
@@ -269,17 +573,1082 @@ pub fn oracle_state_unchecked(acc_info: &impl KeyedAccountReader) -> Result<Orac
 
             let sqrt_price = U64F64::from_bits(pool.sqrt_price_x64);
 
-            let decimals: i8 = (pool.mint_decimals_0 as i8) - (pool.mint_decimals_1 as i8);
+            let decimals = pool.mint_decimals_0 as i32 - pool.mint_decimals_1 as i32;
             let price: f64 =
                 (sqrt_price * sqrt_price).to_num::<f64>() * power_of_ten_float(decimals);
 
             require_gte!(price, 0f64);
             OracleState {
                 price,
+                decimal_price: DecimalPrice::from_sqrt_x64(pool.sqrt_price_x64)
+                    .mul_pow10(decimals as i32),
                 last_update_slot: u64::MAX, // ensure staleness slot will never fail
-                deviation: 0f64,
+                // the pool has no timestamp, see raydium_clmm_state_unchecked()
+                last_update_ts: None,
+                deviation: tick_spacing_deviation(pool.tick_spacing, price),
                 oracle_type: OracleType::RaydiumCLMM,
             }
         }
+        OracleType::OrcaWhirlpool => {
+            return Err(error_msg_typed!(
+                OpenBookError::InvalidOracleTypes,
+                "whirlpools don't store their mint decimals, use orca_whirlpool_state_unchecked()"
+            ))
+        }
+        OracleType::OpenBookV2Market => {
+            return Err(error_msg_typed!(
+                OpenBookError::InvalidOracleTypes,
+                "the book is in other accounts, use openbook_market_state_unchecked()"
+            ))
+        }
+    })
+}
+
+/// Like oracle_state_unchecked(), but returns the price selected by the price mode of `config`
+///
+/// In the Smoothed mode the EMA of Pyth oracles is used. The TWAP of Raydium CLMM pools needs
+/// the pool's ObservationState, see raydium_clmm_state_unchecked().
+pub fn oracle_state_for_mode_unchecked(
+    acc_info: &impl KeyedAccountReader,
+    config: &OracleConfig,
+) -> Result<OracleState> {
+    if config.price_mode()? == OraclePriceMode::Spot {
+        return oracle_state_unchecked(acc_info);
+    }
+
+    let data = &acc_info.data();
+    let oracle_type = determine_oracle_type(acc_info)?;
+
+    Ok(match oracle_type {
+        OracleType::Pyth => {
+            let price_account = pyth_sdk_solana::state::load_price_account(data).unwrap();
+            // the ema is updated together with the aggregate price
+            let (price_data, last_update_slot) = pyth_get_price(price_account);
+
+            let decimal_adj = power_of_ten_float(price_account.expo);
+            let price = price_account.ema_price.val as f64 * decimal_adj;
+            let deviation = price_account.ema_conf.val as f64 * decimal_adj;
+            require_gte!(price, 0f64);
+            OracleState {
+                price,
+                decimal_price: decimal_price(
+                    price_account.ema_price.val as i128,
+                    price_account.expo,
+                )?,
+                last_update_slot,
+                last_update_ts: Some(price_data.publish_time),
+                deviation,
+                oracle_type: OracleType::Pyth,
+            }
+        }
+        OracleType::PythPull => {
+            let update = pyth_pull_price_update(data)?;
+            let message = update.price_message;
+
+            let decimal_adj = power_of_ten_float(message.exponent);
+            let price = message.ema_price as f64 * decimal_adj;
+            let deviation = message.ema_conf as f64 * decimal_adj;
+            require_gte!(price, 0f64);
+            OracleState {
+                price,
+                decimal_price: decimal_price(message.ema_price as i128, message.exponent)?,
+                last_update_slot: update.posted_slot,
+                last_update_ts: Some(message.publish_time),
+                deviation,
+                oracle_type: OracleType::PythPull,
+            }
+        }
+        _ => return Err(OpenBookError::OraclePriceModeUnsupported.into()),
+    })
+}
+
+/// Price difference between two initialized ticks of a concentrated liquidity pool
+///
+/// Liquidity can only be placed at multiples of the tick spacing, so the pool price is not
+/// more precise than that.
+fn tick_spacing_deviation(tick_spacing: u16, price: f64) -> f64 {
+    price * (1.0001f64.powi(tick_spacing as i32) - 1.0)
+}
+
+/// Whether a Raydium CLMM pool is fit to be an oracle
+///
+/// Swaps must be enabled and the in-range liquidity must reach `config.clmm_min_liquidity`,
+/// otherwise the price is trivial to move.
+pub fn raydium_clmm_pool_is_valid(
+    pool_acc: &impl KeyedAccountReader,
+    config: &OracleConfig,
+) -> bool {
+    let pool = bytemuck::from_bytes::<PoolState>(&pool_acc.data()[8..]);
+    let liquidity = pool.liquidity;
+    if !pool.is_swap_enabled() || liquidity < config.clmm_min_liquidity as u128 {
+        msg!(
+            "Raydium CLMM pool not usable as oracle; pubkey {}, status: {}, liquidity: {}, min_liquidity: {}",
+            pool_acc.key(),
+            pool.status,
+            liquidity,
+            config.clmm_min_liquidity,
+        );
+        false
+    } else {
+        true
+    }
+}
+
+/// Whether the pool's price is quote per base, as the market needs, or its inverse
+///
+/// Raydium CLMM prices are token_1 per token_0, with token_mint_0 < token_mint_1.
+pub fn raydium_clmm_is_inverted(
+    pool_acc: &impl KeyedAccountReader,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> bool {
+    let pool = bytemuck::from_bytes::<PoolState>(&pool_acc.data()[8..]);
+    pool.token_mint_0 == *quote_mint && pool.token_mint_1 == *base_mint
+}
+
+/// Check that a Raydium CLMM pool trades the market's mints, in either order
+pub fn check_raydium_clmm_mints(
+    pool_acc: &impl KeyedAccountReader,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> Result<()> {
+    let pool = bytemuck::from_bytes::<PoolState>(&pool_acc.data()[8..]);
+    let mints = (pool.token_mint_0, pool.token_mint_1);
+    require!(
+        mints == (*base_mint, *quote_mint) || mints == (*quote_mint, *base_mint),
+        OpenBookError::InvalidOracleTypes
+    );
+    Ok(())
+}
+
+/// Price of an Orca Whirlpool, in token_b per token_a
+///
+/// Unlike Raydium CLMM pools, whirlpools don't store the decimals of their mints, so they
+/// must be passed in.
+pub fn orca_whirlpool_state_unchecked(
+    acc_info: &impl KeyedAccountReader,
+    decimals_a: u8,
+    decimals_b: u8,
+) -> Result<OracleState> {
+    require!(
+        determine_oracle_type(acc_info)? == OracleType::OrcaWhirlpool,
+        OpenBookError::InvalidOracleTypes
+    );
+    let pool = bytemuck::from_bytes::<Whirlpool>(&acc_info.data()[8..]);
+
+    let sqrt_price = U64F64::from_bits(pool.sqrt_price);
+
+    let decimals = decimals_a as i32 - decimals_b as i32;
+    let price: f64 = (sqrt_price * sqrt_price).to_num::<f64>() * power_of_ten_float(decimals);
+
+    require_gte!(price, 0f64);
+    Ok(OracleState {
+        price,
+        decimal_price: DecimalPrice::from_sqrt_x64(pool.sqrt_price).mul_pow10(decimals as i32),
+        last_update_slot: u64::MAX, // ensure staleness slot will never fail
+        last_update_ts: None,
+        deviation: tick_spacing_deviation(pool.tick_spacing, price),
+        oracle_type: OracleType::OrcaWhirlpool,
+    })
+}
+
+/// Whether an Orca Whirlpool has at least `config.clmm_min_liquidity` in-range liquidity
+pub fn orca_whirlpool_is_valid(acc_info: &impl KeyedAccountReader, config: &OracleConfig) -> bool {
+    let pool = bytemuck::from_bytes::<Whirlpool>(&acc_info.data()[8..]);
+    let liquidity = pool.liquidity;
+    if liquidity < config.clmm_min_liquidity as u128 {
+        msg!(
+            "Orca whirlpool not usable as oracle; pubkey {}, liquidity: {}, min_liquidity: {}",
+            acc_info.key(),
+            liquidity,
+            config.clmm_min_liquidity,
+        );
+        false
+    } else {
+        true
+    }
+}
+
+/// Whether the whirlpool's price is quote per base, as the market needs, or its inverse
+pub fn orca_whirlpool_is_inverted(
+    acc_info: &impl KeyedAccountReader,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> bool {
+    let pool = bytemuck::from_bytes::<Whirlpool>(&acc_info.data()[8..]);
+    pool.token_mint_a == *quote_mint && pool.token_mint_b == *base_mint
+}
+
+/// Check that an Orca Whirlpool trades the market's mints, in either order
+///
+/// The mint decimals of the pool are then known from the market.
+pub fn check_orca_whirlpool_mints(
+    acc_info: &impl KeyedAccountReader,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> Result<()> {
+    let pool = bytemuck::from_bytes::<Whirlpool>(&acc_info.data()[8..]);
+    let mints = (pool.token_mint_a, pool.token_mint_b);
+    require!(
+        mints == (*base_mint, *quote_mint) || mints == (*quote_mint, *base_mint),
+        OpenBookError::InvalidOracleTypes
+    );
+    Ok(())
+}
+
+/// Whether the account is the ObservationState of a Raydium CLMM pool
+pub fn is_raydium_observation(acc_info: &impl KeyedAccountReader) -> bool {
+    let data = acc_info.data();
+    acc_info.owner() == &raydium_internal::ID
+        && data.len() >= 8 + std::mem::size_of::<ObservationState>()
+        && data[0..8] == ObservationState::discriminator()
+}
+
+/// Price of a Raydium CLMM pool, with the timestamp of its latest observation
+///
+/// In the Smoothed price mode this is the time weighted average price over the last
+/// `config.twap_window_secs`. The window ends at the latest observation of the pool. If the
+/// observations don't reach back far enough, the oldest available one is used.
+pub fn raydium_clmm_state_unchecked(
+    pool_acc: &impl KeyedAccountReader,
+    observation_acc: &impl KeyedAccountReader,
+    config: &OracleConfig,
+) -> Result<OracleState> {
+    require!(
+        determine_oracle_type(pool_acc)? == OracleType::RaydiumCLMM
+            && is_raydium_observation(observation_acc),
+        OpenBookError::InvalidOracleTypes
+    );
+    let pool = bytemuck::from_bytes::<PoolState>(&pool_acc.data()[8..]);
+    require_keys_eq!(
+        pool.observation_key,
+        *observation_acc.key(),
+        OpenBookError::InvalidOracleTypes
+    );
+
+    let observation_data = observation_acc.data();
+    let observation_state = bytemuck::from_bytes::<ObservationState>(
+        &observation_data[8..8 + std::mem::size_of::<ObservationState>()],
+    );
+    let observations = &observation_state.observations;
+
+    let newest_index = observation_state.observation_index as usize % OBSERVATION_NUM;
+    let newest = observations[newest_index];
+    let last_update_ts = Some(newest.block_timestamp as i64);
+
+    if config.price_mode()? == OraclePriceMode::Spot {
+        return Ok(OracleState {
+            last_update_ts,
+            ..oracle_state_unchecked(pool_acc)?
+        });
+    }
+
+    let window_start = newest
+        .block_timestamp
+        .saturating_sub(config.twap_window_secs);
+
+    // walk back through the ring buffer until the window is covered, stopping at
+    // unused or overwritten entries
+    let mut oldest = newest;
+    for i in 1..OBSERVATION_NUM {
+        let observation = observations[(newest_index + OBSERVATION_NUM - i) % OBSERVATION_NUM];
+        if observation.block_timestamp == 0 || observation.block_timestamp >= oldest.block_timestamp
+        {
+            break;
+        }
+        oldest = observation;
+        if oldest.block_timestamp <= window_start {
+            break;
+        }
+    }
+
+    let elapsed = newest.block_timestamp - oldest.block_timestamp;
+    if elapsed == 0 {
+        return Err(error_msg_typed!(
+            OpenBookError::OracleStale,
+            "not enough observations for a twap on pool {}",
+            pool_acc.key()
+        ));
+    }
+    // the mean tick, so the twap is the geometric mean of the price
+    let cumulative = newest.tick_cumulative.wrapping_sub(oldest.tick_cumulative);
+    let average_tick = cumulative as f64 / elapsed as f64;
+
+    let decimals = pool.mint_decimals_0 as i32 - pool.mint_decimals_1 as i32;
+    let price: f64 = 1.0001f64.powf(average_tick) * power_of_ten_float(decimals);
+
+    require_gte!(price, 0f64);
+    Ok(OracleState {
+        price,
+        decimal_price: DecimalPrice::from_f64(price).ok_or(OpenBookError::InvalidOraclePrice)?,
+        last_update_slot: u64::MAX, // ensure staleness slot will never fail
+        last_update_ts,
+        deviation: tick_spacing_deviation(pool.tick_spacing, price),
+        oracle_type: OracleType::RaydiumCLMM,
     })
 }
+
+/// Whether the account is an OpenBook market, whose book can be an oracle
+pub fn is_openbook_market(acc_info: &impl KeyedAccountReader) -> bool {
+    matches!(
+        determine_oracle_type(acc_info),
+        Ok(OracleType::OpenBookV2Market)
+    )
+}
+
+fn load_openbook_market(acc_info: &impl KeyedAccountReader) -> Result<MarketRef<'_>> {
+    require!(
+        is_openbook_market(acc_info),
+        OpenBookError::InvalidOracleTypes
+    );
+    MarketRef::from_bytes(acc_info.data())
+}
+
+fn load_openbook_book_side<'a>(
+    acc_info: &'a impl KeyedAccountReader,
+    expected_key: &Pubkey,
+) -> Result<BookSideRef<'a>> {
+    require_keys_eq!(
+        *acc_info.key(),
+        *expected_key,
+        OpenBookError::InvalidOracleTypes
+    );
+    require_keys_eq!(
+        *acc_info.owner(),
+        crate::ID,
+        OpenBookError::InvalidOracleTypes
+    );
+    BookSideRef::from_bytes(acc_info.data())
+}
+
+/// Reference price of a book in price lots, as numerator / denominator, and the spread in
+/// price lots it's taken from
+///
+/// None if the book doesn't have enough orders on both sides.
+fn book_price_lots(
+    bids: &BookSide,
+    asks: &BookSide,
+    method: BookPriceMethod,
+    depth_base_lots: u64,
+    now_ts: u64,
+) -> Option<(u128, u128, i64)> {
+    match method {
+        BookPriceMethod::Mid => {
+            let bid = bids.best_price(now_ts, None)?;
+            let ask = asks.best_price(now_ts, None)?;
+            Some((bid as u128 + ask as u128, 2, ask - bid))
+        }
+        BookPriceMethod::MicroPrice => {
+            let bid = *bids.l2_levels(1, now_ts, None).first()?;
+            let ask = *asks.l2_levels(1, now_ts, None).first()?;
+            let numerator = bid.price_lots as u128 * ask.quantity_lots as u128
+                + ask.price_lots as u128 * bid.quantity_lots as u128;
+            let denominator = bid.quantity_lots as u128 + ask.quantity_lots as u128;
+            Some((numerator, denominator, ask.price_lots - bid.price_lots))
+        }
+        BookPriceMethod::DepthWeightedMid => {
+            let depth = depth_base_lots.min(i64::MAX as u64) as i64;
+            let bid = bids.impact_price(depth, now_ts, None)?;
+            let ask = asks.impact_price(depth, now_ts, None)?;
+            Some((bid as u128 + ask as u128, 2, ask - bid))
+        }
+    }
+}
+
+/// Price of the book of another OpenBook market, in its quote per base tokens
+///
+/// The bids and asks accounts must be the market's. The deviation is
+/// `config.book_spread_deviation` times the spread the price is taken from. Returns None if
+/// the book doesn't have enough orders for `config.book_price_method`.
+pub fn openbook_market_state_unchecked(
+    market_acc: &impl KeyedAccountReader,
+    bids_acc: &impl KeyedAccountReader,
+    asks_acc: &impl KeyedAccountReader,
+    config: &OracleConfig,
+    now_ts: i64,
+) -> Result<Option<OracleState>> {
+    let market = load_openbook_market(market_acc)?;
+    let bids = load_openbook_book_side(bids_acc, &market.bids)?;
+    let asks = load_openbook_book_side(asks_acc, &market.asks)?;
+
+    let method = config.book_price_method()?;
+    let (numerator, denominator, spread) = match book_price_lots(
+        &bids,
+        &asks,
+        method,
+        config.book_depth_base_lots,
+        now_ts as u64,
+    ) {
+        Some(price) => price,
+        None => {
+            msg!(
+                "OpenBook market oracle has not enough orders; pubkey {}",
+                market_acc.key()
+            );
+            return Ok(None);
+        }
+    };
+
+    // price lots to ui price of the market
+    let decimals = market.base_decimals as i32 - market.quote_decimals as i32;
+    let lot_adj =
+        market.quote_lot_size as f64 / market.base_lot_size as f64 * power_of_ten_float(decimals);
+    let price = numerator as f64 / denominator as f64 * lot_adj;
+    let deviation = spread as f64 * lot_adj * config.book_spread_deviation;
+
+    let decimal_numerator = numerator
+        .checked_mul(market.quote_lot_size as u128)
+        .ok_or(OpenBookError::InvalidOraclePrice)?;
+    let decimal_denominator = denominator
+        .checked_mul(market.base_lot_size as u128)
+        .ok_or(OpenBookError::InvalidOraclePrice)?;
+    let decimal_price = DecimalPrice::new(decimal_numerator, 0)
+        .checked_div(DecimalPrice::new(decimal_denominator, 0))
+        .ok_or(OpenBookError::InvalidOraclePrice)?
+        .mul_pow10(decimals as i32);
+
+    Ok(Some(OracleState {
+        price,
+        decimal_price,
+        last_update_slot: u64::MAX, // ensure staleness slot will never fail
+        // the book has no publish time
+        last_update_ts: None,
+        deviation,
+        oracle_type: OracleType::OpenBookV2Market,
+    }))
+}
+
+/// Whether the other market's price is quote per base, as the market needs, or its inverse
+pub fn openbook_market_is_inverted(
+    market_acc: &impl KeyedAccountReader,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> Result<bool> {
+    let market = load_openbook_market(market_acc)?;
+    Ok(market.base_mint == *quote_mint && market.quote_mint == *base_mint)
+}
+
+/// Check an OpenBook market oracle: the market in oracle_a, its bids in oracle_b and its
+/// asks in oracle_c
+///
+/// The market must trade the same mints, in either order, and can't be the market itself.
+pub fn check_openbook_market_oracle(
+    market_acc: &impl KeyedAccountReader,
+    bids_acc: &impl KeyedAccountReader,
+    asks_acc: &impl KeyedAccountReader,
+    config: &OracleConfig,
+    own_market: &Pubkey,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> Result<()> {
+    require_keys_neq!(
+        *market_acc.key(),
+        *own_market,
+        OpenBookError::InvalidOracleTypes
+    );
+    check_price_mode(OracleType::OpenBookV2Market, config)?;
+    let market = load_openbook_market(market_acc)?;
+    load_openbook_book_side(bids_acc, &market.bids)?;
+    load_openbook_book_side(asks_acc, &market.asks)?;
+
+    let mints = (market.base_mint, market.quote_mint);
+    require!(
+        mints == (*base_mint, *quote_mint) || mints == (*quote_mint, *base_mint),
+        OpenBookError::InvalidOracleTypes
+    );
+    if config.book_price_method()? == BookPriceMethod::DepthWeightedMid {
+        require_gt!(config.book_depth_base_lots, 0);
+    }
+    Ok(())
+}
+
+/// Check that oracles of `oracle_type` provide the price mode of `config`
+pub fn check_price_mode(oracle_type: OracleType, config: &OracleConfig) -> Result<()> {
+    if config.price_mode()? == OraclePriceMode::Smoothed {
+        require!(
+            matches!(oracle_type, OracleType::Pyth | OracleType::PythPull),
+            OpenBookError::OraclePriceModeUnsupported
+        );
+    }
+    Ok(())
+}
+
+/// Check that a market can use the oracle on its own, without a second oracle
+pub fn check_oracle_source(
+    acc_info: &impl KeyedAccountReader,
+    config: &OracleConfig,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> Result<()> {
+    require!(
+        !is_raydium_observation(acc_info),
+        OpenBookError::InvalidOracleTypes
+    );
+    let oracle_type = determine_oracle_type(acc_info)?;
+    // the book accounts take the place of a second oracle
+    require!(
+        oracle_type != OracleType::OpenBookV2Market,
+        OpenBookError::InvalidOracleCombination
+    );
+    check_price_mode(oracle_type, config)?;
+    match oracle_type {
+        OracleType::RaydiumCLMM => check_raydium_clmm_mints(acc_info, base_mint, quote_mint),
+        OracleType::OrcaWhirlpool => check_orca_whirlpool_mints(acc_info, base_mint, quote_mint),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a market can use `oracle_a` and `oracle_b` with `config`
+///
+/// Usually both oracles have the same type and the price is their ratio. Alternatively
+/// `oracle_b` can be the ObservationState of the Raydium CLMM pool `oracle_a`, which provides
+/// the publish timestamp and the TWAP of the Smoothed price mode.
+pub fn check_oracle_pair(
+    oracle_a: &impl KeyedAccountReader,
+    oracle_b: &impl KeyedAccountReader,
+    config: &OracleConfig,
+) -> Result<()> {
+    let oracle_a_type = determine_oracle_type(oracle_a)?;
+
+    if is_raydium_observation(oracle_b) {
+        require!(
+            oracle_a_type == OracleType::RaydiumCLMM,
+            OpenBookError::InvalidOracleTypes
+        );
+        let pool = bytemuck::from_bytes::<PoolState>(&oracle_a.data()[8..]);
+        require_keys_eq!(
+            pool.observation_key,
+            *oracle_b.key(),
+            OpenBookError::InvalidOracleTypes
+        );
+        if config.price_mode()? == OraclePriceMode::Smoothed {
+            require_gt!(config.twap_window_secs, 0);
+        }
+        return Ok(());
+    }
+
+    // the mint decimals of whirlpools are only known for the market's own mints
+    require!(
+        oracle_a_type == determine_oracle_type(oracle_b)?
+            && oracle_a_type != OracleType::OrcaWhirlpool,
+        OpenBookError::InvalidOracleTypes
+    );
+    check_price_mode(oracle_a_type, config)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn oracle_config_params() -> OracleConfigParams {
+        OracleConfigParams {
+            conf_filter: 0.1,
+            max_staleness_slots: None,
+            price_mode: OraclePriceMode::Spot,
+            twap_window_secs: 0,
+            max_staleness_secs: None,
+            clmm_min_liquidity: 0,
+            combination: OracleCombination::Ratio,
+            book_price_method: BookPriceMethod::Mid,
+            book_depth_base_lots: 0,
+            book_spread_deviation: 0.0,
+        }
+    }
+
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        data: Vec<u8>,
+    }
+
+    impl AccountReader for TestAccount {
+        fn owner(&self) -> &Pubkey {
+            &self.owner
+        }
+
+        fn data(&self) -> &[u8] {
+            &self.data
+        }
+    }
+
+    impl KeyedAccountReader for TestAccount {
+        fn key(&self) -> &Pubkey {
+            &self.key
+        }
+    }
+
+    /// PriceUpdateV2 account data with price 145.12345678 +- 0.0725, posted at slot 250_000_000
+    fn price_update_fixture(verification_level: &[u8]) -> Vec<u8> {
+        let mut data = vec![34, 241, 35, 99, 157, 126, 244, 205];
+        data.extend([7; 32]); // write_authority
+        data.extend(verification_level);
+        data.extend([0xef; 32]); // feed_id
+        data.extend(14_512_345_678_i64.to_le_bytes()); // price
+        data.extend(7_250_000_u64.to_le_bytes()); // conf
+        data.extend((-8_i32).to_le_bytes()); // exponent
+        data.extend(1_700_000_000_i64.to_le_bytes()); // publish_time
+        data.extend(1_699_999_999_i64.to_le_bytes()); // prev_publish_time
+        data.extend(14_500_000_000_i64.to_le_bytes()); // ema_price
+        data.extend(8_000_000_u64.to_le_bytes()); // ema_conf
+        data.extend(250_000_000_u64.to_le_bytes()); // posted_slot
+                                                    // accounts are allocated for the larger Partial variant
+        data.resize(134, 0);
+        data
+    }
+
+    #[test]
+    fn test_pyth_pull() {
+        let mut account = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: pyth_pull_internal::ID,
+            data: price_update_fixture(&[1]),
+        };
+        assert_eq!(account.data.len(), 134);
+
+        let update = PriceUpdateV2::try_deserialize(&mut &account.data[..]).unwrap();
+        assert_eq!(update.verification_level, VerificationLevel::Full);
+        assert_eq!(update.price_message.exponent, -8);
+        assert_eq!(update.price_message.publish_time, 1_700_000_000);
+        assert_eq!(update.price_message.ema_price, 14_500_000_000);
+
+        assert!(determine_oracle_type(&account).unwrap() == OracleType::PythPull);
+        let state = oracle_state_unchecked(&account).unwrap();
+        assert!((state.price - 145.12345678).abs() < 1e-9);
+        assert_eq!(state.decimal_price, DecimalPrice::new(14_512_345_678, -8));
+        assert!((state.deviation - 0.0725).abs() < 1e-9);
+        assert_eq!(state.last_update_slot, 250_000_000);
+
+        let mut config = oracle_config_params().to_oracle_config();
+        let spot = oracle_state_for_mode_unchecked(&account, &config).unwrap();
+        assert_eq!(spot.price, state.price);
+        config.price_mode = OraclePriceMode::Smoothed.into();
+        let ema = oracle_state_for_mode_unchecked(&account, &config).unwrap();
+        assert!((ema.price - 145.0).abs() < 1e-9);
+        assert_eq!(ema.decimal_price, DecimalPrice::new(145, 0));
+        assert!((ema.deviation - 0.08).abs() < 1e-9);
+        assert_eq!(ema.last_update_slot, 250_000_000);
+
+        // partially verified updates are rejected
+        account.data = price_update_fixture(&[0, 5]);
+        assert!(oracle_state_unchecked(&account)
+            .is_anchor_error_with_code(OpenBookError::OracleNotFullyVerified.error_code()));
+
+        // the discriminator alone doesn't identify the account
+        account.owner = Pubkey::new_unique();
+        assert!(determine_oracle_type(&account).is_err());
+    }
+
+    #[test]
+    fn test_pyth_pull_feed_id() {
+        let mut account = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: pyth_pull_internal::ID,
+            data: price_update_fixture(&[1]),
+        };
+        assert_eq!(pyth_pull_feed_id(&account).unwrap(), Some([0xef; 32]));
+
+        let mut market: Market = bytemuck::Zeroable::zeroed();
+        market.oracle_a = Some(account.key).into();
+        market.oracle_feed_ids[0] = [0xef; 32];
+        market.oracle_config = oracle_config_params().to_oracle_config();
+        let price = |account: &TestAccount| {
+            market.oracle_price(
+                Some(account),
+                None::<&TestAccount>,
+                None::<&TestAccount>,
+                250_000_000,
+                1_700_000_000,
+            )
+        };
+        assert!(price(&account).unwrap().is_some());
+
+        // the owner of the account posted an update of another feed
+        account.data[41..73].copy_from_slice(&[1; 32]);
+        assert!(price(&account)
+            .is_anchor_error_with_code(OpenBookError::OracleFeedMismatch.error_code()));
+    }
+
+    /// PullFeedAccountData with a result of 145.5 +- 0.05 at slot 260_000_000
+    fn pull_feed_fixture() -> Vec<u8> {
+        let mut data = vec![196, 27, 108, 196, 10, 215, 219, 40];
+        data.resize(8 + 3200, 0);
+        // CurrentResult is at offset 2256 of the feed
+        let result = 8 + 2256;
+        data[result..result + 16].copy_from_slice(&145_500_000_000_000_000_000_i128.to_le_bytes());
+        data[result + 16..result + 32].copy_from_slice(&50_000_000_000_000_000_i128.to_le_bytes());
+        data[result + 104..result + 112].copy_from_slice(&260_000_000_u64.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_switchboard_on_demand() {
+        assert_eq!(std::mem::size_of::<PullFeedAccountData>(), 3200);
+
+        let mut account = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: switchboard_on_demand_internal::ID,
+            data: pull_feed_fixture(),
+        };
+        assert!(determine_oracle_type(&account).unwrap() == OracleType::SwitchboardOnDemand);
+        let state = oracle_state_unchecked(&account).unwrap();
+        assert!((state.price - 145.5).abs() < 1e-9);
+        assert_eq!(state.decimal_price, DecimalPrice::new(1455, -1));
+        assert!((state.deviation - 0.05).abs() < 1e-9);
+        assert_eq!(state.last_update_slot, 260_000_000);
+
+        account.owner = switchboard_on_demand_internal::devnet::ID;
+        assert_eq!(
+            determine_oracle_type(&account).is_ok(),
+            cfg!(feature = "devnet")
+        );
+        account.owner = switchboard_on_demand_internal::ID;
+
+        // a truncated feed
+        account.data.truncate(8 + 2000);
+        assert!(oracle_state_unchecked(&account)
+            .is_anchor_error_with_code(ErrorCode::AccountDidNotDeserialize.into()));
+
+        // a feed without a result
+        account.data = vec![196, 27, 108, 196, 10, 215, 219, 40];
+        account.data.resize(8 + 3200, 0);
+        assert!(oracle_state_unchecked(&account).is_err());
+    }
+
+    /// A Raydium CLMM pool at 1.0 and its ObservationState, which has the pool at tick 100 from
+    /// 1000 to 1100 seconds and at tick 300 from 1100 to 1200 seconds
+    fn clmm_fixtures() -> (TestAccount, TestAccount) {
+        let observation_key = Pubkey::new_unique();
+
+        let mut pool = PoolState::default();
+        pool.observation_key = observation_key;
+        pool.sqrt_price_x64 = 1 << 64;
+        pool.tick_spacing = 10;
+        pool.liquidity = 1_000_000;
+        let mut pool_data = PoolState::discriminator().to_vec();
+        pool_data.extend(bytemuck::bytes_of(&pool));
+
+        let mut observation_state: ObservationState = bytemuck::Zeroable::zeroed();
+        observation_state.initialized = true;
+        observation_state.observation_index = 2;
+        let mut cumulative = 0i64;
+        for (i, (timestamp, tick)) in [(1000, 100i64), (1100, 100), (1200, 300)]
+            .iter()
+            .enumerate()
+        {
+            if i > 0 {
+                cumulative += tick * 100;
+            }
+            observation_state.observations[i].block_timestamp = *timestamp;
+            observation_state.observations[i].tick_cumulative = cumulative;
+        }
+        let mut observation_data = ObservationState::discriminator().to_vec();
+        observation_data.extend(bytemuck::bytes_of(&observation_state));
+
+        (
+            TestAccount {
+                key: Pubkey::new_unique(),
+                owner: raydium_internal::ID,
+                data: pool_data,
+            },
+            TestAccount {
+                key: observation_key,
+                owner: raydium_internal::ID,
+                data: observation_data,
+            },
+        )
+    }
+
+    #[test]
+    fn test_raydium_clmm_twap() {
+        let (pool, observations) = clmm_fixtures();
+        assert!(is_raydium_observation(&observations));
+        assert!(!is_raydium_observation(&pool));
+
+        let mut config = OracleConfigParams {
+            price_mode: OraclePriceMode::Smoothed,
+            twap_window_secs: 100,
+            ..oracle_config_params()
+        }
+        .to_oracle_config();
+        check_oracle_pair(&pool, &observations, &config).unwrap();
+        // the spot price has no smoothed equivalent without the observations
+        assert!(check_price_mode(OracleType::RaydiumCLMM, &config).is_err());
+
+        let twap = raydium_clmm_state_unchecked(&pool, &observations, &config).unwrap();
+        assert!((twap.price - 1.0001f64.powi(300)).abs() < 1e-9);
+        assert_eq!(
+            twap.decimal_price,
+            DecimalPrice::from_f64(1.0001f64.powi(300)).unwrap()
+        );
+
+        // the mean tick over both periods
+        config.twap_window_secs = 200;
+        let twap = raydium_clmm_state_unchecked(&pool, &observations, &config).unwrap();
+        assert!((twap.price - 1.0001f64.powi(200)).abs() < 1e-9);
+
+        // limited to the available history
+        config.twap_window_secs = 10_000;
+        let twap = raydium_clmm_state_unchecked(&pool, &observations, &config).unwrap();
+        assert!((twap.price - 1.0001f64.powi(200)).abs() < 1e-9);
+
+        // the observations must belong to the pool
+        let (other_pool, _) = clmm_fixtures();
+        assert!(check_oracle_pair(&other_pool, &observations, &config).is_err());
+        assert!(raydium_clmm_state_unchecked(&other_pool, &observations, &config).is_err());
+
+        let spot = oracle_state_unchecked(&pool).unwrap();
+        assert!((spot.price - 1.0).abs() < 1e-9);
+        assert_eq!(spot.decimal_price, DecimalPrice::ONE);
+        assert!((spot.deviation - 0.0010004501).abs() < 1e-9);
+        assert_eq!(spot.last_update_ts, None);
+
+        // the observations provide the timestamp in the spot mode too
+        config.price_mode = OraclePriceMode::Spot.into();
+        check_oracle_pair(&pool, &observations, &config).unwrap();
+        let spot = raydium_clmm_state_unchecked(&pool, &observations, &config).unwrap();
+        assert!((spot.price - 1.0).abs() < 1e-9);
+        assert_eq!(spot.last_update_ts, Some(1200));
+    }
+
+    /// An ObservationState written field by field at the offsets of the raydium-clmm program,
+    /// whose ObservationState::LEN is 4483 bytes
+    #[test]
+    fn test_raydium_observation_layout() {
+        assert_eq!(8 + std::mem::size_of::<ObservationState>(), 4483);
+        assert_eq!(std::mem::size_of::<raydium_internal::Observation>(), 44);
+
+        let pool_id = Pubkey::new_unique();
+        let mut data = ObservationState::discriminator().to_vec();
+        data.resize(4483, 0);
+        data[8] = 1; // initialized
+        data[9..17].copy_from_slice(&700_u64.to_le_bytes()); // recent_epoch
+        data[17..19].copy_from_slice(&99_u16.to_le_bytes()); // observation_index
+        data[19..51].copy_from_slice(pool_id.as_ref()); // pool_id
+        let last = 51 + 99 * 44; // observations[99]
+        data[last..last + 4].copy_from_slice(&1_700_000_000_u32.to_le_bytes());
+        data[last + 4..last + 12].copy_from_slice(&(-123_456_789_i64).to_le_bytes());
+
+        let account = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: raydium_internal::ID,
+            data,
+        };
+        assert!(is_raydium_observation(&account));
+        let state = bytemuck::from_bytes::<ObservationState>(&account.data[8..]);
+        assert!(state.initialized);
+        assert_eq!({ state.recent_epoch }, 700);
+        assert_eq!({ state.observation_index }, 99);
+        assert_eq!({ state.pool_id }, pool_id);
+        let observation = state.observations[99];
+        assert_eq!({ observation.block_timestamp }, 1_700_000_000);
+        assert_eq!({ observation.tick_cumulative }, -123_456_789);
+    }
+
+    #[test]
+    fn test_raydium_clmm_pool_checks() {
+        let (mut pool_acc, _) = clmm_fixtures();
+        let mut pool = *bytemuck::from_bytes::<PoolState>(&pool_acc.data[8..]);
+        let (mint_0, mint_1) = (Pubkey::new_unique(), Pubkey::new_unique());
+        pool.token_mint_0 = mint_0;
+        pool.token_mint_1 = mint_1;
+        pool_acc.data[8..].copy_from_slice(bytemuck::bytes_of(&pool));
+
+        check_raydium_clmm_mints(&pool_acc, &mint_0, &mint_1).unwrap();
+        check_raydium_clmm_mints(&pool_acc, &mint_1, &mint_0).unwrap();
+        assert!(check_raydium_clmm_mints(&pool_acc, &mint_0, &Pubkey::new_unique()).is_err());
+        assert!(!raydium_clmm_is_inverted(&pool_acc, &mint_0, &mint_1));
+        assert!(raydium_clmm_is_inverted(&pool_acc, &mint_1, &mint_0));
+
+        let state = OracleState {
+            price: 4.0,
+            decimal_price: DecimalPrice::new(4, 0),
+            deviation: 0.4,
+            last_update_slot: u64::MAX,
+            last_update_ts: None,
+            oracle_type: OracleType::RaydiumCLMM,
+        }
+        .inverted()
+        .unwrap();
+        assert_eq!(state.price, 0.25);
+        assert_eq!(state.deviation, 0.025);
+        assert_eq!(state.decimal_price, DecimalPrice::new(25, -2));
+
+        let zero = OracleState {
+            price: 0.0,
+            decimal_price: DecimalPrice::ZERO,
+            deviation: 0.0,
+            last_update_slot: u64::MAX,
+            last_update_ts: None,
+            oracle_type: OracleType::RaydiumCLMM,
+        };
+        assert!(zero.inverted().is_err());
+
+        let mut config = OracleConfigParams {
+            clmm_min_liquidity: 1_000_000,
+            ..oracle_config_params()
+        }
+        .to_oracle_config();
+        assert!(raydium_clmm_pool_is_valid(&pool_acc, &config));
+        config.clmm_min_liquidity = 1_000_001;
+        assert!(!raydium_clmm_pool_is_valid(&pool_acc, &config));
+        config.clmm_min_liquidity = 0;
+
+        // swaps disabled
+        pool.status = 1 << 4;
+        pool_acc.data[8..].copy_from_slice(bytemuck::bytes_of(&pool));
+        assert!(!raydium_clmm_pool_is_valid(&pool_acc, &config));
+        // only positions disabled
+        pool.status = 1;
+        pool_acc.data[8..].copy_from_slice(bytemuck::bytes_of(&pool));
+        assert!(raydium_clmm_pool_is_valid(&pool_acc, &config));
+    }
+
+    #[test]
+    fn test_orca_whirlpool() {
+        assert_eq!(8 + std::mem::size_of::<Whirlpool>(), 653);
+
+        let (mint_a, mint_b) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut pool = Whirlpool::default();
+        pool.tick_spacing = 64;
+        pool.liquidity = 1_000_000;
+        // 1 native b per native a
+        pool.sqrt_price = 1 << 64;
+        pool.token_mint_a = mint_a;
+        pool.token_mint_b = mint_b;
+        let mut data = Whirlpool::discriminator().to_vec();
+        data.extend(bytemuck::bytes_of(&pool));
+        let mut account = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: orca_internal::ID,
+            data,
+        };
+
+        assert!(determine_oracle_type(&account).unwrap() == OracleType::OrcaWhirlpool);
+        // the decimals are needed
+        assert!(oracle_state_unchecked(&account).is_err());
+
+        // a with 9 decimals, b with 6
+        let state = orca_whirlpool_state_unchecked(&account, 9, 6).unwrap();
+        assert!((state.price - 1000.0).abs() < 1e-9);
+        assert_eq!(state.decimal_price, DecimalPrice::new(1000, 0));
+        assert!((state.deviation - 1000.0 * (1.0001f64.powi(64) - 1.0)).abs() < 1e-9);
+
+        check_orca_whirlpool_mints(&account, &mint_b, &mint_a).unwrap();
+        assert!(check_orca_whirlpool_mints(&account, &mint_a, &Pubkey::new_unique()).is_err());
+        assert!(!orca_whirlpool_is_inverted(&account, &mint_a, &mint_b));
+        assert!(orca_whirlpool_is_inverted(&account, &mint_b, &mint_a));
+
+        let mut config = OracleConfigParams {
+            clmm_min_liquidity: 1_000_000,
+            ..oracle_config_params()
+        }
+        .to_oracle_config();
+        assert!(orca_whirlpool_is_valid(&account, &config));
+        config.clmm_min_liquidity = 1_000_001;
+        assert!(!orca_whirlpool_is_valid(&account, &config));
+
+        // no a/b ratio of whirlpools
+        let other = TestAccount {
+            key: Pubkey::new_unique(),
+            owner: orca_internal::ID,
+            data: account.data.clone(),
+        };
+        assert!(check_oracle_pair(&account, &other, &config).is_err());
+
+        // other accounts of the whirlpool program are no oracles
+        account.data[0..8].copy_from_slice(&[1; 8]);
+        assert!(determine_oracle_type(&account).is_err());
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let mut config = OracleConfigParams {
+            max_staleness_secs: Some(60),
+            ..oracle_config_params()
+        }
+        .to_oracle_config();
+        let mut state = OracleState {
+            price: 1.0,
+            decimal_price: DecimalPrice::ONE,
+            deviation: 0.0,
+            last_update_slot: u64::MAX,
+            last_update_ts: Some(1000),
+            oracle_type: OracleType::Stub,
+        };
+        let key = Pubkey::new_unique();
+
+        assert!(!state.is_stale(&key, &config, 100, 1060));
+        assert!(state.is_stale(&key, &config, 100, 1061));
+
+        // without a timestamp, freshness can't be established
+        state.last_update_ts = None;
+        assert!(state.is_stale(&key, &config, 100, 1000));
+
+        config.max_staleness_secs = 0;
+        assert!(!state.is_stale(&key, &config, 100, 1_000_000));
+        config.max_staleness_secs = -1;
+        assert!(!state.is_stale(&key, &config, 100, 1_000_000));
+
+        // slot based staleness still applies
+        config.max_staleness_slots = 10;
+        state.last_update_slot = 50;
+        assert!(!state.is_stale(&key, &config, 60, 0));
+        assert!(state.is_stale(&key, &config, 61, 0));
+    }
+
+    #[test]
+    fn test_is_stale_baseline_config() {
+        // conf_filter, max_staleness_slots and 72 zeroed reserved bytes
+        let mut bytes = [0u8; 88];
+        bytes[0..8].copy_from_slice(&0.1f64.to_le_bytes());
+        bytes[8..16].copy_from_slice(&(-1i64).to_le_bytes());
+        let config: OracleConfig = bytemuck::pod_read_unaligned(&bytes);
+        assert_eq!(config.max_staleness_secs, 0);
+
+        let state = OracleState {
+            price: 1.0,
+            decimal_price: DecimalPrice::ONE,
+            deviation: 0.0,
+            last_update_slot: 0,
+            last_update_ts: None,
+            oracle_type: OracleType::Stub,
+        };
+        assert!(!state.is_stale(&Pubkey::new_unique(), &config, 1_000, 1_000_000));
+    }
+
+    #[test]
+    fn test_oracle_config_corrupt_bytes() {
+        let mut config: OracleConfig = bytemuck::Zeroable::zeroed();
+        assert_eq!(config.price_mode().unwrap(), OraclePriceMode::Spot);
+        assert_eq!(config.combination().unwrap(), OracleCombination::Ratio);
+        assert_eq!(config.book_price_method().unwrap(), BookPriceMethod::Mid);
+
+        let is_corrupt = |result: Result<()>| {
+            result
+                .unwrap_err()
+                .is_anchor_error_with_code(OpenBookError::InvalidOracleConfig.error_code())
+        };
+        config.price_mode = 2;
+        config.combination = 2;
+        config.book_price_method = 3;
+        assert!(is_corrupt(config.price_mode().map(|_| ())));
+        assert!(is_corrupt(config.combination().map(|_| ())));
+        assert!(is_corrupt(config.book_price_method().map(|_| ())));
+        assert!(is_corrupt(check_price_mode(OracleType::Pyth, &config)));
+    }
+
+    #[test]
+    fn test_power_of_ten_float() {
+        assert_eq!(power_of_ten_float(0), 1.0);
+        assert_eq!(power_of_ten_float(-12), 1e-12);
+        assert_eq!(power_of_ten_float(12), 1e12);
+        // beyond the table
+        assert!((power_of_ten_float(-18) / 1e-18 - 1.0).abs() < 1e-12);
+        assert!((power_of_ten_float(20) / 1e20 - 1.0).abs() < 1e-12);
+        // exponents that don't fit in an i8
+        assert!((power_of_ten_float(-200) / 1e-200 - 1.0).abs() < 1e-12);
+        assert!((power_of_ten_float(200) / 1e200 - 1.0).abs() < 1e-12);
+        assert_eq!(power_of_ten_float(i32::MIN), 0.0);
+        assert_eq!(power_of_ten_float(i32::MAX), f64::INFINITY);
+    }
+}
@@ -0,0 +1,70 @@
+use anchor_lang::{account, declare_id, zero_copy};
+use solana_program::pubkey::Pubkey;
+
+declare_id!("whirLbMiicVdio4qvUfM5KAg6Ct8VwpYzGff3uctyCc");
+
+pub const NUM_REWARDS: usize = 3;
+
+/// A concentrated liquidity pool
+///
+/// PDA of `["whirlpool", whirlpools_config, token_mint_a, token_mint_b, tick_spacing]`
+#[account(zero_copy(unsafe))]
+#[repr(C, packed)]
+#[derive(Default, Debug)]
+#[allow(dead_code)]
+pub struct Whirlpool {
+    pub whirlpools_config: Pubkey,
+    pub whirlpool_bump: [u8; 1],
+
+    pub tick_spacing: u16,
+    pub tick_spacing_seed: [u8; 2],
+
+    /// Stored as hundredths of a basis point
+    pub fee_rate: u16,
+    /// Portion of fee rate taken stored as basis points
+    pub protocol_fee_rate: u16,
+
+    /// Maximum amount that can be held by Solana account
+    pub liquidity: u128,
+
+    /// The current price of the pool as a sqrt(token_b/token_a) Q64.64 value
+    pub sqrt_price: u128,
+    pub tick_current_index: i32,
+
+    pub protocol_fee_owed_a: u64,
+    pub protocol_fee_owed_b: u64,
+
+    /// Token pair of the pool, where token_mint_a address < token_mint_b address
+    pub token_mint_a: Pubkey,
+    pub token_vault_a: Pubkey,
+
+    /// Q64.64
+    pub fee_growth_global_a: u128,
+
+    pub token_mint_b: Pubkey,
+    pub token_vault_b: Pubkey,
+
+    /// Q64.64
+    pub fee_growth_global_b: u128,
+
+    pub reward_last_updated_timestamp: u64,
+
+    pub reward_infos: [WhirlpoolRewardInfo; NUM_REWARDS],
+}
+
+#[zero_copy(unsafe)]
+#[repr(C, packed)]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct WhirlpoolRewardInfo {
+    /// Reward token mint.
+    pub mint: Pubkey,
+    /// Reward vault token account.
+    pub vault: Pubkey,
+    /// Authority account that has permission to initialize the reward and set emissions.
+    pub authority: Pubkey,
+    /// Q64.64 number that indicates how many tokens per second are earned per unit of liquidity.
+    pub emissions_per_second_x64: u128,
+    /// Q64.64 number that tracks the total tokens earned per unit of liquidity since the reward
+    /// emissions were turned on.
+    pub growth_global_x64: u128,
+}
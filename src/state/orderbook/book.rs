@@ -7,6 +7,7 @@ use crate::{
 use anchor_lang::prelude::*;
 use bytemuck::cast;
 use std::cell::RefMut;
+use std::collections::HashMap;
 
 use super::*;
 
@@ -22,6 +23,17 @@ pub struct Orderbook<'a> {
     pub asks: RefMut<'a, BookSide>,
 }
 
+/// The book sides of an Orderbook, borrowed for reading
+///
+/// Quotes off chain can use BookSideRef or deserialized BookSide accounts with this, without
+/// the mutable borrows an Orderbook holds.
+#[derive(Clone, Copy)]
+pub struct OrderbookRef<'a> {
+    pub bids: &'a BookSide,
+    pub asks: &'a BookSide,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OrderWithAmounts {
     pub order_id: Option<u128>,
     pub posted_base_native: u64,
@@ -33,6 +45,45 @@ pub struct OrderWithAmounts {
     pub referrer_amount: u64,
 }
 
+/// Result of OrderbookRef::simulate_order()
+pub struct SimulatedOrder {
+    pub amounts: OrderWithAmounts,
+    /// The fills against makers, best price first
+    pub fills: Vec<FillEvent>,
+    /// Expired orders on the opposing side that would be dropped while matching and the
+    /// order that would be dropped to make room for posting the remainder
+    pub outs: Vec<OutEvent>,
+}
+
+/// An order on the opposing side reached while matching, in book order
+enum MatchedOrder {
+    /// An expired order, dropped from the book
+    Expired(OutEvent),
+    /// An order of the taker, canceled due to SelfTradeBehavior::CancelProvide
+    SelfTrade {
+        owner_slot: u8,
+        quantity: i64,
+    },
+    Filled(FillEvent),
+}
+
+/// Result of Orderbook::match_order(), the changes new_order() makes
+struct OrderMatch {
+    amounts: OrderWithAmounts,
+    price_data: u64,
+    post_target: Option<BookSideOrderTree>,
+    post_base_lots: i64,
+    /// The price the quote of a posted bid is locked at
+    post_price_lots: i64,
+    /// The expired order dropped from the book before posting
+    expired_order: Option<(BookSideOrderTree, LeafNode)>,
+    /// The order dropped if the book is still full after dropping the expired order
+    worst_order: Option<(BookSideOrderTree, LeafNode)>,
+    matched_orders: Vec<MatchedOrder>,
+    matched_order_changes: Vec<(BookSideOrderHandle, i64)>,
+    matched_order_deletes: Vec<(BookSideOrderTree, u128)>,
+}
+
 impl Orderbook<'_> {
     pub fn init(&mut self) {
         self.bids.nodes.order_tree_type = OrderTreeType::Bids.into();
@@ -58,7 +109,7 @@ impl Orderbook<'_> {
     }
 
     #[allow(clippy::too_many_arguments)]
-    pub fn new_order<'c: 'info, 'info>(
+    pub fn new_order(
         &mut self,
         order: &Order,
         open_book_market: &mut Market,
@@ -68,11 +119,302 @@ impl Orderbook<'_> {
         mut open_orders_account: Option<&mut OpenOrdersAccount>,
         owner: &Pubkey,
         now_ts: u64,
-        mut limit: u8,
-        remaining_accs: &'c [AccountInfo<'info>],
+        limit: u8,
+        mut makers: impl MakerAccountResolver,
     ) -> std::result::Result<OrderWithAmounts, Error> {
         let market = open_book_market;
 
+        let side = order.side;
+        let other_side = side.invert_side();
+
+        let matched = self.view().match_order(
+            order,
+            market,
+            now_ts,
+            oracle_price_lots,
+            owner,
+            open_orders_account.is_some(),
+            limit,
+        )?;
+        let amounts = matched.amounts;
+
+        // generate new order id, the fills already carry its seq_num
+        let order_id = market.gen_order_id(side, matched.price_data);
+
+        let mut number_of_processed_fill_events = 0;
+        for matched_order in matched.matched_orders {
+            match matched_order {
+                MatchedOrder::Expired(mut event) => {
+                    event.seq_num = event_heap.header.seq_num;
+                    process_out_event(
+                        event,
+                        market,
+                        event_heap,
+                        open_orders_account.as_deref_mut(),
+                        owner,
+                        &mut makers,
+                    )?;
+                }
+                MatchedOrder::SelfTrade {
+                    owner_slot,
+                    quantity,
+                } => {
+                    // The open orders acc is always present in this case, no need event_heap
+                    open_orders_account.as_mut().unwrap().cancel_order(
+                        owner_slot as usize,
+                        quantity,
+                        *market,
+                    );
+                }
+                MatchedOrder::Filled(fill) => {
+                    emit_stack(TakerSignatureLog {
+                        market: *market_pk,
+                        seq_num: market.seq_num,
+                    });
+
+                    process_fill_event(
+                        fill,
+                        market,
+                        event_heap,
+                        &mut makers,
+                        &mut number_of_processed_fill_events,
+                    )?;
+                }
+            }
+        }
+
+        // Record the taker trade in the account already, even though it will only be
+        // realized when the fill event gets executed
+        if amounts.total_quote_taken_native > 0 || amounts.total_base_taken_native > 0 {
+            // Only account taker fees now. Maker fees accounted once processing the event
+            market.fees_accrued += amounts.referrer_amount as u128;
+
+            if let Some(open_orders_account) = &mut open_orders_account {
+                open_orders_account.execute_taker(
+                    market,
+                    side,
+                    amounts.total_base_taken_native,
+                    amounts.total_quote_taken_native,
+                    amounts.taker_fees,
+                    amounts.referrer_amount,
+                );
+            } else {
+                market.taker_volume_wo_oo += amounts.total_quote_taken_native as u128;
+            }
+
+            let (total_quantity_paid, total_quantity_received) = match side {
+                Side::Bid => (
+                    amounts.total_quote_taken_native + amounts.taker_fees,
+                    amounts.total_base_taken_native,
+                ),
+                Side::Ask => (
+                    amounts.total_base_taken_native,
+                    amounts.total_quote_taken_native - amounts.taker_fees,
+                ),
+            };
+
+            emit_stack(TotalOrderFillEvent {
+                side: side.into(),
+                taker: *owner,
+                total_quantity_paid,
+                total_quantity_received,
+                fees: amounts.taker_fees,
+            });
+        }
+
+        // Apply changes to matched asks (handles invalidate on delete!)
+        let opposing_bookside = self.bookside_mut(other_side);
+        for (handle, new_quantity) in matched.matched_order_changes {
+            opposing_bookside
+                .node_mut(handle.node)
+                .unwrap()
+                .as_leaf_mut()
+                .unwrap()
+                .quantity = new_quantity;
+        }
+        for (component, key) in matched.matched_order_deletes {
+            let _removed_leaf = opposing_bookside.remove_by_key(component, key).unwrap();
+        }
+
+        //
+        // Place remainder on the book if requested
+        //
+
+        if let Some(order_tree_target) = matched.post_target {
+            // Open orders always exists in this case
+            let open_orders = open_orders_account.as_mut().unwrap();
+
+            // Subtract maker fees in bid.
+            if side == Side::Bid {
+                open_orders.position.locked_maker_fees += amounts.maker_fees;
+            }
+
+            let bookside = self.bookside_mut(side);
+            // Drop an expired order if possible
+            if let Some((order_tree, expired_order)) = matched.expired_order {
+                bookside
+                    .remove_by_key(order_tree, expired_order.key)
+                    .unwrap();
+                let event = OutEvent::new(
+                    side,
+                    expired_order.owner_slot,
+                    now_ts,
+                    event_heap.header.seq_num,
+                    expired_order.owner,
+                    expired_order.quantity,
+                );
+                process_out_event(
+                    event,
+                    market,
+                    event_heap,
+                    Some(open_orders),
+                    owner,
+                    &mut makers,
+                )?;
+            }
+
+            if bookside.is_full() {
+                // match_order() checked that this order is better than the worst one
+                let (order_tree, worst_order) = matched.worst_order.unwrap();
+                bookside.remove_by_key(order_tree, worst_order.key).unwrap();
+                let event = OutEvent::new(
+                    side,
+                    worst_order.owner_slot,
+                    now_ts,
+                    event_heap.header.seq_num,
+                    worst_order.owner,
+                    worst_order.quantity,
+                );
+                process_out_event(
+                    event,
+                    market,
+                    event_heap,
+                    Some(open_orders),
+                    owner,
+                    &mut makers,
+                )?;
+            }
+
+            let owner_slot = open_orders.next_order_slot()?;
+            let new_order = LeafNode::new(
+                owner_slot as u8,
+                order_id,
+                *owner,
+                matched.post_base_lots,
+                now_ts,
+                order.time_in_force,
+                order.peg_limit(),
+                order.client_order_id,
+            );
+            let _result = bookside.insert_leaf(order_tree_target, &new_order)?;
+
+            open_orders.add_order(
+                side,
+                order_tree_target,
+                &new_order,
+                order.client_order_id,
+                matched.post_price_lots,
+            );
+        }
+
+        Ok(amounts)
+    }
+
+    /// Borrow the book sides for reading
+    pub fn view(&self) -> OrderbookRef<'_> {
+        OrderbookRef {
+            bids: &self.bids,
+            asks: &self.asks,
+        }
+    }
+
+    /// See OrderbookRef::simulate_order()
+    pub fn simulate_order(
+        &self,
+        order: &Order,
+        market: &Market,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+        limit: u8,
+    ) -> Result<SimulatedOrder> {
+        self.view()
+            .simulate_order(order, market, now_ts, oracle_price_lots, limit)
+    }
+}
+
+impl<'a> OrderbookRef<'a> {
+    pub fn bookside(&self, side: Side) -> &'a BookSide {
+        match side {
+            Side::Bid => self.bids,
+            Side::Ask => self.asks,
+        }
+    }
+
+    /// Compute the outcome of `new_order()` for `order` without changing the book or the market
+    ///
+    /// The order is treated like one without an open orders account, as in place_take_order,
+    /// and fills name the default pubkey as taker. So self trades aren't simulated: orders of
+    /// the taker's own account fill like any other, whatever its SelfTradeBehavior. The seq_num
+    /// of the out events is left at zero since it depends on the event heap.
+    pub fn simulate_order(
+        &self,
+        order: &Order,
+        market: &Market,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+        limit: u8,
+    ) -> Result<SimulatedOrder> {
+        let matched = self.match_order(
+            order,
+            market,
+            now_ts,
+            oracle_price_lots,
+            &Pubkey::default(),
+            false,
+            limit,
+        )?;
+
+        let mut fills = vec![];
+        let mut outs = vec![];
+        for matched_order in matched.matched_orders {
+            match matched_order {
+                MatchedOrder::Expired(event) => outs.push(event),
+                MatchedOrder::Filled(fill) => fills.push(fill),
+                MatchedOrder::SelfTrade { .. } => unreachable!(),
+            }
+        }
+        for (_, dropped_order) in matched.expired_order.iter().chain(&matched.worst_order) {
+            outs.push(OutEvent::new(
+                order.side,
+                dropped_order.owner_slot,
+                now_ts,
+                0,
+                dropped_order.owner,
+                dropped_order.quantity,
+            ));
+        }
+
+        Ok(SimulatedOrder {
+            amounts: matched.amounts,
+            fills,
+            outs,
+        })
+    }
+
+    /// Match `order` against the book, the part of `new_order()` that only reads state
+    ///
+    /// Self trades are only handled for takers with an open orders account.
+    #[allow(clippy::too_many_arguments)]
+    fn match_order(
+        &self,
+        order: &Order,
+        market: &Market,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+        owner: &Pubkey,
+        has_open_orders_account: bool,
+        mut limit: u8,
+    ) -> Result<OrderMatch> {
         let side = order.side;
 
         let other_side = side.invert_side();
@@ -81,13 +423,14 @@ impl Orderbook<'_> {
         let mut post_target = order.post_target();
         let (price_lots, price_data) = order.price(now_ts, oracle_price_lots, self)?;
 
-        // generate new order id
-        let order_id = market.gen_order_id(side, price_data);
+        // new_order() increments the market's seq_num for the order id before matching
+        let seq_num = market.seq_num + 1;
+        let order_id = new_node_key(side, price_data, seq_num);
 
         // Iterate through book and match against this new order.
         //
         // Any changes to matching orders on the other side of the book are collected in
-        // matched_changes/matched_deletes and then applied after this loop.
+        // matched_changes/matched_deletes and then applied by new_order().
 
         let order_max_base_lots = order.max_base_lots;
         let order_max_quote_lots = if side == Side::Bid && !post_only {
@@ -115,12 +458,12 @@ impl Orderbook<'_> {
         let mut referrer_amount = 0_u64;
         let mut maker_rebates_acc = 0_u64;
 
+        let mut matched_orders: Vec<MatchedOrder> = vec![];
         let mut matched_order_changes: Vec<(BookSideOrderHandle, i64)> = vec![];
         let mut matched_order_deletes: Vec<(BookSideOrderTree, u128)> = vec![];
         let mut number_of_dropped_expired_orders = 0;
-        let mut number_of_processed_fill_events = 0;
 
-        let opposing_bookside = self.bookside_mut(other_side);
+        let opposing_bookside = self.bookside(other_side);
         for best_opposing in opposing_bookside.iter_all_including_invalid(now_ts, oracle_price_lots)
         {
             if remaining_base_lots == 0 || remaining_quote_lots == 0 {
@@ -131,25 +474,19 @@ impl Orderbook<'_> {
                 // Remove the order from the book unless we've done that enough
                 if number_of_dropped_expired_orders < DROP_EXPIRED_ORDER_LIMIT {
                     number_of_dropped_expired_orders += 1;
-                    let event = OutEvent::new(
+                    // new_order() fills in the seq_num of the event heap
+                    matched_orders.push(MatchedOrder::Expired(OutEvent::new(
                         other_side,
                         best_opposing.node.owner_slot,
                         now_ts,
-                        event_heap.header.seq_num,
+                        0,
                         best_opposing.node.owner,
                         best_opposing.node.quantity,
-                    );
-
-                    process_out_event(
-                        event,
-                        market,
-                        event_heap,
-                        open_orders_account.as_deref_mut(),
-                        owner,
-                        remaining_accs,
-                    )?;
-                    matched_order_deletes
-                        .push((best_opposing.handle.order_tree, best_opposing.node.key.into()));
+                    )));
+                    matched_order_deletes.push((
+                        best_opposing.handle.order_tree,
+                        best_opposing.node.key.into(),
+                    ));
                 }
                 continue;
             }
@@ -183,21 +520,21 @@ impl Orderbook<'_> {
             let match_quote_lots = match_base_lots * best_opposing_price;
 
             // Self-trade behaviour
-            if open_orders_account.is_some() && owner == &best_opposing.node.owner {
+            if has_open_orders_account && owner == &best_opposing.node.owner {
                 match order.self_trade_behavior {
                     SelfTradeBehavior::DecrementTake => {
                         // remember all decremented quote lots to only charge fees on not-self-trades
                         decremented_quote_lots += match_quote_lots;
                     }
                     SelfTradeBehavior::CancelProvide => {
-                        // The open orders acc is always present in this case, no need event_heap
-                        open_orders_account.as_mut().unwrap().cancel_order(
-                            best_opposing.node.owner_slot as usize,
-                            best_opposing.node.quantity,
-                            *market,
-                        );
-                        matched_order_deletes
-                            .push((best_opposing.handle.order_tree, best_opposing.node.key.into()));
+                        matched_orders.push(MatchedOrder::SelfTrade {
+                            owner_slot: best_opposing.node.owner_slot,
+                            quantity: best_opposing.node.quantity,
+                        });
+                        matched_order_deletes.push((
+                            best_opposing.handle.order_tree,
+                            best_opposing.node.key.into(),
+                        ));
 
                         // skip actual matching
                         continue;
@@ -219,18 +556,20 @@ impl Orderbook<'_> {
             let new_best_opposing_quantity = best_opposing.node.quantity - match_base_lots;
             let maker_out = new_best_opposing_quantity == 0;
             if maker_out {
-                matched_order_deletes
-                    .push((best_opposing.handle.order_tree, best_opposing.node.key.into()));
+                matched_order_deletes.push((
+                    best_opposing.handle.order_tree,
+                    best_opposing.node.key.into(),
+                ));
             } else {
                 matched_order_changes.push((best_opposing.handle, new_best_opposing_quantity));
             }
 
-            let fill = FillEvent::new(
+            matched_orders.push(MatchedOrder::Filled(FillEvent::new(
                 side,
                 maker_out,
                 best_opposing.node.owner_slot,
                 now_ts,
-                market.seq_num,
+                seq_num,
                 best_opposing.node.owner,
                 best_opposing.node.client_order_id,
                 best_opposing.node.timestamp,
@@ -239,20 +578,7 @@ impl Orderbook<'_> {
                 best_opposing_price,
                 best_opposing.node.peg_limit,
                 match_base_lots,
-            );
-
-            emit_stack(TakerSignatureLog {
-                market: *market_pk,
-                seq_num: market.seq_num,
-            });
-
-            process_fill_event(
-                fill,
-                market,
-                event_heap,
-                remaining_accs,
-                &mut number_of_processed_fill_events,
-            )?;
+            )));
 
             limit -= 1;
         }
@@ -265,8 +591,6 @@ impl Orderbook<'_> {
         let total_base_taken_native = (total_base_lots_taken * market.base_lot_size) as u64;
         let total_quote_taken_native = (total_quote_lots_taken * market.quote_lot_size) as u64;
 
-        // Record the taker trade in the account already, even though it will only be
-        // realized when the fill event gets executed
         let mut taker_fees_native = 0_u64;
         if total_quote_lots_taken > 0 || total_base_lots_taken > 0 {
             let total_quote_taken_native_wo_self =
@@ -274,43 +598,8 @@ impl Orderbook<'_> {
 
             if total_quote_taken_native_wo_self > 0 {
                 taker_fees_native = market.taker_fees_ceil(total_quote_taken_native_wo_self);
-
-                // Only account taker fees now. Maker fees accounted once processing the event
                 referrer_amount = taker_fees_native - maker_rebates_acc;
-                market.fees_accrued += referrer_amount as u128;
             };
-
-            if let Some(open_orders_account) = &mut open_orders_account {
-                open_orders_account.execute_taker(
-                    market,
-                    side,
-                    total_base_taken_native,
-                    total_quote_taken_native,
-                    taker_fees_native,
-                    referrer_amount,
-                );
-            } else {
-                market.taker_volume_wo_oo += total_quote_taken_native as u128;
-            }
-
-            let (total_quantity_paid, total_quantity_received) = match side {
-                Side::Bid => (
-                    total_quote_taken_native + taker_fees_native,
-                    total_base_taken_native,
-                ),
-                Side::Ask => (
-                    total_base_taken_native,
-                    total_quote_taken_native - taker_fees_native,
-                ),
-            };
-
-            emit_stack(TotalOrderFillEvent {
-                side: side.into(),
-                taker: *owner,
-                total_quantity_paid,
-                total_quantity_received,
-                fees: taker_fees_native,
-            });
         }
 
         // The native taker fees in lots, rounded up.
@@ -325,19 +614,6 @@ impl Orderbook<'_> {
         remaining_quote_lots =
             order.max_quote_lots_including_fees - total_quote_lots_taken - taker_fees_lots;
 
-        // Apply changes to matched asks (handles invalidate on delete!)
-        for (handle, new_quantity) in matched_order_changes {
-            opposing_bookside
-                .node_mut(handle.node)
-                .unwrap()
-                .as_leaf_mut()
-                .unwrap()
-                .quantity = new_quantity;
-        }
-        for (component, key) in matched_order_deletes {
-            let _removed_leaf = opposing_bookside.remove_by_key(component, key).unwrap();
-        }
-
         //
         // Place remainder on the book if requested
         //
@@ -378,6 +654,8 @@ impl Orderbook<'_> {
         let mut maker_fees_native = 0;
         let mut posted_base_native = 0;
         let mut posted_quote_native = 0;
+        let mut expired_order = None;
+        let mut worst_order = None;
 
         if let Some(order_tree_target) = post_target {
             require_gte!(
@@ -389,87 +667,40 @@ impl Orderbook<'_> {
             posted_base_native = book_base_quantity_lots * market.base_lot_size;
             posted_quote_native = book_base_quantity_lots * price * market.quote_lot_size;
 
-            // Open orders always exists in this case
-            let open_orders = open_orders_account.as_mut().unwrap();
-
             // Subtract maker fees in bid.
             if side == Side::Bid {
                 maker_fees_native = market
                     .maker_fees_ceil(posted_quote_native)
                     .try_into()
                     .unwrap();
-
-                open_orders.position.locked_maker_fees += maker_fees_native;
             }
 
-            let bookside = self.bookside_mut(side);
             // Drop an expired order if possible
-            if let Some(expired_order) = bookside.remove_one_expired(order_tree_target, now_ts) {
-                let event = OutEvent::new(
-                    side,
-                    expired_order.owner_slot,
-                    now_ts,
-                    event_heap.header.seq_num,
-                    expired_order.owner,
-                    expired_order.quantity,
-                );
-                process_out_event(
-                    event,
-                    market,
-                    event_heap,
-                    Some(open_orders),
-                    owner,
-                    remaining_accs,
-                )?;
-            }
-
-            if bookside.is_full() {
+            let bookside = self.bookside(side);
+            expired_order = bookside
+                .find_one_expired(order_tree_target, now_ts)
+                .map(|(order_tree, expired_order)| (order_tree, *expired_order));
+
+            // Dropping the expired order doesn't make room if it's the only one in its order
+            // tree. Then that tree is empty and the worst order is in the other one.
+            let (is_full, emptied_order_tree) = match expired_order {
+                Some((order_tree, _)) => {
+                    (bookside.is_full_after_remove(order_tree), Some(order_tree))
+                }
+                None => (bookside.is_full(), None),
+            };
+            if is_full {
                 // If this bid is higher than lowest bid, boot that bid and insert this one
-                let (worst_order, worst_price) =
-                    bookside.remove_worst(now_ts, oracle_price_lots).unwrap();
+                let worst = bookside
+                    .find_worst_without(emptied_order_tree, now_ts, oracle_price_lots)
+                    .unwrap();
                 // OpenBookErrorCode::OutOfSpace
                 require!(
-                    side.is_price_better(price_lots, worst_price),
+                    side.is_price_better(price_lots, worst.price_lots),
                     OpenBookError::SomeError
                 );
-                let event = OutEvent::new(
-                    side,
-                    worst_order.owner_slot,
-                    now_ts,
-                    event_heap.header.seq_num,
-                    worst_order.owner,
-                    worst_order.quantity,
-                );
-                process_out_event(
-                    event,
-                    market,
-                    event_heap,
-                    Some(open_orders),
-                    owner,
-                    remaining_accs,
-                )?;
+                worst_order = Some((worst.handle.order_tree, *worst.node));
             }
-
-            let owner_slot = open_orders.next_order_slot()?;
-            let new_order = LeafNode::new(
-                owner_slot as u8,
-                order_id,
-                *owner,
-                book_base_quantity_lots,
-                now_ts,
-                order.time_in_force,
-                order.peg_limit(),
-                order.client_order_id,
-            );
-            let _result = bookside.insert_leaf(order_tree_target, &new_order)?;
-
-            open_orders.add_order(
-                side,
-                order_tree_target,
-                &new_order,
-                order.client_order_id,
-                price,
-            );
         }
 
         let placed_order_id = if post_target.is_some() {
@@ -478,18 +709,31 @@ impl Orderbook<'_> {
             None
         };
 
-        Ok(OrderWithAmounts {
-            order_id: placed_order_id,
-            posted_base_native: posted_base_native as u64,
-            posted_quote_native: posted_quote_native as u64,
-            total_base_taken_native,
-            total_quote_taken_native,
-            referrer_amount,
-            taker_fees: taker_fees_native,
-            maker_fees: maker_fees_native,
+        Ok(OrderMatch {
+            amounts: OrderWithAmounts {
+                order_id: placed_order_id,
+                posted_base_native: posted_base_native as u64,
+                posted_quote_native: posted_quote_native as u64,
+                total_base_taken_native,
+                total_quote_taken_native,
+                referrer_amount,
+                taker_fees: taker_fees_native,
+                maker_fees: maker_fees_native,
+            },
+            price_data,
+            post_target,
+            post_base_lots: book_base_quantity_lots,
+            post_price_lots: price,
+            expired_order,
+            worst_order,
+            matched_orders,
+            matched_order_changes,
+            matched_order_deletes,
         })
     }
+}
 
+impl Orderbook<'_> {
     /// Cancels up to `limit` orders that are listed on the openorders account for the given market.
     /// Optionally filters by `side_to_cancel_option`.
     /// The orders are removed from the book and from the openorders account open order list.
@@ -577,13 +821,64 @@ impl Orderbook<'_> {
     }
 }
 
-pub fn process_out_event<'c: 'info, 'info>(
+/// Access to the OpenOrdersAccounts of makers while matching
+///
+/// Fills and outs for makers that can be resolved are applied immediately, all others are
+/// pushed to the event heap.
+pub trait MakerAccountResolver {
+    /// Call `f` with the OpenOrdersAccount at `key`, None if the account isn't available
+    fn with_maker<R>(
+        &mut self,
+        key: &Pubkey,
+        f: impl FnOnce(&mut OpenOrdersAccount) -> R,
+    ) -> Result<Option<R>>;
+}
+
+impl<T: MakerAccountResolver + ?Sized> MakerAccountResolver for &mut T {
+    fn with_maker<R>(
+        &mut self,
+        key: &Pubkey,
+        f: impl FnOnce(&mut OpenOrdersAccount) -> R,
+    ) -> Result<Option<R>> {
+        (**self).with_maker(key, f)
+    }
+}
+
+/// Makers passed as remaining accounts of an instruction
+impl<'c: 'info, 'info> MakerAccountResolver for &'c [AccountInfo<'info>] {
+    fn with_maker<R>(
+        &mut self,
+        key: &Pubkey,
+        f: impl FnOnce(&mut OpenOrdersAccount) -> R,
+    ) -> Result<Option<R>> {
+        let remaining_accs: &'c [AccountInfo<'info>] = self;
+        if let Some(acc) = remaining_accs.iter().find(|ai| ai.key == key) {
+            let ooa: AccountLoader<OpenOrdersAccount> = AccountLoader::try_from(acc)?;
+            let mut acc = ooa.load_mut()?;
+            Ok(Some(f(&mut acc)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl MakerAccountResolver for HashMap<Pubkey, OpenOrdersAccount> {
+    fn with_maker<R>(
+        &mut self,
+        key: &Pubkey,
+        f: impl FnOnce(&mut OpenOrdersAccount) -> R,
+    ) -> Result<Option<R>> {
+        Ok(self.get_mut(key).map(f))
+    }
+}
+
+pub fn process_out_event(
     event: OutEvent,
     market: &Market,
     event_heap: &mut EventHeap,
     open_orders_account: Option<&mut OpenOrdersAccount>,
     owner: &Pubkey,
-    remaining_accs: &'c [AccountInfo<'info>],
+    makers: &mut impl MakerAccountResolver,
 ) -> Result<()> {
     if let Some(acc) = open_orders_account {
         if owner == &event.owner {
@@ -592,30 +887,28 @@ pub fn process_out_event<'c: 'info, 'info>(
         }
     }
 
-    if let Some(acc) = remaining_accs.iter().find(|ai| ai.key == &event.owner) {
-        let ooa: AccountLoader<OpenOrdersAccount> = AccountLoader::try_from(acc)?;
-        let mut acc = ooa.load_mut()?;
-        acc.cancel_order(event.owner_slot as usize, event.quantity, *market);
-    } else {
+    let processed = makers.with_maker(&event.owner, |acc| {
+        acc.cancel_order(event.owner_slot as usize, event.quantity, *market)
+    })?;
+    if processed.is_none() {
         event_heap.push_back(cast(event));
     }
 
     Ok(())
 }
 
-pub fn process_fill_event<'c: 'info, 'info>(
+pub fn process_fill_event(
     event: FillEvent,
     market: &mut Market,
     event_heap: &mut EventHeap,
-    remaining_accs: &'c [AccountInfo<'info>],
+    makers: &mut impl MakerAccountResolver,
     number_of_processed_fill_events: &mut usize,
 ) -> Result<()> {
     let mut is_processed = false;
     if *number_of_processed_fill_events < FILL_EVENT_REMAINING_LIMIT {
-        if let Some(acc) = remaining_accs.iter().find(|ai| ai.key == &event.maker) {
-            let ooa: AccountLoader<OpenOrdersAccount> = AccountLoader::try_from(acc)?;
-            let mut maker = ooa.load_mut()?;
-            maker.execute_maker(market, &event);
+        let processed =
+            makers.with_maker(&event.maker, |maker| maker.execute_maker(market, &event))?;
+        if processed.is_some() {
             is_processed = true;
             *number_of_processed_fill_events += 1;
         }
@@ -627,3 +920,377 @@ pub fn process_fill_event<'c: 'info, 'info>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::Discriminator;
+    use bytemuck::Zeroable;
+    use std::cell::RefCell;
+
+    fn new_bookside(order_tree_type: OrderTreeType) -> RefCell<Box<BookSide>> {
+        RefCell::new(Box::new(BookSide {
+            roots: [OrderTreeRoot::zeroed(); 2],
+            reserved_roots: [OrderTreeRoot::zeroed(); 4],
+            reserved: [0; 256],
+            nodes: OrderTreeNodes {
+                order_tree_type: order_tree_type.into(),
+                padding: [0; 3],
+                bump_index: 0,
+                free_list_len: 0,
+                free_list_head: 0,
+                reserved: [0; 512],
+                nodes: [AnyNode::zeroed(); MAX_ORDERTREE_NODES],
+            },
+        }))
+    }
+
+    fn orderbook<'a>(
+        bids: &'a RefCell<Box<BookSide>>,
+        asks: &'a RefCell<Box<BookSide>>,
+    ) -> Orderbook<'a> {
+        Orderbook {
+            bids: RefMut::map(bids.borrow_mut(), |bids| bids.as_mut()),
+            asks: RefMut::map(asks.borrow_mut(), |asks| asks.as_mut()),
+        }
+    }
+
+    fn order(side: Side, max_base_lots: i64, params: OrderParams) -> Order {
+        Order {
+            side,
+            max_base_lots,
+            max_quote_lots_including_fees: 1_000_000,
+            client_order_id: 0,
+            time_in_force: 0,
+            self_trade_behavior: SelfTradeBehavior::default(),
+            params,
+        }
+    }
+
+    #[test]
+    fn test_simulate_order_matches_execution() {
+        let now_ts = 1_700_000_000;
+        let market_pk = Pubkey::new_unique();
+        let mut market = Market::zeroed();
+        market.quote_lot_size = 10;
+        market.base_lot_size = 100;
+        market.maker_fee = -200;
+        market.taker_fee = 400;
+        let mut event_heap = EventHeap::zeroed();
+        event_heap.init();
+
+        let bids = new_bookside(OrderTreeType::Bids);
+        let asks = new_bookside(OrderTreeType::Asks);
+        let mut book = orderbook(&bids, &asks);
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = OpenOrdersAccount::default_for_tests();
+        for price_lots in [1000, 990] {
+            book.new_order(
+                &order(
+                    Side::Bid,
+                    5,
+                    OrderParams::Fixed {
+                        price_lots,
+                        order_type: PostOrderType::Limit,
+                    },
+                ),
+                &mut market,
+                &market_pk,
+                &mut event_heap,
+                None,
+                Some(&mut maker),
+                &maker_pk,
+                now_ts,
+                10,
+                &mut HashMap::new(),
+            )
+            .unwrap();
+        }
+
+        // an ask that has expired by now_ts
+        book.new_order(
+            &Order {
+                time_in_force: 10,
+                ..order(
+                    Side::Ask,
+                    1,
+                    OrderParams::Fixed {
+                        price_lots: 2000,
+                        order_type: PostOrderType::Limit,
+                    },
+                )
+            },
+            &mut market,
+            &market_pk,
+            &mut event_heap,
+            None,
+            Some(&mut maker),
+            &maker_pk,
+            now_ts - 100,
+            10,
+            &mut HashMap::new(),
+        )
+        .unwrap();
+
+        let take = order(
+            Side::Bid,
+            1,
+            OrderParams::ImmediateOrCancel { price_lots: 2000 },
+        );
+        let simulated = book
+            .simulate_order(&take, &market, now_ts, None, 10)
+            .unwrap();
+        // the expired ask is dropped without a fill
+        assert!(simulated.fills.is_empty());
+        assert_eq!(simulated.outs.len(), 1);
+        assert_eq!(simulated.outs[0].owner, maker_pk);
+
+        let take = order(
+            Side::Ask,
+            8,
+            OrderParams::ImmediateOrCancel { price_lots: 990 },
+        );
+        let levels_before = book.bids.l2_levels(10, now_ts, None);
+        let asks_before = book.asks.l2_levels(10, now_ts - 100, None);
+        let simulated = book
+            .simulate_order(&take, &market, now_ts, None, 10)
+            .unwrap();
+        assert_eq!(book.bids.l2_levels(10, now_ts, None), levels_before);
+        assert_eq!(book.asks.l2_levels(10, now_ts - 100, None), asks_before);
+
+        let amounts = simulated.amounts;
+        assert_eq!(amounts.order_id, None);
+        assert_eq!(amounts.total_base_taken_native, 800);
+        assert_eq!(amounts.total_quote_taken_native, 79_700);
+        assert_eq!(amounts.taker_fees, 32);
+        assert_eq!(amounts.referrer_amount, 17);
+        assert_eq!(simulated.fills.len(), 2);
+        assert!(simulated.fills[0].maker_out());
+        assert_eq!(simulated.fills[1].quantity, 3);
+        assert!(simulated.outs.is_empty());
+
+        // the same quote from the account data, without borrowing the book mutably
+        let account_data = |book_side: &BookSide| {
+            let mut data = BookSide::DISCRIMINATOR.to_vec();
+            data.extend_from_slice(bytemuck::bytes_of(book_side));
+            data
+        };
+        let bids_data = account_data(&*book.bids);
+        let asks_data = account_data(&*book.asks);
+        let bids_ref = BookSideRef::from_bytes(&bids_data).unwrap();
+        let asks_ref = BookSideRef::from_bytes(&asks_data).unwrap();
+        let quoted = OrderbookRef {
+            bids: &bids_ref,
+            asks: &asks_ref,
+        }
+        .simulate_order(&take, &market, now_ts, None, 10)
+        .unwrap();
+        assert_eq!(quoted.amounts, simulated.amounts);
+        assert_eq!(quoted.fills.len(), simulated.fills.len());
+        for (fill, simulated_fill) in quoted.fills.iter().zip(&simulated.fills) {
+            assert_eq!(bytemuck::bytes_of(fill), bytemuck::bytes_of(simulated_fill));
+        }
+
+        let seq_num = market.seq_num;
+        let executed = book
+            .new_order(
+                &take,
+                &mut market,
+                &market_pk,
+                &mut event_heap,
+                None,
+                None,
+                &Pubkey::default(),
+                now_ts,
+                10,
+                &mut HashMap::new(),
+            )
+            .unwrap();
+        assert_eq!(executed, amounts);
+        assert_eq!(market.seq_num, seq_num + 1);
+
+        let fills: Vec<FillEvent> = event_heap
+            .iter_events()
+            .filter_map(|item| match item.unwrap().1 {
+                Event::Fill(fill) => Some(fill),
+                Event::Out(_) => None,
+            })
+            .collect();
+        assert_eq!(fills.len(), simulated.fills.len());
+        for (fill, simulated_fill) in fills.iter().zip(&simulated.fills) {
+            assert_eq!(bytemuck::bytes_of(fill), bytemuck::bytes_of(simulated_fill));
+        }
+    }
+
+    #[test]
+    fn test_new_order_resolves_makers() {
+        let market_pk = Pubkey::new_unique();
+        let mut market = Market::zeroed();
+        market.quote_lot_size = 10;
+        market.base_lot_size = 100;
+        let mut event_heap = EventHeap::zeroed();
+        event_heap.init();
+        let bids = new_bookside(OrderTreeType::Bids);
+        let asks = new_bookside(OrderTreeType::Asks);
+        let mut book = orderbook(&bids, &asks);
+
+        let maker_pk = Pubkey::new_unique();
+        let mut maker = OpenOrdersAccount::default_for_tests();
+        book.new_order(
+            &order(
+                Side::Bid,
+                5,
+                OrderParams::Fixed {
+                    price_lots: 1000,
+                    order_type: PostOrderType::Limit,
+                },
+            ),
+            &mut market,
+            &market_pk,
+            &mut event_heap,
+            None,
+            Some(&mut maker),
+            &maker_pk,
+            0,
+            10,
+            &mut HashMap::new(),
+        )
+        .unwrap();
+
+        let mut makers = HashMap::from([(maker_pk, *maker)]);
+        book.new_order(
+            &order(
+                Side::Ask,
+                3,
+                OrderParams::ImmediateOrCancel { price_lots: 1000 },
+            ),
+            &mut market,
+            &market_pk,
+            &mut event_heap,
+            None,
+            None,
+            &Pubkey::new_unique(),
+            0,
+            10,
+            &mut makers,
+        )
+        .unwrap();
+
+        // the fill was applied to the maker directly instead of going through the heap
+        assert!(event_heap.is_empty());
+        let position = makers[&maker_pk].position;
+        assert_eq!(position.base_free_native, 300);
+        assert_eq!(position.bids_base_lots, 2);
+    }
+
+    #[test]
+    fn test_new_order_evicts_worst_after_lone_expired_order() {
+        let now_ts = 1_700_000_000;
+        let mut market = Market::zeroed();
+        market.quote_lot_size = 10;
+        market.base_lot_size = 100;
+        let mut event_heap = EventHeap::zeroed();
+        event_heap.init();
+        let bids = new_bookside(OrderTreeType::Bids);
+        let asks = new_bookside(OrderTreeType::Asks);
+        let mut book = orderbook(&bids, &asks);
+
+        // the only oracle pegged bid has expired
+        let maker_pk = Pubkey::new_unique();
+        let expired_bid = LeafNode::new(
+            0,
+            new_node_key(Side::Bid, oracle_pegged_price_data(-10), 0),
+            maker_pk,
+            1000,
+            now_ts - 100,
+            10,
+            1000,
+            0,
+        );
+        book.bids
+            .insert_leaf(BookSideOrderTree::OraclePegged, &expired_bid)
+            .unwrap();
+
+        // n fixed bids take 2 * n - 1 nodes, with the expired bid that's all of them
+        let fixed_order_count = MAX_ORDERTREE_NODES as i64 / 2;
+        for price_lots in 1..=fixed_order_count {
+            let leaf = LeafNode::new(
+                0,
+                new_node_key(
+                    Side::Bid,
+                    fixed_price_data(price_lots).unwrap(),
+                    price_lots as u64,
+                ),
+                maker_pk,
+                price_lots,
+                now_ts,
+                0,
+                -1,
+                0,
+            );
+            book.bids
+                .insert_leaf(BookSideOrderTree::Fixed, &leaf)
+                .unwrap();
+        }
+        assert!(book.bids.is_full());
+        assert!(book
+            .bids
+            .is_full_after_remove(BookSideOrderTree::OraclePegged));
+
+        let bid = order(
+            Side::Bid,
+            1,
+            OrderParams::Fixed {
+                price_lots: 1000,
+                order_type: PostOrderType::Limit,
+            },
+        );
+        let simulated = book
+            .simulate_order(&bid, &market, now_ts, Some(1000), 10)
+            .unwrap();
+        // the expired bid and the fixed bid at price 1
+        let out_quantities: Vec<i64> = simulated.outs.iter().map(|out| out.quantity).collect();
+        assert_eq!(out_quantities, [1000, 1]);
+
+        let mut open_orders_account = OpenOrdersAccount::default_for_tests();
+        let amounts = book
+            .new_order(
+                &bid,
+                &mut market,
+                &Pubkey::new_unique(),
+                &mut event_heap,
+                Some(1000),
+                Some(&mut open_orders_account),
+                &Pubkey::new_unique(),
+                now_ts,
+                10,
+                &mut HashMap::new(),
+            )
+            .unwrap();
+        assert_eq!(amounts, simulated.amounts);
+        assert!(amounts.order_id.is_some());
+        book.bids.validate().unwrap();
+
+        assert_eq!(
+            book.bids.root(BookSideOrderTree::OraclePegged).leaf_count,
+            0
+        );
+        let prices: Vec<i64> = book
+            .bids
+            .iter_valid(now_ts, Some(1000))
+            .map(|item| item.price_lots)
+            .collect();
+        assert_eq!(prices.len() as i64, fixed_order_count);
+        assert_eq!(prices[0], 1000);
+        assert_eq!(*prices.last().unwrap(), 2);
+        let out_quantities: Vec<i64> = event_heap
+            .iter_events()
+            .filter_map(|item| match item.unwrap().1 {
+                Event::Out(out) => Some(out.quantity),
+                Event::Fill(_) => None,
+            })
+            .collect();
+        assert_eq!(out_quantities, [1000, 1]);
+    }
+}
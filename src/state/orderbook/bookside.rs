@@ -1,5 +1,10 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use std::ops::Deref;
+
+use crate::accounts_zerocopy::{cast_account_data, AccountReader};
+use crate::error::*;
 
 use super::*;
 
@@ -26,7 +31,35 @@ pub struct BookSideOrderHandle {
     pub order_tree: BookSideOrderTree,
 }
 
-#[derive(Debug, anchor_lang::AnchorSerialize, anchor_lang::AnchorDeserialize)]
+/// Aggregated quantity of all valid orders at one price
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct L2Level {
+    pub price_lots: i64,
+    /// Sum of the quantities in base lots
+    pub quantity_lots: i64,
+    pub order_count: u32,
+}
+
+/// A single valid order on the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct L3Order {
+    pub order_id: u128,
+    pub order_tree: BookSideOrderTree,
+    /// Effective price, for oracle pegged orders at the given oracle price
+    pub price_lots: i64,
+    /// Quantity in base lots
+    pub quantity_lots: i64,
+    /// Address of the owning OpenOrdersAccount
+    pub owner: Pubkey,
+    pub owner_slot: u8,
+    pub client_order_id: u64,
+    pub timestamp: u64,
+    /// Time at which the order expires, u64::MAX if never
+    pub expiry: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, anchor_lang::AnchorSerialize, anchor_lang::AnchorDeserialize)]
 pub struct BookSide {
     pub roots: [OrderTreeRoot; 2],
     pub reserved_roots: [OrderTreeRoot; 4],
@@ -34,6 +67,24 @@ pub struct BookSide {
     pub nodes: OrderTreeNodes,
 }
 
+// same layout as on chain, and no padding, so also the same as the serialized one
+const _: () = assert!(std::mem::size_of::<BookSide>() == 90944);
+const _: () = assert!(std::mem::align_of::<BookSide>() == 8);
+
+impl anchor_lang::Discriminator for BookSide {
+    const DISCRIMINATOR: [u8; 8] = [72, 44, 225, 141, 178, 130, 97, 57];
+}
+
+unsafe impl bytemuck::Pod for BookSide {}
+unsafe impl bytemuck::Zeroable for BookSide {}
+
+impl anchor_lang::ZeroCopy for BookSide {}
+impl anchor_lang::Owner for BookSide {
+    fn owner() -> Pubkey {
+        crate::ID
+    }
+}
+
 impl BookSide {
     /// The length of the BookSide account
     pub const LEN: usize = 8 + std::mem::size_of::<Self>();
@@ -79,6 +130,22 @@ impl BookSide {
         self.nodes.is_full()
     }
 
+    /// Check the structure of both order trees and of the free list
+    ///
+    /// See OrderTreeNodes::validate(). Also checks that all nodes below bump_index are used.
+    pub fn validate(&self) -> Result<()> {
+        let used = self.nodes.validate_trees(&self.roots)?;
+        if used != self.nodes.bump_index as usize {
+            return Err(error_msg_typed!(
+                OpenBookError::InvalidOrderTree,
+                "{} nodes below bump_index {} are neither in a tree nor in the free list",
+                self.nodes.bump_index as usize - used,
+                self.nodes.bump_index
+            ));
+        }
+        Ok(())
+    }
+
     pub fn is_empty(&self) -> bool {
         [BookSideOrderTree::Fixed, BookSideOrderTree::OraclePegged]
             .into_iter()
@@ -94,23 +161,62 @@ impl BookSide {
         self.nodes.insert_leaf(root, new_leaf)
     }
 
-    /// Remove the overall worst-price order.
-    pub fn remove_worst(
-        &mut self,
+    /// Return whether the book is still full after removing an order from `component`
+    ///
+    /// Removing an order frees its node and its parent, unless it's the only order in the order
+    /// tree. Then a single node is freed, which isn't enough to insert another order.
+    pub fn is_full_after_remove(&self, component: BookSideOrderTree) -> bool {
+        let freed_nodes = if self.root(component).leaf_count == 1 {
+            1
+        } else {
+            2
+        };
+        self.nodes.free_list_len + freed_nodes <= 1 && self.is_full()
+    }
+
+    /// Return the overall worst-price order.
+    pub fn find_worst(
+        &self,
         now_ts: u64,
         oracle_price_lots: Option<i64>,
-    ) -> Option<(LeafNode, i64)> {
-        let worst_fixed = self.nodes.find_worst(&self.roots[0]);
-        let worst_pegged = self.nodes.find_worst(&self.roots[1]);
+    ) -> Option<BookSideIterItem> {
+        self.find_worst_without(None, now_ts, oracle_price_lots)
+    }
+
+    /// Return the worst-price order, ignoring the orders in `skipped_component`
+    pub fn find_worst_without(
+        &self,
+        skipped_component: Option<BookSideOrderTree>,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+    ) -> Option<BookSideIterItem> {
+        let worst = move |component| {
+            if skipped_component == Some(component) {
+                None
+            } else {
+                self.nodes.find_worst(self.root(component))
+            }
+        };
+        let worst_fixed = worst(BookSideOrderTree::Fixed);
+        let worst_pegged = worst(BookSideOrderTree::OraclePegged);
         let side = self.nodes.order_tree_type().side();
-        let worse = rank_orders(
+        rank_orders(
             side,
             worst_fixed,
             worst_pegged,
             true,
             now_ts,
             oracle_price_lots,
-        )?;
+        )
+    }
+
+    /// Remove the overall worst-price order.
+    pub fn remove_worst(
+        &mut self,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+    ) -> Option<(LeafNode, i64)> {
+        let worse = self.find_worst(now_ts, oracle_price_lots)?;
         let price = worse.price_lots;
         let key = worse.node.key;
         let order_tree = worse.handle.order_tree;
@@ -138,6 +244,28 @@ impl BookSide {
         self.nodes.remove_one_expired(other_root, now_ts)
     }
 
+    /// Return the order that remove_one_expired() would remove, with its order tree
+    pub fn find_one_expired(
+        &self,
+        component: BookSideOrderTree,
+        now_ts: u64,
+    ) -> Option<(BookSideOrderTree, &LeafNode)> {
+        let other_component = match component {
+            BookSideOrderTree::Fixed => BookSideOrderTree::OraclePegged,
+            BookSideOrderTree::OraclePegged => BookSideOrderTree::Fixed,
+        };
+        [component, other_component]
+            .into_iter()
+            .find_map(|component| {
+                let (handle, expires_at) = self.nodes.find_earliest_expiry(self.root(component))?;
+                if expires_at < now_ts {
+                    Some((component, self.nodes.node(handle)?.as_leaf()?))
+                } else {
+                    None
+                }
+            })
+    }
+
     pub fn remove_by_key(
         &mut self,
         component: BookSideOrderTree,
@@ -180,9 +308,16 @@ impl BookSide {
 
     /// Walk up the book `quantity` units and return the price at that level. If `quantity` units
     /// not on book, return None
-    pub fn impact_price(&self, quantity: i64, now_ts: u64, oracle_price_lots: i64) -> Option<i64> {
+    ///
+    /// Without `oracle_price_lots` oracle pegged orders are skipped.
+    pub fn impact_price(
+        &self,
+        quantity: i64,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+    ) -> Option<i64> {
         let mut sum: i64 = 0;
-        for order in self.iter_valid(now_ts, Some(oracle_price_lots)) {
+        for order in self.iter_valid(now_ts, oracle_price_lots) {
             sum += order.node.quantity;
             if sum >= quantity {
                 return Some(order.price_lots);
@@ -190,4 +325,263 @@ impl BookSide {
         }
         None
     }
+
+    /// Aggregate the valid orders into at most `depth` price levels, best price first
+    ///
+    /// Fixed and oracle pegged orders at the same effective price are merged into one level.
+    /// Without `oracle_price_lots` oracle pegged orders are skipped.
+    pub fn l2_levels(
+        &self,
+        depth: usize,
+        now_ts: u64,
+        oracle_price_lots: Option<i64>,
+    ) -> Vec<L2Level> {
+        let mut levels: Vec<L2Level> = Vec::with_capacity(depth);
+        for item in self.iter_valid(now_ts, oracle_price_lots) {
+            match levels.last_mut() {
+                Some(level) if level.price_lots == item.price_lots => {
+                    level.quantity_lots += item.node.quantity;
+                    level.order_count += 1;
+                }
+                _ => {
+                    if levels.len() == depth {
+                        break;
+                    }
+                    levels.push(L2Level {
+                        price_lots: item.price_lots,
+                        quantity_lots: item.node.quantity,
+                        order_count: 1,
+                    });
+                }
+            }
+        }
+        levels
+    }
+
+    /// List the valid orders in matching order
+    ///
+    /// Without `oracle_price_lots` oracle pegged orders are skipped.
+    pub fn l3_orders(&self, now_ts: u64, oracle_price_lots: Option<i64>) -> Vec<L3Order> {
+        self.iter_valid(now_ts, oracle_price_lots)
+            .map(|item| L3Order {
+                order_id: item.node.key.into(),
+                order_tree: item.handle.order_tree,
+                price_lots: item.price_lots,
+                quantity_lots: item.node.quantity,
+                owner: item.node.owner,
+                owner_slot: item.node.owner_slot,
+                client_order_id: item.node.client_order_id,
+                timestamp: item.node.timestamp,
+                expiry: item.node.expiry(),
+            })
+            .collect()
+    }
+}
+
+/// A BookSide borrowed from the data of its account, without deserializing it
+///
+/// Derefs to the BookSide, so all of its read functions are available.
+#[derive(Clone, Copy)]
+pub struct BookSideRef<'a> {
+    book_side: &'a BookSide,
+}
+
+impl<'a> BookSideRef<'a> {
+    /// Checks the discriminator and length of the account data
+    pub fn from_bytes(data: &'a [u8]) -> Result<Self> {
+        let book_side = cast_account_data::<BookSide>(data, &BookSide::DISCRIMINATOR)?;
+        Ok(Self { book_side })
+    }
+
+    /// Like from_bytes(), and checks that the account is owned by the program
+    pub fn from_account(account: &'a impl AccountReader) -> Result<Self> {
+        if account.owner() != &crate::ID {
+            return Err(ErrorCode::AccountOwnedByWrongProgram.into());
+        }
+        Self::from_bytes(account.data())
+    }
+}
+
+impl Deref for BookSideRef<'_> {
+    type Target = BookSide;
+
+    fn deref(&self) -> &BookSide {
+        self.book_side
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn new_bids() -> Box<BookSide> {
+        Box::new(BookSide {
+            roots: [OrderTreeRoot::zeroed(); 2],
+            reserved_roots: [OrderTreeRoot::zeroed(); 4],
+            reserved: [0; 256],
+            nodes: OrderTreeNodes {
+                order_tree_type: OrderTreeType::Bids.into(),
+                padding: [0; 3],
+                bump_index: 0,
+                free_list_len: 0,
+                free_list_head: 0,
+                reserved: [0; 512],
+                nodes: [AnyNode::zeroed(); MAX_ORDERTREE_NODES],
+            },
+        })
+    }
+
+    #[test]
+    fn test_l2_levels_merge_pegged() {
+        let mut bids = new_bids();
+        let owner = Pubkey::new_unique();
+        let mut insert = |component, price_data, seq_num, quantity, time_in_force| {
+            let key = new_node_key(Side::Bid, price_data, seq_num);
+            let leaf = LeafNode::new(0, key, owner, quantity, 0, time_in_force, -1, seq_num);
+            bids.insert_leaf(component, &leaf).unwrap();
+        };
+        let fixed = BookSideOrderTree::Fixed;
+        let pegged = BookSideOrderTree::OraclePegged;
+        insert(fixed, fixed_price_data(100).unwrap(), 1, 5, 0);
+        insert(pegged, oracle_pegged_price_data(-10), 2, 3, 0);
+        insert(fixed, fixed_price_data(95).unwrap(), 3, 2, 0);
+        // expired at now_ts
+        insert(fixed, fixed_price_data(90).unwrap(), 4, 1, 10);
+
+        let now_ts = 1000;
+        let level = |price_lots, quantity_lots, order_count| L2Level {
+            price_lots,
+            quantity_lots,
+            order_count,
+        };
+        assert_eq!(
+            bids.l2_levels(5, now_ts, Some(110)),
+            vec![level(100, 8, 2), level(95, 2, 1)]
+        );
+        assert_eq!(bids.l2_levels(1, now_ts, Some(110)), vec![level(100, 8, 2)]);
+        // the pegged order moves with the oracle
+        assert_eq!(
+            bids.l2_levels(5, now_ts, Some(105)),
+            vec![level(100, 5, 1), level(95, 5, 2)]
+        );
+        assert_eq!(
+            bids.l2_levels(5, now_ts, None),
+            vec![level(100, 5, 1), level(95, 2, 1)]
+        );
+
+        let orders = bids.l3_orders(now_ts, Some(110));
+        assert_eq!(orders.len(), 3);
+        assert_eq!(orders[1].order_tree, pegged);
+        assert_eq!(orders[1].price_lots, 100);
+        assert_eq!(orders[1].client_order_id, 2);
+        assert_eq!(orders[1].owner, owner);
+        assert_eq!(orders[1].expiry, u64::MAX);
+        assert_eq!(
+            orders.iter().map(|o| o.quantity_lots).collect::<Vec<_>>(),
+            vec![5, 3, 2]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut bids = new_bids();
+        let owner = Pubkey::new_unique();
+        for (component, price_data, seq_num) in [
+            (BookSideOrderTree::Fixed, fixed_price_data(100).unwrap(), 1),
+            (
+                BookSideOrderTree::OraclePegged,
+                oracle_pegged_price_data(-10),
+                2,
+            ),
+            (BookSideOrderTree::Fixed, fixed_price_data(95).unwrap(), 3),
+            (
+                BookSideOrderTree::OraclePegged,
+                oracle_pegged_price_data(5),
+                4,
+            ),
+        ] {
+            let key = new_node_key(Side::Bid, price_data, seq_num);
+            let leaf = LeafNode::new(0, key, owner, 1, 0, 0, -1, seq_num);
+            bids.insert_leaf(component, &leaf).unwrap();
+        }
+        bids.remove_worst(1000, Some(100)).unwrap();
+        bids.validate().unwrap();
+
+        // a node that's in neither tree nor the free list
+        let mut leaked = bids.clone();
+        leaked.nodes.bump_index += 1;
+        assert!(leaked.validate().is_err());
+
+        // both trees share a node
+        let mut shared = bids.clone();
+        shared.roots[1] = shared.roots[0];
+        assert!(shared.validate().is_err());
+    }
+
+    #[test]
+    fn test_book_side_ref() {
+        let mut bids = new_bids();
+        let owner = Pubkey::new_unique();
+        for (seq_num, price_lots) in [(1, 100), (2, 95)] {
+            let key = new_node_key(Side::Bid, fixed_price_data(price_lots).unwrap(), seq_num);
+            let leaf = LeafNode::new(0, key, owner, 5, 0, 0, -1, seq_num);
+            bids.insert_leaf(BookSideOrderTree::Fixed, &leaf).unwrap();
+        }
+
+        let serialized = bids.try_to_vec().unwrap();
+        assert_eq!(serialized, bytemuck::bytes_of(bids.as_ref()));
+        let mut data = BookSide::DISCRIMINATOR.to_vec();
+        data.extend(serialized);
+
+        let view = BookSideRef::from_bytes(&data).unwrap();
+        assert_eq!(view.best_price(1000, None), Some(100));
+        assert_eq!(view.impact_price(6, 1000, None), Some(95));
+        assert_eq!(view.l2_levels(5, 1000, None), bids.l2_levels(5, 1000, None));
+
+        // truncated data
+        assert!(BookSideRef::from_bytes(&data[..BookSide::LEN - 8]).is_err());
+        assert!(BookSideRef::from_bytes(&data[..4]).is_err());
+        data[0] += 1;
+        assert!(BookSideRef::from_bytes(&data).is_err());
+    }
+
+    struct TestAccount {
+        owner: Pubkey,
+        data: Vec<u8>,
+    }
+
+    impl AccountReader for TestAccount {
+        fn owner(&self) -> &Pubkey {
+            &self.owner
+        }
+
+        fn data(&self) -> &[u8] {
+            &self.data
+        }
+    }
+
+    #[test]
+    fn test_load_zero_copy() {
+        use crate::accounts_zerocopy::LoadZeroCopy;
+
+        let mut bids = new_bids();
+        let key = new_node_key(Side::Bid, fixed_price_data(100).unwrap(), 1);
+        let leaf = LeafNode::new(0, key, Pubkey::new_unique(), 5, 0, 0, -1, 1);
+        bids.insert_leaf(BookSideOrderTree::Fixed, &leaf).unwrap();
+
+        let mut account = TestAccount {
+            owner: crate::ID,
+            data: BookSide::DISCRIMINATOR.to_vec(),
+        };
+        account.data.extend(bytemuck::bytes_of(bids.as_ref()));
+
+        let loaded = account.load::<BookSide>().unwrap();
+        assert_eq!(loaded.nodes.order_tree_type(), OrderTreeType::Bids);
+        assert_eq!(loaded.best_price(0, None), Some(100));
+        assert!(account.load::<crate::state::Market>().is_err());
+
+        account.owner = Pubkey::new_unique();
+        assert!(account.load::<BookSide>().is_err());
+    }
 }
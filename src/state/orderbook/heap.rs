@@ -1,12 +1,19 @@
-use crate::error::OpenBookError;
+use crate::accounts_zerocopy::{cast_account_data, AccountReader};
+use crate::error::*;
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+use bytemuck::cast_ref;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use std::ops::Deref;
 
 use super::Side;
 
 pub const MAX_NUM_EVENTS: u16 = 600;
 pub const NO_NODE: u16 = u16::MAX;
 
+// Max events to consume per ix.
+pub const MAX_EVENTS_CONSUME: usize = 8;
+
 /// Container for the different EventTypes.
 ///
 /// Events are stored in a fixed-array of nodes. Free nodes are connected by a single-linked list
@@ -19,6 +26,10 @@ pub struct EventHeap {
     pub reserved: [u8; 64],
 }
 
+// same layout as on chain, and no padding
+const _: () = assert!(std::mem::size_of::<EventHeap>() == 91280);
+const _: () = assert!(std::mem::align_of::<EventHeap>() == 8);
+
 impl EventHeap {
     pub fn init(&mut self) {
         self.header = EventHeapHeader {
@@ -130,6 +141,37 @@ impl EventHeap {
             slot: self.header.used_head(),
         }
     }
+
+    /// Iterate over the decoded events in heap order, together with their slot
+    ///
+    /// Yields an error for events of an unknown type.
+    pub fn iter_events(&self) -> impl Iterator<Item = Result<(usize, Event)>> + '_ {
+        self.iter()
+            .map(|(event, slot)| Event::decode(event).map(|event| (slot, event)))
+    }
+
+    /// Iterate over the events that apply to the OpenOrdersAccount `owner`, see Event::owner()
+    pub fn iter_events_for_owner<'a>(
+        &'a self,
+        owner: &'a Pubkey,
+    ) -> impl Iterator<Item = Result<(usize, Event)>> + 'a {
+        self.iter_events().filter(move |item| match item {
+            Ok((_, event)) => event.owner() == *owner,
+            Err(_) => true,
+        })
+    }
+
+    /// Iterate over the fills where `maker` was the maker
+    pub fn iter_fills_for_maker<'a>(
+        &'a self,
+        maker: &'a Pubkey,
+    ) -> impl Iterator<Item = Result<(usize, FillEvent)>> + 'a {
+        self.iter_events().filter_map(move |item| match item {
+            Ok((slot, Event::Fill(fill))) if fill.maker == *maker => Some(Ok((slot, fill))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
 }
 
 struct EventHeapIterator<'a> {
@@ -152,6 +194,36 @@ impl<'a> Iterator for EventHeapIterator<'a> {
     }
 }
 
+/// An EventHeap borrowed from the data of its account, see BookSideRef
+#[derive(Clone, Copy)]
+pub struct EventHeapRef<'a> {
+    event_heap: &'a EventHeap,
+}
+
+impl<'a> EventHeapRef<'a> {
+    /// Checks the discriminator and length of the account data
+    pub fn from_bytes(data: &'a [u8]) -> Result<Self> {
+        let event_heap = cast_account_data::<EventHeap>(data, &EventHeap::discriminator())?;
+        Ok(Self { event_heap })
+    }
+
+    /// Like from_bytes(), and checks that the account is owned by the program
+    pub fn from_account(account: &'a impl AccountReader) -> Result<Self> {
+        if account.owner() != &crate::ID {
+            return Err(ErrorCode::AccountOwnedByWrongProgram.into());
+        }
+        Self::from_bytes(account.data())
+    }
+}
+
+impl Deref for EventHeapRef<'_> {
+    type Target = EventHeap;
+
+    fn deref(&self) -> &EventHeap {
+        self.event_heap
+    }
+}
+
 #[zero_copy]
 pub struct EventHeapHeader {
     free_head: u16,
@@ -217,6 +289,38 @@ pub enum EventType {
     Out,
 }
 
+/// An AnyEvent decoded according to its event_type
+#[derive(Copy, Clone, Debug)]
+pub enum Event {
+    Fill(FillEvent),
+    Out(OutEvent),
+}
+
+impl Event {
+    pub fn decode(event: &AnyEvent) -> Result<Self> {
+        let event_type = EventType::try_from(event.event_type).map_err(|_| {
+            error_msg_typed!(
+                OpenBookError::SomeError,
+                "unknown event type {}",
+                event.event_type
+            )
+        })?;
+        Ok(match event_type {
+            EventType::Fill => Event::Fill(*cast_ref(event)),
+            EventType::Out => Event::Out(*cast_ref(event)),
+        })
+    }
+
+    /// The OpenOrdersAccount that needs to be passed to consume the event: the maker of a
+    /// fill or the owner of an out
+    pub fn owner(&self) -> Pubkey {
+        match self {
+            Event::Fill(fill) => fill.maker,
+            Event::Out(out) => out.owner,
+        }
+    }
+}
+
 #[derive(
     Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, AnchorSerialize, AnchorDeserialize,
 )]
@@ -361,6 +465,23 @@ mod tests {
         assert_eq!(count_free_nodes(&eq), MAX_NUM_EVENTS as usize);
     }
 
+    #[test]
+    fn event_heap_ref() {
+        let mut eq = EventHeap::zeroed();
+        eq.init();
+        eq.push_back(dummy_event_with_number(1));
+
+        let mut data = EventHeap::discriminator().to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(&eq));
+        let view = EventHeapRef::from_bytes(&data).unwrap();
+        assert_eq!(view.len(), 1);
+        assert_eq!(view.front().unwrap().event_type, 1);
+
+        assert!(EventHeapRef::from_bytes(&data[..data.len() - 1]).is_err());
+        data[0] += 1;
+        assert!(EventHeapRef::from_bytes(&data).is_err());
+    }
+
     #[test]
     #[should_panic]
     fn cannot_insert_if_full() {
@@ -580,4 +701,56 @@ mod tests {
         assert_eq!(eq.header.free_head(), 2);
         assert_eq!(eq.nodes[2].next, 3);
     }
+
+    #[test]
+    fn iter_events() {
+        let mut eq = EventHeap::zeroed();
+        eq.init();
+
+        let maker = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let fill = |maker, quantity| {
+            FillEvent::new(
+                Side::Bid,
+                false,
+                0,
+                0,
+                0,
+                maker,
+                0,
+                0,
+                Pubkey::new_unique(),
+                0,
+                100,
+                -1,
+                quantity,
+            )
+        };
+        eq.push_back(bytemuck::cast(fill(maker, 1)));
+        eq.push_back(bytemuck::cast(OutEvent::new(Side::Ask, 3, 0, 0, maker, 2)));
+        eq.push_back(bytemuck::cast(fill(other, 3)));
+        eq.pop_front().unwrap();
+        eq.push_back(bytemuck::cast(fill(maker, 4)));
+
+        let events: Vec<(usize, Event)> = eq.iter_events().map(|e| e.unwrap()).collect();
+        let slots: Vec<usize> = events.iter().map(|(slot, _)| *slot).collect();
+        assert_eq!(slots, vec![1, 2, 0]);
+        assert!(matches!(events[0].1, Event::Out(out) if out.owner_slot == 3));
+        assert!(matches!(events[1].1, Event::Fill(fill) if fill.quantity == 3));
+
+        let for_maker: Vec<usize> = eq
+            .iter_events_for_owner(&maker)
+            .map(|e| e.unwrap().0)
+            .collect();
+        assert_eq!(for_maker, vec![1, 0]);
+        let fills: Vec<i64> = eq
+            .iter_fills_for_maker(&maker)
+            .map(|e| e.unwrap().1.quantity)
+            .collect();
+        assert_eq!(fills, vec![4]);
+
+        eq.push_back(dummy_event_with_number(7));
+        let result: Result<Vec<_>> = eq.iter_events().collect();
+        assert!(result.is_err());
+    }
 }
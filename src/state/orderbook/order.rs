@@ -95,7 +95,7 @@ impl Order {
         oracle_price_lots: Option<i64>,
         price_lots: i64,
         order_type: PostOrderType,
-        order_book: &Orderbook,
+        order_book: &OrderbookRef,
     ) -> i64 {
         if order_type == PostOrderType::PostOnlySlide {
             if let Some(best_other_price) = order_book
@@ -118,7 +118,7 @@ impl Order {
         &self,
         now_ts: u64,
         oracle_price_lots: Option<i64>,
-        order_book: &Orderbook,
+        order_book: &OrderbookRef,
     ) -> Result<(i64, u64)> {
         let price_lots = match self.params {
             OrderParams::Market => market_order_limit_for_side(self.side),
@@ -186,6 +186,6 @@ fn market_order_limit_for_side(side: Side) -> i64 {
 fn post_only_slide_limit(side: Side, best_other_side: i64, limit: i64) -> i64 {
     match side {
         Side::Bid => limit.min(best_other_side - 1),
-        Side::Ask => limit.max(best_other_side + 1),
+        Side::Ask => limit.max(best_other_side.saturating_add(1)),
     }
 }
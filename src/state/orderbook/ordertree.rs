@@ -4,7 +4,7 @@ use bytemuck::{cast, cast_mut, cast_ref};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use super::*;
-use crate::error::OpenBookError;
+use crate::error::*;
 
 pub const MAX_ORDERTREE_NODES: usize = 1024;
 
@@ -54,6 +54,7 @@ impl OrderTreeRoot {
 /// A binary tree on AnyNode::key()
 ///
 /// The key encodes the price in the top 64 bits.
+#[repr(C)]
 #[derive(Debug, Clone, Copy, anchor_lang::AnchorSerialize, anchor_lang::AnchorDeserialize)]
 pub struct OrderTreeNodes {
     pub order_tree_type: u8, // OrderTreeType, but that's not POD
@@ -65,6 +66,10 @@ pub struct OrderTreeNodes {
     pub nodes: [AnyNode; MAX_ORDERTREE_NODES],
 }
 
+// no padding: the fields before the nodes add up to a multiple of their alignment
+unsafe impl bytemuck::Pod for OrderTreeNodes {}
+unsafe impl bytemuck::Zeroable for OrderTreeNodes {}
+
 impl OrderTreeNodes {
     pub fn order_tree_type(&self) -> OrderTreeType {
         OrderTreeType::try_from(self.order_tree_type).unwrap()
@@ -434,4 +439,547 @@ impl OrderTreeNodes {
             };
         }
     }
+
+    /// Check the structure of the tree at `root` and of the free list
+    ///
+    /// Unlike the other functions this doesn't panic on corrupted nodes. The error is
+    /// InvalidOrderTree, with the first violated invariant as context.
+    pub fn validate(&self, root: &OrderTreeRoot) -> Result<()> {
+        self.validate_trees(std::slice::from_ref(root))?;
+        Ok(())
+    }
+
+    /// Check the trees at `roots`, which share the nodes, and the free list
+    ///
+    /// Returns the number of nodes used by the trees and the free list.
+    pub(crate) fn validate_trees(&self, roots: &[OrderTreeRoot]) -> Result<usize> {
+        if OrderTreeType::try_from(self.order_tree_type).is_err() {
+            return Err(error_msg_typed!(
+                OpenBookError::InvalidOrderTree,
+                "unknown order tree type {}",
+                self.order_tree_type
+            ));
+        }
+        if self.bump_index as usize > MAX_ORDERTREE_NODES {
+            return Err(error_msg_typed!(
+                OpenBookError::InvalidOrderTree,
+                "bump_index {} is beyond the end of the nodes",
+                self.bump_index
+            ));
+        }
+
+        let mut visited = vec![false; self.bump_index as usize];
+        let mut used = 0;
+        for root in roots {
+            used += self.validate_tree(root, &mut visited)?;
+        }
+        used += self.validate_free_list(&mut visited)?;
+        Ok(used)
+    }
+
+    /// Returns the number of nodes in the tree
+    fn validate_tree(&self, root: &OrderTreeRoot, visited: &mut [bool]) -> Result<usize> {
+        let root_handle = match root.node() {
+            Some(handle) => handle,
+            None => return Ok(0),
+        };
+        self.validate_node(root_handle, visited)?;
+
+        // earliest expiry of the subtree at each handle, once its children are checked
+        let mut earliest_expiry = vec![u64::MAX; visited.len()];
+        let mut node_count = 1;
+        let mut leaf_count = 0;
+        // depth first, inner nodes are popped again after their children
+        let mut stack = vec![(root_handle, false)];
+        while let Some((handle, children_checked)) = stack.pop() {
+            // validate_node() ensured it's an InnerNode or a LeafNode
+            match self.nodes[handle as usize].case().unwrap() {
+                NodeRef::Leaf(leaf) => {
+                    leaf_count += 1;
+                    earliest_expiry[handle as usize] = if leaf.time_in_force == 0 {
+                        u64::MAX
+                    } else {
+                        leaf.timestamp
+                            .checked_add(leaf.time_in_force as u64)
+                            .ok_or_else(|| {
+                                error_msg_typed!(
+                                    OpenBookError::InvalidOrderTree,
+                                    "expiry of leaf {} overflows",
+                                    handle
+                                )
+                            })?
+                    };
+                }
+                NodeRef::Inner(inner) if children_checked => {
+                    for (i, &child) in inner.children.iter().enumerate() {
+                        let expiry = earliest_expiry[child as usize];
+                        if inner.child_earliest_expiry[i] != expiry {
+                            return Err(error_msg_typed!(
+                                OpenBookError::InvalidOrderTree,
+                                "inner node {} has child_earliest_expiry {} for child {}, \
+                                 but its earliest expiry is {}",
+                                handle,
+                                inner.child_earliest_expiry[i],
+                                child,
+                                expiry
+                            ));
+                        }
+                    }
+                    earliest_expiry[handle as usize] = inner.earliest_expiry();
+                }
+                NodeRef::Inner(inner) => {
+                    if inner.prefix_len >= 128 {
+                        return Err(error_msg_typed!(
+                            OpenBookError::InvalidOrderTree,
+                            "inner node {} has prefix_len {}",
+                            handle,
+                            inner.prefix_len
+                        ));
+                    }
+                    let key = u128::from(inner.key);
+                    // the children share the top prefix_len bits and differ in the next one
+                    let prefix_mask = !(u128::MAX >> inner.prefix_len);
+                    let crit_bit_mask = 1u128 << (127 - inner.prefix_len);
+
+                    stack.push((handle, true));
+                    for (i, &child) in inner.children.iter().enumerate() {
+                        let child_node = self.validate_node(child, visited)?;
+                        let child_key = child_node.key().unwrap();
+                        let crit_bit = (child_key & crit_bit_mask) != 0;
+                        if (child_key ^ key) & prefix_mask != 0 || crit_bit != (i == 1) {
+                            return Err(error_msg_typed!(
+                                OpenBookError::InvalidOrderTree,
+                                "key {} of node {} doesn't belong below inner node {} \
+                                 with key {} and prefix_len {}",
+                                child_key,
+                                child,
+                                handle,
+                                key,
+                                inner.prefix_len
+                            ));
+                        }
+                        if let Some(child_inner) = child_node.as_inner() {
+                            if child_inner.prefix_len <= inner.prefix_len {
+                                return Err(error_msg_typed!(
+                                    OpenBookError::InvalidOrderTree,
+                                    "inner node {} has prefix_len {}, not more than its \
+                                     parent {}",
+                                    child,
+                                    child_inner.prefix_len,
+                                    handle
+                                ));
+                            }
+                        }
+                        node_count += 1;
+                        stack.push((child, false));
+                    }
+                }
+            }
+        }
+
+        if leaf_count != root.leaf_count {
+            return Err(error_msg_typed!(
+                OpenBookError::InvalidOrderTree,
+                "root has leaf_count {}, but the tree has {} leaves",
+                root.leaf_count,
+                leaf_count
+            ));
+        }
+        Ok(node_count)
+    }
+
+    /// Returns the free list length
+    fn validate_free_list(&self, visited: &mut [bool]) -> Result<usize> {
+        let mut handle = self.free_list_head;
+        for i in 0..self.free_list_len {
+            Self::visit(handle, visited)?;
+            let node = &self.nodes[handle as usize];
+            let expected_tag = if i + 1 == self.free_list_len {
+                NodeTag::LastFreeNode
+            } else {
+                NodeTag::FreeNode
+            };
+            if node.tag != u8::from(expected_tag) {
+                return Err(error_msg_typed!(
+                    OpenBookError::InvalidOrderTree,
+                    "node {} at position {} of the free list of length {} has tag {}",
+                    handle,
+                    i,
+                    self.free_list_len,
+                    node.tag
+                ));
+            }
+            handle = cast_ref::<AnyNode, FreeNode>(node).next;
+        }
+        Ok(self.free_list_len as usize)
+    }
+
+    /// Marks `handle` as visited and returns its node, which must be in a tree
+    fn validate_node(&self, handle: NodeHandle, visited: &mut [bool]) -> Result<&AnyNode> {
+        Self::visit(handle, visited)?;
+        self.node(handle).ok_or_else(|| {
+            error_msg_typed!(
+                OpenBookError::InvalidOrderTree,
+                "node {} in a tree has tag {}",
+                handle,
+                self.nodes[handle as usize].tag
+            )
+        })
+    }
+
+    fn visit(handle: NodeHandle, visited: &mut [bool]) -> Result<()> {
+        let bump_index = visited.len();
+        match visited.get_mut(handle as usize) {
+            None => Err(error_msg_typed!(
+                OpenBookError::InvalidOrderTree,
+                "node {} is not below bump_index {}",
+                handle,
+                bump_index
+            )),
+            Some(true) => Err(error_msg_typed!(
+                OpenBookError::InvalidOrderTree,
+                "node {} is reachable twice",
+                handle
+            )),
+            Some(seen) => {
+                *seen = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+    use std::collections::BTreeMap;
+
+    fn new_asks() -> (Box<OrderTreeNodes>, OrderTreeRoot) {
+        let mut tree = Box::new(OrderTreeNodes::zeroed());
+        tree.order_tree_type = OrderTreeType::Asks.into();
+        (tree, OrderTreeRoot::zeroed())
+    }
+
+    fn new_leaf(price_lots: i64, seq_num: u64, timestamp: u64, time_in_force: u16) -> LeafNode {
+        let key = new_node_key(Side::Ask, fixed_price_data(price_lots).unwrap(), seq_num);
+        let owner = Pubkey::default();
+        LeafNode::new(0, key, owner, 1, timestamp, time_in_force, -1, seq_num)
+    }
+
+    fn assert_invalid(result: Result<()>) {
+        assert!(result.is_anchor_error_with_code(OpenBookError::InvalidOrderTree.error_code()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let (mut tree, mut root) = new_asks();
+        tree.validate(&root).unwrap();
+
+        let mut keys = vec![];
+        for seq_num in 0..200u64 {
+            let price_lots = (seq_num * 7919 % 150 + 1) as i64;
+            let leaf = new_leaf(price_lots, seq_num, seq_num, (seq_num % 3 * 10) as u16);
+            tree.insert_leaf(&mut root, &leaf).unwrap();
+            keys.push(u128::from(leaf.key));
+            tree.validate(&root).unwrap();
+        }
+        assert_eq!(root.leaf_count, 200);
+
+        for key in keys.iter().step_by(2) {
+            tree.remove_by_key(&mut root, *key).unwrap();
+            tree.validate(&root).unwrap();
+        }
+        while tree.remove_one_expired(&mut root, 1000).is_some() {
+            tree.validate(&root).unwrap();
+        }
+        assert!(tree.free_list_len > 0);
+
+        // reuses the free list
+        for seq_num in 200..300u64 {
+            let leaf = new_leaf(seq_num as i64, seq_num, 0, 0);
+            tree.insert_leaf(&mut root, &leaf).unwrap();
+        }
+        tree.validate(&root).unwrap();
+    }
+
+    #[test]
+    fn test_validate_corrupted() {
+        let (mut tree, mut root) = new_asks();
+        for (price_lots, seq_num) in [(10, 1), (20, 2), (15, 3), (30, 4)] {
+            let leaf = new_leaf(price_lots, seq_num, 100, seq_num as u16);
+            tree.insert_leaf(&mut root, &leaf).unwrap();
+        }
+        let leaf = new_leaf(25, 5, 100, 0);
+        tree.insert_leaf(&mut root, &leaf).unwrap();
+        tree.remove_by_key(&mut root, leaf.key.into()).unwrap();
+        tree.validate(&root).unwrap();
+        let root_handle = root.maybe_node;
+        let (worst_handle, _) = tree.find_worst(&root).unwrap();
+
+        let mut bad_root = root;
+        bad_root.leaf_count += 1;
+        assert_invalid(tree.validate(&bad_root));
+
+        let mut bad_tree = tree.clone();
+        bad_tree.bump_index = MAX_ORDERTREE_NODES as u32 + 1;
+        assert_invalid(bad_tree.validate(&root));
+
+        let mut bad_tree = tree.clone();
+        bad_tree.free_list_len += 1;
+        assert_invalid(bad_tree.validate(&root));
+
+        let mut bad_tree = tree.clone();
+        bad_tree.nodes[worst_handle as usize].tag = NodeTag::FreeNode.into();
+        assert_invalid(bad_tree.validate(&root));
+
+        let corrupt_root = |corrupt: &dyn Fn(&mut InnerNode)| {
+            let mut bad_tree = tree.clone();
+            corrupt(bad_tree.nodes[root_handle as usize].as_inner_mut().unwrap());
+            bad_tree.validate(&root)
+        };
+        assert_invalid(corrupt_root(&|inner| inner.child_earliest_expiry[0] += 1));
+        assert_invalid(corrupt_root(&|inner| inner.children.swap(0, 1)));
+        assert_invalid(corrupt_root(&|inner| inner.children[1] = inner.children[0]));
+        assert_invalid(corrupt_root(&|inner| inner.prefix_len = 128));
+        assert_invalid(corrupt_root(&|inner| inner.key = u128::MAX.into()));
+    }
+
+    /// Deterministic xorshift generator, so a failing case can be replayed from its seed
+    ///
+    /// There is no shrinking as in proptest: a failing seed replays the whole op sequence, not a
+    /// minimal one.
+    struct Rng(u64);
+
+    impl Rng {
+        fn new(seed: u64) -> Self {
+            // the state must not be zero
+            Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+        }
+
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    struct ModelConfig {
+        /// Weight of inserts, each kind of removal has weight 1
+        insert_weight: u64,
+        price_range: u64,
+        max_time_in_force: u16,
+    }
+
+    /// An order tree next to a BTreeMap with the same leaves
+    struct ModelTest {
+        rng: Rng,
+        config: ModelConfig,
+        tree: Box<OrderTreeNodes>,
+        root: OrderTreeRoot,
+        model: BTreeMap<u128, LeafNode>,
+        seq_num: u64,
+        now_ts: u64,
+    }
+
+    impl ModelTest {
+        fn new(seed: u64, order_tree_type: OrderTreeType, config: ModelConfig) -> Self {
+            let mut tree = Box::new(OrderTreeNodes::zeroed());
+            tree.order_tree_type = order_tree_type.into();
+            Self {
+                rng: Rng::new(seed),
+                config,
+                tree,
+                root: OrderTreeRoot::zeroed(),
+                model: BTreeMap::new(),
+                seq_num: 0,
+                now_ts: 1000,
+            }
+        }
+
+        fn new_key(&mut self) -> u128 {
+            let price_lots = 1 + self.rng.below(self.config.price_range) as i64;
+            self.seq_num += 1;
+            new_node_key(
+                self.tree.order_tree_type().side(),
+                fixed_price_data(price_lots).unwrap(),
+                self.seq_num,
+            )
+        }
+
+        fn model_worst(&self) -> Option<u128> {
+            match self.tree.order_tree_type() {
+                OrderTreeType::Bids => self.model.keys().next().copied(),
+                OrderTreeType::Asks => self.model.keys().next_back().copied(),
+            }
+        }
+
+        fn remove_worst(&mut self) {
+            let expected = self.model_worst();
+            let removed = self.tree.remove_worst(&mut self.root);
+            assert_eq!(removed.map(|leaf| u128::from(leaf.key)), expected);
+            if let Some(key) = expected {
+                self.model.remove(&key);
+            }
+        }
+
+        fn insert(&mut self) {
+            // like the book, make room first
+            if self.tree.is_full() {
+                self.remove_worst();
+            }
+            let key = self.new_key();
+            let time_in_force = self.rng.below(self.config.max_time_in_force as u64 + 1) as u16;
+            let quantity = 1 + self.rng.below(100) as i64;
+            let leaf = LeafNode::new(
+                0,
+                key,
+                Pubkey::default(),
+                quantity,
+                self.now_ts,
+                time_in_force,
+                -1,
+                self.seq_num,
+            );
+            let (_, replaced) = self.tree.insert_leaf(&mut self.root, &leaf).unwrap();
+            assert!(replaced.is_none());
+            self.model.insert(key, leaf);
+        }
+
+        fn remove_by_key(&mut self) {
+            if self.model.is_empty() || self.rng.below(4) == 0 {
+                let key = self.new_key();
+                assert!(self.tree.remove_by_key(&mut self.root, key).is_none());
+                return;
+            }
+            let index = self.rng.below(self.model.len() as u64) as usize;
+            let key = *self.model.keys().nth(index).unwrap();
+            let removed = self.tree.remove_by_key(&mut self.root, key).unwrap();
+            assert_eq!(u128::from(removed.key), key);
+            self.model.remove(&key);
+        }
+
+        fn remove_expired(&mut self) {
+            self.now_ts += self.rng.below(self.config.max_time_in_force as u64 + 1);
+            for _ in 0..1 + self.rng.below(8) {
+                // ties go to the lower key
+                let expected = self
+                    .model
+                    .values()
+                    .map(|leaf| (leaf.expiry(), u128::from(leaf.key)))
+                    .min()
+                    .filter(|(expiry, _)| *expiry < self.now_ts)
+                    .map(|(_, key)| key);
+                let removed = self.tree.remove_one_expired(&mut self.root, self.now_ts);
+                assert_eq!(removed.map(|leaf| u128::from(leaf.key)), expected);
+                match expected {
+                    Some(key) => self.model.remove(&key),
+                    None => break,
+                };
+            }
+        }
+
+        fn check(&self) {
+            self.tree.validate(&self.root).unwrap();
+            assert_eq!(self.root.leaf_count as usize, self.model.len());
+
+            let leaves: Vec<&LeafNode> = self.tree.iter(&self.root).map(|(_, l)| l).collect();
+            let expected: Vec<&LeafNode> = match self.tree.order_tree_type() {
+                OrderTreeType::Bids => self.model.values().rev().collect(),
+                OrderTreeType::Asks => self.model.values().collect(),
+            };
+            assert_eq!(leaves.len(), expected.len());
+            for (leaf, expected) in leaves.iter().zip(expected) {
+                assert_eq!(bytemuck::bytes_of(*leaf), bytemuck::bytes_of(expected));
+            }
+        }
+
+        fn run(&mut self, ops: usize) {
+            for _ in 0..ops {
+                match self.rng.below(3 + self.config.insert_weight) {
+                    0 => self.remove_by_key(),
+                    1 => self.remove_worst(),
+                    2 => self.remove_expired(),
+                    _ => self.insert(),
+                }
+                self.check();
+            }
+        }
+    }
+
+    /// Run `f` for both tree types and each seed, and name the seed if it panics
+    fn for_each_seed(seeds: std::ops::Range<u64>, f: impl Fn(u64, OrderTreeType)) {
+        for seed in seeds {
+            for order_tree_type in [OrderTreeType::Bids, OrderTreeType::Asks] {
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    f(seed, order_tree_type)
+                }));
+                if let Err(payload) = result {
+                    let message = payload
+                        .downcast_ref::<String>()
+                        .map(String::as_str)
+                        .or_else(|| payload.downcast_ref::<&str>().copied())
+                        .unwrap_or("");
+                    panic!("seed {seed}, {order_tree_type:?}: {message}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_model_random_ops() {
+        for_each_seed(0..16, |seed, order_tree_type| {
+            let config = ModelConfig {
+                insert_weight: 4,
+                price_range: 50,
+                max_time_in_force: 30,
+            };
+            ModelTest::new(seed, order_tree_type, config).run(1000);
+        });
+    }
+
+    #[test]
+    fn test_model_full_tree() {
+        for_each_seed(0..4, |seed, order_tree_type| {
+            let config = ModelConfig {
+                insert_weight: 30,
+                price_range: 10_000,
+                max_time_in_force: 0,
+            };
+            let mut test = ModelTest::new(seed, order_tree_type, config);
+            // a full tree of n leaves uses 2n - 1 nodes
+            let max_leaves = (MAX_ORDERTREE_NODES + 1) / 2;
+            while test.model.len() < max_leaves {
+                test.insert();
+            }
+            test.check();
+            assert!(test.tree.is_full());
+
+            // inserts into the full tree replace the worst order
+            test.run(2000);
+            while test.model.len() < max_leaves {
+                test.insert();
+            }
+            test.check();
+            assert!(test.tree.is_full());
+        });
+    }
+
+    #[test]
+    fn test_model_expiry_churn() {
+        for_each_seed(0..16, |seed, order_tree_type| {
+            // few prices, so the expiry of many leaves ties
+            let config = ModelConfig {
+                insert_weight: 6,
+                price_range: 5,
+                max_time_in_force: 5,
+            };
+            ModelTest::new(seed, order_tree_type, config).run(1000);
+        });
+    }
 }
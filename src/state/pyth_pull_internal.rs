@@ -0,0 +1,39 @@
+use anchor_lang::prelude::*;
+
+declare_id!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+
+/// Wormhole guardian verification of a price update
+#[derive(AnchorSerialize, AnchorDeserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VerificationLevel {
+    /// Signed by `num_signatures` guardians, less than a quorum
+    Partial { num_signatures: u8 },
+    /// Signed by a quorum of guardians
+    Full,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PriceFeedMessage {
+    pub feed_id: [u8; 32],
+    pub price: i64,
+    pub conf: u64,
+    pub exponent: i32,
+    /// The timestamp of this price update in seconds
+    pub publish_time: i64,
+    /// The timestamp of the previous price update
+    pub prev_publish_time: i64,
+    pub ema_price: i64,
+    pub ema_conf: u64,
+}
+
+/// A price update account posted by the Pyth receiver program
+///
+/// Borsh serialized, the size of `verification_level` depends on its variant.
+#[account]
+#[derive(Debug)]
+pub struct PriceUpdateV2 {
+    pub write_authority: Pubkey,
+    pub verification_level: VerificationLevel,
+    pub price_message: PriceFeedMessage,
+    /// The slot at which the update was posted
+    pub posted_slot: u64,
+}
@@ -125,3 +125,47 @@ pub struct RewardInfo {
     /// emissions were turned on.
     pub reward_growth_global_x64: u128,
 }
+
+impl PoolState {
+    /// Whether swaps are enabled, see the `status` bits
+    pub fn is_swap_enabled(&self) -> bool {
+        self.status & (1 << 4) == 0
+    }
+}
+
+pub const OBSERVATION_NUM: usize = 100;
+
+/// Tick history of a pool
+///
+/// `observation_index` points to the most recent observation, older ones precede it in the
+/// ring buffer. The pool's own `observation_index` is no longer maintained.
+#[account(zero_copy(unsafe))]
+#[repr(C, packed)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct ObservationState {
+    /// Whether the ObservationState is initialized
+    pub initialized: bool,
+    /// recent update epoch
+    pub recent_epoch: u64,
+    /// the most-recently updated index of the observations array
+    pub observation_index: u16,
+    /// belongs to which pool
+    pub pool_id: Pubkey,
+    /// observation array
+    pub observations: [Observation; OBSERVATION_NUM],
+    /// padding for feature update
+    pub padding: [u64; 4],
+}
+
+#[zero_copy(unsafe)]
+#[repr(C, packed)]
+#[derive(Default, Debug)]
+pub struct Observation {
+    /// The block timestamp of the observation
+    pub block_timestamp: u32,
+    /// the cumulative of tick during the duration time
+    pub tick_cumulative: i64,
+    /// padding for feature update
+    pub padding: [u64; 4],
+}
@@ -0,0 +1,90 @@
+use anchor_lang::{account, declare_id, zero_copy};
+use solana_program::pubkey::Pubkey;
+
+declare_id!("SBondMDrcV3K4kxZR1HNVT7osZxAHVHgYXL5Ze1oMUv");
+
+/// Only accepted with the devnet feature
+pub mod devnet {
+    use solana_program::declare_id;
+    declare_id!("Aio4gaXjXzJNVLtzwtNVmSqGKpANtXhybbkhtAC94ji2");
+}
+
+/// Number of decimals of the fixed point values in a feed
+pub const PRECISION: i32 = 18;
+
+/// A pull feed, updated by oracles on demand
+#[account(zero_copy(unsafe))]
+#[repr(C, packed)]
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct PullFeedAccountData {
+    /// The oracle submissions for this feed
+    pub submissions: [OracleSubmission; 32],
+    pub authority: Pubkey,
+    /// The queue which oracles must be bound to in order to submit data to this feed
+    pub queue: Pubkey,
+    /// SHA-256 hash of the job schema oracles execute to produce data for this feed
+    pub feed_hash: [u8; 32],
+    pub initialized_at: i64,
+    pub permissions: u64,
+    pub max_variance: u64,
+    pub min_responses: u32,
+    pub name: [u8; 32],
+    pub padding1: [u8; 2],
+    pub historical_result_idx: u8,
+    pub min_sample_size: u8,
+    pub last_update_timestamp: i64,
+    pub lut_slot: u64,
+    pub reserved1: [u8; 32],
+    pub result: CurrentResult,
+    pub max_staleness: u32,
+    pub padding2: [u8; 12],
+    pub historical_results: [CompactResult; 32],
+    pub ebuf4: [u8; 8],
+    pub ebuf3: [u8; 24],
+    pub submission_timestamps: [i64; 32],
+}
+
+#[zero_copy(unsafe)]
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct OracleSubmission {
+    pub oracle: Pubkey,
+    /// The slot at which this value was signed
+    pub slot: u64,
+    /// The slot at which this value landed on chain
+    pub landed_at: u64,
+    pub value: i128,
+}
+
+/// Aggregate of the submissions needed for the quorum, values scaled by 10^PRECISION
+#[zero_copy(unsafe)]
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct CurrentResult {
+    /// The median value of the submissions
+    pub value: i128,
+    pub std_dev: i128,
+    pub mean: i128,
+    pub range: i128,
+    pub min_value: i128,
+    pub max_value: i128,
+    pub num_samples: u8,
+    pub submission_idx: u8,
+    pub padding1: [u8; 6],
+    /// The slot at which this value was signed, 0 if there is no result yet
+    pub slot: u64,
+    /// The slot at which the first considered submission was made
+    pub min_slot: u64,
+    /// The slot at which the last considered submission was made
+    pub max_slot: u64,
+}
+
+#[zero_copy(unsafe)]
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct CompactResult {
+    pub std_dev: f32,
+    pub mean: f32,
+    pub slot: u64,
+}
@@ -10,7 +10,11 @@ pub struct I80F48 {
 /// A 128-bit unsigned integer.
 /// This is a workaround for the fact the rust changed
 /// the alignment of u128 from 8 to 16 bytes.
-#[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod, Debug)]
+///
+/// Serializes like a u128.
+#[derive(
+    Copy, Clone, bytemuck::Zeroable, bytemuck::Pod, Debug, AnchorSerialize, AnchorDeserialize,
+)]
 #[repr(C)]
 #[allow(non_camel_case_types)]
 pub struct aligned_u128(pub [u8; 16]);
@@ -27,6 +31,12 @@ impl From<u128> for aligned_u128 {
     }
 }
 
+impl std::ops::AddAssign<u128> for aligned_u128 {
+    fn add_assign(&mut self, rhs: u128) {
+        *self = (u128::from(*self) + rhs).into();
+    }
+}
+
 /// A 128-bit signed integer.
 /// This is a workaround for the fact the rust changed
 /// the alignment of i128 from 8 to 16 bytes.